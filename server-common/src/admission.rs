@@ -0,0 +1,215 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::sync::Notify;
+
+/// Seconds clients are asked to wait before retrying when the queue is full.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Caps how many generations run at once; the rest wait in a bounded FIFO queue.
+#[derive(Clone)]
+pub struct Admission {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    changed: Notify,
+    max_active: usize,
+    max_queue: usize,
+}
+
+struct QueueState {
+    active: usize,
+    queue: VecDeque<u64>,
+    next_id: u64,
+}
+
+/// A place in the queue. Dropping it before admission gives the place up.
+pub struct Ticket {
+    shared: Arc<Shared>,
+    id: u64,
+    admitted: bool,
+}
+
+/// A running generation's slot, released on drop.
+pub struct Permit {
+    shared: Arc<Shared>,
+}
+
+impl Admission {
+    pub fn new(max_active: usize, max_queue: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(QueueState {
+                    active: 0,
+                    queue: VecDeque::new(),
+                    next_id: 0,
+                }),
+                changed: Notify::new(),
+                max_active,
+                max_queue,
+            }),
+        }
+    }
+
+    /// Join the back of the queue, or return `None` if it is already full.
+    pub fn enqueue(&self) -> Option<Ticket> {
+        let mut state = self.shared.state.lock().unwrap();
+        let free = self.shared.max_active.saturating_sub(state.active);
+        // How many would be left waiting once every free slot is taken
+        let waiting = (state.queue.len() + 1).saturating_sub(free);
+        if waiting > self.shared.max_queue {
+            return None;
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push_back(id);
+        Some(Ticket {
+            shared: Arc::clone(&self.shared),
+            id,
+            admitted: false,
+        })
+    }
+}
+
+impl Ticket {
+    /// Wait for a free slot. `on_position` gets the 1-based queue position
+    /// each time it changes while waiting.
+    pub async fn wait(mut self, mut on_position: impl FnMut(usize)) -> Permit {
+        let mut last_position = None;
+        loop {
+            // Register for wake-ups before looking, so no release is missed
+            let changed = self.shared.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.queue.front() == Some(&self.id) && state.active < self.shared.max_active {
+                    state.queue.pop_front();
+                    state.active += 1;
+                    self.admitted = true;
+                    drop(state);
+                    // The next in line may fit as well
+                    self.shared.changed.notify_waiters();
+                    return Permit {
+                        shared: Arc::clone(&self.shared),
+                    };
+                }
+
+                let position = state
+                    .queue
+                    .iter()
+                    .position(|&id| id == self.id)
+                    .unwrap_or(0)
+                    + 1;
+                if last_position != Some(position) {
+                    last_position = Some(position);
+                    on_position(position);
+                }
+            }
+
+            changed.await;
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        state.queue.retain(|&id| id != self.id);
+        drop(state);
+        self.shared.changed.notify_waiters();
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.active -= 1;
+        drop(state);
+        self.shared.changed.notify_waiters();
+    }
+}
+
+/// `503 Service Unavailable` with a `Retry-After` header, for when the queue is full.
+pub fn queue_full_response() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        "Server is busy, please retry later",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn admits_up_to_the_limit_then_queues_then_rejects() {
+        let admission = Admission::new(2, 1);
+        let first = admission.enqueue().expect("free slot");
+        let second = admission.enqueue().expect("free slot");
+        let third = admission.enqueue().expect("room in the queue");
+        assert!(admission.enqueue().is_none());
+
+        let mut positions = Vec::new();
+        let _first = first.wait(|p| positions.push(p)).await;
+        let _second = second.wait(|p| positions.push(p)).await;
+        assert!(positions.is_empty());
+        assert!(admission.enqueue().is_none());
+        drop(third);
+        assert!(admission.enqueue().is_some());
+    }
+
+    #[tokio::test]
+    async fn releasing_a_permit_admits_the_next_in_line() {
+        let admission = Admission::new(1, 2);
+        let permit = admission.enqueue().unwrap().wait(|_| {}).await;
+
+        let (positions_tx, mut positions) = tokio::sync::mpsc::unbounded_channel();
+        let ticket = admission.enqueue().unwrap();
+        let waiter =
+            tokio::spawn(async move { ticket.wait(move |p| positions_tx.send(p).unwrap()).await });
+        assert_eq!(positions.recv().await, Some(1));
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!waiter.is_finished());
+
+        drop(permit);
+        let _permit = waiter.await.unwrap();
+        let state = admission.shared.state.lock().unwrap();
+        assert_eq!((state.active, state.queue.len()), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn dropped_ticket_gives_up_its_place() {
+        let admission = Admission::new(1, 2);
+        let permit = admission.enqueue().unwrap().wait(|_| {}).await;
+        let ahead = admission.enqueue().unwrap();
+        let behind = admission.enqueue().unwrap();
+        assert!(admission.enqueue().is_none());
+
+        drop(ahead);
+        drop(permit);
+        let mut positions = Vec::new();
+        let _permit = behind.wait(|p| positions.push(p)).await;
+        assert!(positions.is_empty());
+    }
+
+    #[test]
+    fn queue_full_response_asks_to_retry() {
+        let response = queue_full_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::Serialize;
+use tokenizers::Tokenizer;
+
+use crate::stop::StopSequences;
+use crate::token_output_stream::TokenOutputStream;
+
+/// Most beams one request may ask for; each one holds its own KV cache.
+pub const MAX_BEAM_WIDTH: usize = 8;
+
+const DEFAULT_LENGTH_PENALTY: f32 = 1.0;
+
+/// Effective beam search settings for one request.
+#[derive(Debug, Clone, Serialize)]
+pub struct BeamConfig {
+    pub width: usize,
+    /// Finished answers are ranked by their summed log-probability divided by
+    /// `length ^ length_penalty`: 0 ranks by the sum, above 1 favours longer answers.
+    pub length_penalty: f32,
+    /// Stop as soon as `width` answers are finished, instead of once no live
+    /// beam can still beat them.
+    pub early_stopping: bool,
+}
+
+impl BeamConfig {
+    /// `None` when the request didn't ask for beam search.
+    pub fn new(
+        width: Option<usize>,
+        length_penalty: Option<f32>,
+        early_stopping: bool,
+    ) -> Result<Option<Self>> {
+        let Some(width) = width else {
+            if length_penalty.is_some() || early_stopping {
+                anyhow::bail!("length_penalty and early_stopping need beam_width");
+            }
+            return Ok(None);
+        };
+        if !(1..=MAX_BEAM_WIDTH).contains(&width) {
+            anyhow::bail!("beam_width must be between 1 and {MAX_BEAM_WIDTH}, got {width}");
+        }
+
+        let length_penalty = length_penalty.unwrap_or(DEFAULT_LENGTH_PENALTY);
+        if !(0.0..=2.0).contains(&length_penalty) {
+            anyhow::bail!("length_penalty must be between 0 and 2, got {length_penalty}");
+        }
+
+        Ok(Some(Self {
+            width,
+            length_penalty,
+            early_stopping,
+        }))
+    }
+
+    fn normalize(&self, score: f32, len: usize) -> f32 {
+        score / (len.max(1) as f32).powf(self.length_penalty)
+    }
+}
+
+/// A live beam: the answer tokens so far, their summed log-probability and
+/// the KV cache that has seen all of them but the last.
+pub struct Beam<C> {
+    pub tokens: Vec<u32>,
+    pub score: f32,
+    pub cache: C,
+}
+
+/// A finished answer, ranked by its length-normalized score.
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    /// Answer tokens, without the end-of-sequence token.
+    pub tokens: Vec<u32>,
+    pub score: f32,
+    /// Ended on an end-of-sequence token rather than at the token limit.
+    pub stopped: bool,
+}
+
+/// Beam search over any KV cache type. The caller runs the model for every
+/// live beam and hands its next-token log-probabilities to [`BeamSearch::step`].
+pub struct BeamSearch<C> {
+    config: BeamConfig,
+    max_steps: usize,
+    beams: Vec<Beam<C>>,
+    hypotheses: Vec<Hypothesis>,
+    done: bool,
+}
+
+impl<C: Clone> BeamSearch<C> {
+    /// Starts from a single beam whose cache holds the prompt.
+    pub fn new(config: BeamConfig, max_steps: usize, cache: C) -> Self {
+        Self {
+            config,
+            max_steps,
+            beams: vec![Beam {
+                tokens: Vec::new(),
+                score: 0.0,
+                cache,
+            }],
+            hypotheses: Vec::new(),
+            done: max_steps == 0,
+        }
+    }
+
+    pub fn config(&self) -> &BeamConfig {
+        &self.config
+    }
+
+    pub fn beams(&self) -> &[Beam<C>] {
+        &self.beams
+    }
+
+    pub fn beams_mut(&mut self) -> &mut [Beam<C>] {
+        &mut self.beams
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Extend the beams, given the log-probabilities of the next token for
+    /// each live beam in order.
+    ///
+    /// Every beam proposes its `2 * width` likeliest tokens and the best
+    /// `width` of all proposals carry on. A proposal of an end-of-sequence
+    /// token finishes that answer instead, if it ranks among the top `width`.
+    pub fn step(&mut self, logprobs: &[Vec<f32>], eos_tokens: &HashSet<u32>) -> Result<()> {
+        if logprobs.len() != self.beams.len() {
+            anyhow::bail!(
+                "got log-probabilities for {} beams, expected {}",
+                logprobs.len(),
+                self.beams.len()
+            );
+        }
+        let width = self.config.width;
+
+        let mut proposals = Vec::new();
+        for (parent, (beam, logprobs)) in self.beams.iter().zip(logprobs).enumerate() {
+            let mut top: Vec<usize> = (0..logprobs.len()).collect();
+            let k = (2 * width).min(top.len());
+            if k < top.len() {
+                top.select_nth_unstable_by(k, |&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+                top.truncate(k);
+            }
+            proposals.extend(
+                top.into_iter()
+                    .map(|token| (beam.score + logprobs[token], parent, token as u32)),
+            );
+        }
+        proposals.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut selected = Vec::with_capacity(width);
+        for (rank, &(score, parent, token)) in proposals.iter().enumerate() {
+            if selected.len() == width {
+                break;
+            }
+            if eos_tokens.contains(&token) {
+                if rank < width {
+                    let tokens = self.beams[parent].tokens.clone();
+                    // The end-of-sequence token counts towards the length
+                    self.add_hypothesis(tokens, score, 1, true);
+                }
+                continue;
+            }
+            selected.push((score, parent, token));
+        }
+
+        // Caches move to the last child of each beam and are cloned for the others
+        let mut children = vec![0usize; self.beams.len()];
+        for &(_, parent, _) in &selected {
+            children[parent] += 1;
+        }
+        let mut parents: Vec<Option<Beam<C>>> = std::mem::take(&mut self.beams)
+            .into_iter()
+            .map(Some)
+            .collect();
+        for (score, parent, token) in selected {
+            children[parent] -= 1;
+            let mut beam = if children[parent] == 0 {
+                parents[parent]
+                    .take()
+                    .ok_or_else(|| anyhow::anyhow!("beam {parent} was already moved"))?
+            } else {
+                let source = parents[parent]
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("beam {parent} was already moved"))?;
+                Beam {
+                    tokens: source.tokens.clone(),
+                    score: source.score,
+                    cache: source.cache.clone(),
+                }
+            };
+            beam.tokens.push(token);
+            beam.score = score;
+            self.beams.push(beam);
+        }
+
+        let len = self.beams.first().map_or(0, |beam| beam.tokens.len());
+        if self.beams.is_empty() || len >= self.max_steps {
+            for beam in std::mem::take(&mut self.beams) {
+                self.add_hypothesis(beam.tokens, beam.score, 0, false);
+            }
+            self.done = true;
+        } else if self.hypotheses.len() == width {
+            self.done = self.config.early_stopping || !self.can_improve(len);
+        }
+        Ok(())
+    }
+
+    // Whether the best live beam, scored at its current length, still beats the
+    // worst finished answer. Like Hugging Face's heuristic, it is not a bound:
+    // a longer answer can come out ahead under a length penalty.
+    fn can_improve(&self, len: usize) -> bool {
+        let Some(best) = self.beams.iter().map(|b| b.score).reduce(f32::max) else {
+            return false;
+        };
+        let worst = self
+            .hypotheses
+            .iter()
+            .map(|h| h.score)
+            .fold(f32::INFINITY, f32::min);
+        self.config.normalize(best, len) > worst
+    }
+
+    fn add_hypothesis(&mut self, tokens: Vec<u32>, score: f32, extra_len: usize, stopped: bool) {
+        let score = self.config.normalize(score, tokens.len() + extra_len);
+        self.hypotheses.push(Hypothesis {
+            tokens,
+            score,
+            stopped,
+        });
+        self.hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.hypotheses.truncate(self.config.width);
+    }
+
+    /// The best finished answer, or the best live beam if none has finished.
+    pub fn best(self) -> Option<Hypothesis> {
+        if let Some(best) = self.hypotheses.into_iter().next() {
+            return Some(best);
+        }
+        let config = self.config;
+        self.beams
+            .into_iter()
+            .map(|beam| Hypothesis {
+                score: config.normalize(beam.score, beam.tokens.len()),
+                tokens: beam.tokens,
+                stopped: false,
+            })
+            .max_by(|a, b| a.score.total_cmp(&b.score))
+    }
+}
+
+/// The answer text of `tokens`, cut at the first stop string; `true` when one was hit.
+pub fn answer_text(
+    tokenizer: Arc<Tokenizer>,
+    prompt_tokens: &[u32],
+    tokens: &[u32],
+    stop: Vec<String>,
+) -> Result<(String, bool)> {
+    let mut token_stream = TokenOutputStream::new(tokenizer, prompt_tokens);
+    let mut stop_sequences = StopSequences::new(stop);
+    let mut answer = String::new();
+    for &token in tokens {
+        let text = token_stream.next_token(token)?.unwrap_or_default();
+        let (text, hit_stop) = stop_sequences.push(&text);
+        answer.push_str(&text);
+        if hit_stop {
+            return Ok((answer, true));
+        }
+    }
+    let (rest, hit_stop) = stop_sequences.push(&token_stream.decode_rest()?);
+    answer.push_str(&rest);
+    if !hit_stop {
+        answer.push_str(&stop_sequences.flush());
+    }
+    Ok((answer, hit_stop))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOS: u32 = 0;
+    const A: u32 = 1;
+    const B: u32 = 2;
+
+    // Stands in for a KV cache: the tokens the model was fed through it
+    #[derive(Clone, Default)]
+    struct FakeCache(Vec<u32>);
+
+    // Probabilities of EOS, A, B and C after `tokens`. Straight away, ending
+    // (0.45) is less likely than A (0.55), but after A ending is likely (0.6).
+    fn model(tokens: &[u32]) -> Vec<f32> {
+        let probs: [f32; 4] = match tokens {
+            [] => [0.45, 0.55, 1e-4, 1e-4],
+            [A] => [0.6, 0.4, 1e-4, 1e-4],
+            [A, ..] => [0.5, 0.5, 1e-4, 1e-4],
+            _ => [0.25; 4],
+        };
+        probs.iter().map(|p| p.ln()).collect()
+    }
+
+    fn search(
+        length_penalty: f32,
+        early_stopping: bool,
+        max_steps: usize,
+    ) -> BeamSearch<FakeCache> {
+        let config = BeamConfig::new(Some(2), Some(length_penalty), early_stopping)
+            .unwrap()
+            .unwrap();
+        BeamSearch::new(config, max_steps, FakeCache::default())
+    }
+
+    // Run the search to the end; returns the best answer and the steps taken
+    fn run(mut search: BeamSearch<FakeCache>) -> (Hypothesis, usize) {
+        let eos = HashSet::from([EOS]);
+        let mut steps = 0;
+        while !search.is_done() {
+            let mut distributions = Vec::new();
+            for beam in search.beams_mut() {
+                // Each cache came down the beam's own line
+                assert!(beam.tokens.starts_with(&beam.cache.0));
+                beam.cache.0 = beam.tokens.clone();
+                distributions.push(model(&beam.tokens));
+            }
+            search.step(&distributions, &eos).unwrap();
+            steps += 1;
+        }
+        (search.best().unwrap(), steps)
+    }
+
+    #[test]
+    fn length_penalty_ranks_answers() {
+        // Summed log-probabilities favour ending straight away...
+        let (best, _) = run(search(0.0, false, 2));
+        assert!(best.tokens.is_empty());
+        assert!(best.stopped);
+        assert!((best.score - 0.45f32.ln()).abs() < 1e-5);
+
+        // ...dividing by the length favours A then EOS, over two tokens
+        for length_penalty in [1.0, 2.0] {
+            let (best, _) = run(search(length_penalty, false, 2));
+            assert_eq!(best.tokens, [A]);
+            assert!(best.stopped);
+            let expected = (0.55f32.ln() + 0.6f32.ln()) / 2f32.powf(length_penalty);
+            assert!((best.score - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn stops_once_no_beam_can_win() {
+        // Both answers are in after two steps, and the live A A beam (-1.51)
+        // can't beat the worse of them (-1.11)
+        let (best, steps) = run(search(0.0, false, 10));
+        assert_eq!(steps, 2);
+        assert!(best.tokens.is_empty());
+    }
+
+    #[test]
+    fn early_stopping() {
+        // Normalized by its length, the live A A beam (-0.76) still beats the
+        // empty answer (-0.80), so the search goes on without early stopping
+        let (best, steps) = run(search(1.0, false, 10));
+        assert!(steps > 2);
+        assert_eq!(best.tokens, [A]);
+
+        let (best, steps) = run(search(1.0, true, 10));
+        assert_eq!(steps, 2);
+        assert_eq!(best.tokens, [A]);
+    }
+
+    #[test]
+    fn token_limit_ends_live_beams() {
+        let (best, steps) = run(search(1.0, false, 1));
+        assert_eq!(steps, 1);
+        // The live A beam (-0.60) beats ending straight away (-0.80)
+        assert_eq!(best.tokens, [A]);
+        assert!(!best.stopped);
+    }
+
+    #[test]
+    fn no_steps() {
+        let search = search(1.0, false, 0);
+        assert!(search.is_done());
+        let best = search.best().unwrap();
+        assert!(best.tokens.is_empty());
+        assert_eq!(best.score, 0.0);
+        assert!(!best.stopped);
+    }
+
+    #[test]
+    fn beams_follow_their_parents() {
+        let mut search = search(1.0, false, 10);
+        let eos = HashSet::from([EOS]);
+        search.beams_mut()[0].cache.0 = vec![7];
+        search.step(&[model(&[])], &eos).unwrap();
+
+        // A and B carry on from the one beam, each with its own copy of the cache
+        let beams: Vec<_> = search.beams().iter().map(|b| b.tokens.clone()).collect();
+        assert_eq!(beams, [vec![A], vec![B]]);
+        for beam in search.beams() {
+            assert_eq!(beam.cache.0, [7]);
+        }
+        assert!((search.beams()[0].score - 0.55f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn one_distribution_per_beam() {
+        let mut search = search(1.0, false, 10);
+        let error = search.step(&[], &HashSet::from([EOS])).unwrap_err();
+        assert!(error.to_string().contains("expected 1"));
+    }
+
+    #[test]
+    fn config_validation() {
+        assert!(BeamConfig::new(None, None, false).unwrap().is_none());
+        assert!(BeamConfig::new(None, Some(1.0), false).is_err());
+        assert!(BeamConfig::new(None, None, true).is_err());
+        assert!(BeamConfig::new(Some(0), None, false).is_err());
+        assert!(BeamConfig::new(Some(MAX_BEAM_WIDTH + 1), None, false).is_err());
+        assert!(BeamConfig::new(Some(2), Some(2.5), false).is_err());
+        let config = BeamConfig::new(Some(3), None, false).unwrap().unwrap();
+        assert_eq!(config.length_penalty, DEFAULT_LENGTH_PENALTY);
+    }
+}
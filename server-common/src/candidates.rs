@@ -0,0 +1,18 @@
+use anyhow::Result;
+
+/// Most candidates one request may ask for.
+pub const MAX_CANDIDATES: usize = 8;
+
+pub fn validate(n: Option<usize>) -> Result<usize> {
+    let n = n.unwrap_or(1);
+    if !(1..=MAX_CANDIDATES).contains(&n) {
+        anyhow::bail!("n must be between 1 and {MAX_CANDIDATES}, got {n}");
+    }
+    Ok(n)
+}
+
+/// Seed for candidate `index`, so that every candidate samples differently
+/// while the request's `seed` still reproduces all of them.
+pub fn seed(seed: u64, index: usize) -> u64 {
+    seed.wrapping_add(index as u64)
+}
@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use anyhow::Result;
+use minijinja::{Environment, ErrorKind};
+use serde::{Deserialize, Serialize};
+
+const TEMPLATE_NAME: &str = "chat";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+}
+
+/// Renders a list of chat turns into the prompt format the model was trained on.
+///
+/// The template is the Jinja `chat_template` shipped in the model's
+/// `tokenizer_config.json`; if the model directory has none, the built-in
+/// template passed to [`ChatTemplate::load`] is used instead.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+// `bos_token` / `eos_token` are either a plain string or an AddedToken object.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Text(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn into_string(self) -> String {
+        match self {
+            SpecialToken::Text(s) => s,
+            SpecialToken::Added { content } => content,
+        }
+    }
+}
+
+// `chat_template` is either one template or a list of named templates.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TemplateField {
+    Single(String),
+    Named(Vec<NamedTemplate>),
+}
+
+#[derive(Deserialize)]
+struct NamedTemplate {
+    name: String,
+    template: String,
+}
+
+#[derive(Deserialize, Default)]
+struct TokenizerConfig {
+    chat_template: Option<TemplateField>,
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+}
+
+#[derive(Serialize)]
+struct RenderContext<'a> {
+    messages: &'a [ChatMessage],
+    add_generation_prompt: bool,
+    bos_token: &'a str,
+    eos_token: &'a str,
+}
+
+impl ChatTemplate {
+    pub fn load(model_dir: &Path, builtin: &str) -> Result<Self> {
+        let config_path = model_dir.join("tokenizer_config.json");
+        let config: TokenizerConfig = if config_path.exists() {
+            serde_json::from_slice(&std::fs::read(&config_path)?)?
+        } else {
+            TokenizerConfig::default()
+        };
+
+        let source = match config.chat_template {
+            Some(TemplateField::Single(t)) => t,
+            Some(TemplateField::Named(list)) => list
+                .into_iter()
+                .find(|t| t.name == "default")
+                .map(|t| t.template)
+                .unwrap_or_else(|| builtin.to_string()),
+            None => {
+                println!("[Template] No chat_template in {config_path:?}, using built-in template");
+                builtin.to_string()
+            }
+        };
+
+        let mut env = Environment::new();
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function(
+            "raise_exception",
+            |msg: String| -> Result<String, minijinja::Error> {
+                Err(minijinja::Error::new(ErrorKind::InvalidOperation, msg))
+            },
+        );
+        env.add_template_owned(TEMPLATE_NAME, source)
+            .map_err(|e| anyhow::anyhow!("invalid chat template: {e}"))?;
+
+        Ok(Self {
+            env,
+            bos_token: config
+                .bos_token
+                .map(SpecialToken::into_string)
+                .unwrap_or_default(),
+            eos_token: config
+                .eos_token
+                .map(SpecialToken::into_string)
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String> {
+        let template = self.env.get_template(TEMPLATE_NAME)?;
+        let rendered = template
+            .render(RenderContext {
+                messages,
+                add_generation_prompt,
+                bos_token: &self.bos_token,
+                eos_token: &self.eos_token,
+            })
+            .map_err(|e| anyhow::anyhow!("chat template render error: {e}"))?;
+
+        // The tokenizer adds BOS itself, so drop it if the template already did.
+        match rendered.strip_prefix(self.bos_token.as_str()) {
+            Some(rest) if !self.bos_token.is_empty() => Ok(rest.to_string()),
+            _ => Ok(rendered),
+        }
+    }
+}
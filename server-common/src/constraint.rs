@@ -0,0 +1,591 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::Result;
+use candle_core::Tensor;
+use serde_json::Value;
+use tokenizers::Tokenizer;
+
+use crate::grammar::{Grammar, GrammarState};
+use crate::json_schema;
+use crate::samplers::Sampler;
+
+/// What shape the answer has to take, from the `response_format` parameter.
+#[derive(Clone)]
+pub struct ResponseFormat {
+    grammar: Arc<Grammar>,
+    kind: FormatKind,
+}
+
+#[derive(Clone)]
+enum FormatKind {
+    JsonObject,
+    JsonSchema(Arc<Value>),
+    Grammar,
+}
+
+impl ResponseFormat {
+    /// Parse `{"type": "json_object"}`, `{"type": "json_schema", "json_schema":
+    /// {"schema": ...}}` or `{"type": "grammar", "grammar": "root ::= ..."}`.
+    pub fn parse(param: &str) -> Result<Self> {
+        let format: Value = serde_json::from_str(param)
+            .map_err(|e| anyhow::anyhow!("response_format must be a JSON object: {e}"))?;
+        let (grammar, kind) = match format.get("type").and_then(Value::as_str) {
+            Some("json_object") => (
+                json_schema::to_grammar(&serde_json::json!({ "type": "object" }))?,
+                FormatKind::JsonObject,
+            ),
+            Some("json_schema") => {
+                // OpenAI nests the schema in `json_schema`; a bare `schema` works too
+                let schema = format
+                    .pointer("/json_schema/schema")
+                    .or_else(|| format.get("schema"))
+                    .ok_or_else(|| anyhow::anyhow!("response_format json_schema needs a schema"))?;
+                (
+                    json_schema::to_grammar(schema)?,
+                    FormatKind::JsonSchema(Arc::new(schema.clone())),
+                )
+            }
+            Some("grammar") => {
+                let grammar = format
+                    .get("grammar")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow::anyhow!("response_format grammar needs a grammar"))?;
+                (grammar.to_string(), FormatKind::Grammar)
+            }
+            _ => anyhow::bail!("response_format type must be json_object, json_schema or grammar"),
+        };
+        let grammar = Grammar::parse(&grammar)
+            .map_err(|e| anyhow::anyhow!("invalid response_format: {e}"))?;
+        Ok(Self {
+            grammar: Arc::new(grammar),
+            kind,
+        })
+    }
+
+    pub fn constraint(&self, vocabulary: Arc<Vocabulary>) -> Constraint {
+        Constraint {
+            state: self.grammar.start(),
+            grammar: self.grammar.clone(),
+            partial: PartialChar::default(),
+            vocabulary,
+        }
+    }
+
+    /// Check a finished answer, which may still fall short if generation
+    /// stopped early.
+    pub fn validate(&self, answer: &str) -> Result<()> {
+        match &self.kind {
+            FormatKind::Grammar => {
+                if !self.grammar.matches(answer) {
+                    anyhow::bail!("answer doesn't match the grammar");
+                }
+            }
+            FormatKind::JsonObject => {
+                let value: Value = serde_json::from_str(answer)?;
+                if !value.is_object() {
+                    anyhow::bail!("answer is not a JSON object");
+                }
+            }
+            FormatKind::JsonSchema(schema) => {
+                json_schema::validate(&serde_json::from_str(answer)?, schema)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bytes of every token, with a trie over them to find the tokens a
+/// grammar allows without trying them one by one. Built once per tokenizer.
+///
+/// Working on bytes keeps tokens that hold only part of a character, such as
+/// byte fallback pieces, usable for text outside of ASCII.
+pub struct Vocabulary {
+    // None for tokens that never fit, such as special tokens
+    bytes: Vec<Option<Vec<u8>>>,
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    children: BTreeMap<u8, usize>,
+    tokens: Vec<u32>,
+}
+
+impl Vocabulary {
+    pub fn new(tokenizer: &Tokenizer) -> Result<Self> {
+        let decode = |ids: &[u32]| {
+            tokenizer
+                .decode(ids, true)
+                .map_err(|e| anyhow::anyhow!("tokenizer decode error: {e}"))
+        };
+        // Decoded after another token, as some tokenizers drop a leading
+        // space at the start of the text
+        let anchor = tokenizer
+            .encode("a", false)
+            .map_err(|e| anyhow::anyhow!("tokenizer encode error: {e}"))?
+            .get_ids()
+            .last()
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("tokenizer can't encode \"a\""))?;
+        let prefix = decode(&[anchor])?;
+
+        let size = tokenizer.get_vocab_size(true);
+        let mut vocabulary = Self {
+            bytes: Vec::with_capacity(size),
+            nodes: vec![TrieNode::default()],
+        };
+        for id in 0..size as u32 {
+            let bytes = match decode(&[anchor, id])?.strip_prefix(prefix.as_str()) {
+                Some("") | None => None,
+                // Part of a character decodes to U+FFFD; take the token's own bytes
+                Some(text) if text.contains('\u{fffd}') => tokenizer
+                    .id_to_token(id)
+                    .and_then(|piece| piece_bytes(&piece)),
+                Some(text) => Some(text.as_bytes().to_vec()),
+            };
+            if let Some(bytes) = &bytes {
+                vocabulary.insert(id, bytes);
+            }
+            vocabulary.bytes.push(bytes);
+        }
+        Ok(vocabulary)
+    }
+
+    fn insert(&mut self, token: u32, bytes: &[u8]) {
+        let mut node = 0;
+        for &byte in bytes {
+            node = match self.nodes[node].children.get(&byte) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(byte, child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].tokens.push(token);
+    }
+
+    fn bytes(&self, token: u32) -> Option<&[u8]> {
+        self.bytes.get(token as usize)?.as_deref()
+    }
+
+    // Every token whose bytes the grammar accepts from `state`, with `partial`
+    // left over from the tokens before
+    fn allowed(
+        &self,
+        grammar: &Grammar,
+        node: usize,
+        state: &GrammarState,
+        partial: PartialChar,
+        out: &mut Vec<u32>,
+    ) {
+        out.extend(&self.nodes[node].tokens);
+        for (&byte, &child) in &self.nodes[node].children {
+            match partial.push(byte) {
+                Some(Utf8Step::Char(c)) => {
+                    if let Some(next) = grammar.accept(state, c) {
+                        self.allowed(grammar, child, &next, PartialChar::default(), out);
+                    }
+                }
+                Some(Utf8Step::Partial(partial)) => {
+                    let (lo, hi) = partial.code_points();
+                    if grammar.accepts_any(state, lo, hi) {
+                        self.allowed(grammar, child, state, partial, out);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+// The bytes behind a token that decodes to only part of a character: a
+// `<0xE4>` byte fallback piece, or a byte-level BPE piece such as "Ġä¸"
+fn piece_bytes(piece: &str) -> Option<Vec<u8>> {
+    if let Some(hex) = piece.strip_prefix("<0x").and_then(|p| p.strip_suffix('>')) {
+        return u8::from_str_radix(hex, 16).ok().map(|byte| vec![byte]);
+    }
+    piece.chars().map(byte_level_byte).collect()
+}
+
+// Inverse of GPT-2's byte-to-character table: printable Latin-1 characters
+// stand for themselves, the other bytes for U+0100 onwards in order
+fn byte_level_byte(c: char) -> Option<u8> {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    let code = c as u32;
+    if code < 0x100 {
+        let byte = code as u8;
+        return printable(byte).then_some(byte);
+    }
+    (0..=u8::MAX)
+        .filter(|&b| !printable(b))
+        .nth((code - 0x100) as usize)
+}
+
+/// The first bytes of a character whose remaining bytes are still to come.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PartialChar {
+    bytes: [u8; 4],
+    len: u8,
+}
+
+enum Utf8Step {
+    Char(char),
+    Partial(PartialChar),
+}
+
+impl PartialChar {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a byte: completes a character, leaves it partial, or is `None`
+    /// for bytes that can't continue valid UTF-8.
+    fn push(self, byte: u8) -> Option<Utf8Step> {
+        let mut next = self;
+        next.bytes[next.len as usize] = byte;
+        next.len += 1;
+        let bytes = &next.bytes[..next.len as usize];
+
+        let width = match bytes[0] {
+            0x00..=0x7F => 1,
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => return None,
+        };
+        if bytes.len() == width {
+            let c = std::str::from_utf8(bytes).ok()?.chars().next()?;
+            return Some(Utf8Step::Char(c));
+        }
+        if bytes.len() > 1 && !(0x80..=0xBF).contains(&byte) {
+            return None;
+        }
+        // Overlong forms, surrogates and code points past U+10FFFF
+        let valid_second = match bytes {
+            [0xE0, b, ..] => *b >= 0xA0,
+            [0xED, b, ..] => *b <= 0x9F,
+            [0xF0, b, ..] => *b >= 0x90,
+            [0xF4, b, ..] => *b <= 0x8F,
+            _ => true,
+        };
+        valid_second.then_some(Utf8Step::Partial(next))
+    }
+
+    /// The code points this can still become, lowest and highest.
+    fn code_points(&self) -> (u32, u32) {
+        let bytes = &self.bytes[..self.len as usize];
+        let width = match bytes[0] {
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            _ => 4,
+        };
+        let lead_bits = [0, 0, 5, 4, 3][width];
+        let mut lo = u32::from(bytes[0]) & ((1 << lead_bits) - 1);
+        for &byte in &bytes[1..] {
+            lo = (lo << 6) | u32::from(byte & 0x3F);
+        }
+        let missing = 6 * (width - bytes.len()) as u32;
+        let lo = lo << missing;
+        let hi = lo | ((1 << missing) - 1);
+        // E0 and F0 can't start the overlong forms below their width's range,
+        // nor F4 go past U+10FFFF
+        let least = [0, 0, 0x80, 0x800, 0x10000][width];
+        (lo.max(least), hi.min(char::MAX as u32))
+    }
+}
+
+/// Keeps one sequence's answer inside its grammar, token by token.
+pub struct Constraint {
+    grammar: Arc<Grammar>,
+    state: GrammarState,
+    // Bytes of a character the last token only started
+    partial: PartialChar,
+    vocabulary: Arc<Vocabulary>,
+}
+
+impl Constraint {
+    /// Sample the next token among those the grammar allows. End-of-sequence
+    /// tokens are only allowed once the answer is complete.
+    ///
+    /// The logits are masked before sampling, so the sampler draws once per
+    /// token, as it would without a grammar.
+    pub fn sample(
+        &mut self,
+        logits: &Tensor,
+        sampler: &mut Sampler,
+        eos_tokens: &HashSet<u32>,
+    ) -> Result<u32> {
+        let mut allowed = Vec::new();
+        self.vocabulary
+            .allowed(&self.grammar, 0, &self.state, self.partial, &mut allowed);
+        if self.state.is_complete() && self.partial.is_empty() {
+            allowed.extend(eos_tokens);
+        }
+        let vocab_size = logits.dim(0)?;
+        let mut mask = vec![f32::NEG_INFINITY; vocab_size];
+        allowed
+            .iter()
+            .filter(|&&t| (t as usize) < vocab_size)
+            .for_each(|&t| mask[t as usize] = 0.0);
+        if mask.iter().all(|m| m.is_infinite()) {
+            anyhow::bail!("no token fits the response format here");
+        }
+        let mask = Tensor::from_vec(mask, vocab_size, logits.device())?;
+        let logits = logits
+            .to_dtype(candle_core::DType::F32)?
+            .broadcast_add(&mask)?;
+
+        let token = sampler.sample(&logits)?;
+        if !self.try_accept(token, eos_tokens) {
+            anyhow::bail!("sampled token {token} outside the response format");
+        }
+        Ok(token)
+    }
+
+    fn try_accept(&mut self, token: u32, eos_tokens: &HashSet<u32>) -> bool {
+        if eos_tokens.contains(&token) {
+            return self.state.is_complete() && self.partial.is_empty();
+        }
+        let Some(bytes) = self.vocabulary.bytes(token) else {
+            return false;
+        };
+        let mut state = self.state.clone();
+        let mut partial = self.partial;
+        for &byte in bytes {
+            match partial.push(byte) {
+                Some(Utf8Step::Char(c)) => {
+                    let Some(next) = self.grammar.accept(&state, c) else {
+                        return false;
+                    };
+                    state = next;
+                    partial = PartialChar::default();
+                }
+                Some(Utf8Step::Partial(next)) => partial = next,
+                None => return false,
+            }
+        }
+        self.state = state;
+        self.partial = partial;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samplers::ChainConfig;
+    use crate::sampling::SamplingConfig;
+    use crate::testing::{self, BYTE_LEVEL_EOS, BYTE_LEVEL_VOCAB_SIZE};
+    use candle_core::Device;
+
+    const VOCAB_SIZE: usize = BYTE_LEVEL_VOCAB_SIZE;
+    const EOS: u32 = BYTE_LEVEL_EOS;
+
+    fn vocabulary() -> Arc<Vocabulary> {
+        Arc::new(Vocabulary::new(&testing::byte_level()).unwrap())
+    }
+
+    fn constraint(grammar: &str) -> Constraint {
+        let format = serde_json::json!({ "type": "grammar", "grammar": grammar });
+        ResponseFormat::parse(&format.to_string())
+            .unwrap()
+            .constraint(vocabulary())
+    }
+
+    fn greedy() -> Sampler {
+        Sampler::new(&SamplingConfig::new(Some(0.0), None, None, Some(0), false).unwrap())
+    }
+
+    // Logits that rank `favoured` first, in that order
+    fn logits(favoured: &[u32]) -> Tensor {
+        let mut logits = vec![0f32; VOCAB_SIZE];
+        for (i, &token) in favoured.iter().enumerate() {
+            logits[token as usize] = 10.0 - i as f32;
+        }
+        Tensor::new(logits, &Device::Cpu).unwrap()
+    }
+
+    #[test]
+    fn vocabulary_keeps_partial_characters() {
+        let vocabulary = vocabulary();
+        assert_eq!(vocabulary.bytes(0), Some(&b"a"[..]));
+        assert_eq!(vocabulary.bytes(7), Some(&b" "[..]));
+        assert_eq!(vocabulary.bytes(8), Some(&[0xC3][..]));
+        assert_eq!(vocabulary.bytes(10), Some("é".as_bytes()));
+        assert_eq!(vocabulary.bytes(12), Some(&[0xB8, 0xAD][..]));
+        assert_eq!(vocabulary.bytes(14), Some(&[0xF0, 0x9F, 0x98][..]));
+        assert_eq!(vocabulary.bytes(EOS), None);
+    }
+
+    #[test]
+    fn piece_bytes_from_token_pieces() {
+        assert_eq!(piece_bytes("<0xE4>"), Some(vec![0xE4]));
+        assert_eq!(
+            piece_bytes("\u{120}\u{e4}\u{b8}"),
+            Some(vec![0x20, 0xE4, 0xB8])
+        );
+        assert_eq!(piece_bytes("\u{122}"), Some(vec![0x80]));
+        // Not a byte-level character
+        assert_eq!(piece_bytes("\u{2581}"), None);
+    }
+
+    #[test]
+    fn partial_characters() {
+        let partial = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .try_fold(PartialChar::default(), |p, &b| match p.push(b)? {
+                    Utf8Step::Partial(p) => Some(p),
+                    Utf8Step::Char(_) => None,
+                })
+        };
+        assert_eq!(partial(&[0xE4]).unwrap().code_points(), (0x4000, 0x4FFF));
+        assert_eq!(
+            partial(&[0xE4, 0xB8]).unwrap().code_points(),
+            (0x4E00, 0x4E3F)
+        );
+        assert_eq!(partial(&[0xC3]).unwrap().code_points(), (0xC0, 0xFF));
+        assert_eq!(partial(&[0xE0]).unwrap().code_points(), (0x800, 0xFFF));
+        assert_eq!(partial(&[0xF0]).unwrap().code_points(), (0x10000, 0x3FFFF));
+        assert_eq!(
+            partial(&[0xF4]).unwrap().code_points(),
+            (0x100000, 0x10FFFF)
+        );
+        assert_eq!(
+            partial(&[0xF0, 0x9F, 0x98]).unwrap().code_points(),
+            (0x1F600, 0x1F63F)
+        );
+        assert!(matches!(
+            partial(&[0xE4, 0xB8]).unwrap().push(0xAD),
+            Some(Utf8Step::Char('中'))
+        ));
+
+        // Continuation bytes can't start a character, nor ASCII continue one
+        assert!(partial(&[0x80]).is_none());
+        assert!(partial(&[0xC3, b'a']).is_none());
+        // Overlong forms, surrogates and code points past U+10FFFF
+        assert!(partial(&[0xC0]).is_none());
+        assert!(partial(&[0xE0, 0x80]).is_none());
+        assert!(partial(&[0xED, 0xA0]).is_none());
+        assert!(partial(&[0xF4, 0x90]).is_none());
+    }
+
+    #[test]
+    fn masks_tokens_outside_the_grammar() {
+        let mut constraint = constraint(r#"root ::= "{" "1"+ "}""#);
+        let mut sampler = greedy();
+        let eos = HashSet::from([EOS]);
+        let mut sample = |favoured: &[u32]| {
+            constraint
+                .sample(&logits(favoured), &mut sampler, &eos)
+                .unwrap()
+        };
+
+        assert_eq!(sample(&[6, 1]), 1);
+        // "}" needs a "1" before it
+        assert_eq!(sample(&[2, 13, 5]), 5);
+        // Not complete yet, so no end of sequence
+        assert_eq!(sample(&[EOS, 2]), 2);
+        assert_eq!(sample(&[5, EOS]), EOS);
+    }
+
+    #[test]
+    fn no_token_fits() {
+        let mut constraint = constraint(r#"root ::= "a""#);
+        // Done, and no end of sequence token to say so
+        assert_eq!(
+            constraint
+                .sample(&logits(&[0]), &mut greedy(), &HashSet::new())
+                .unwrap(),
+            0
+        );
+        let error = constraint
+            .sample(&logits(&[0]), &mut greedy(), &HashSet::new())
+            .unwrap_err();
+        assert!(error.to_string().contains("no token fits"));
+    }
+
+    #[test]
+    fn characters_split_across_tokens() {
+        let format =
+            ResponseFormat::parse(r#"{"type": "json_schema", "schema": {"type": "string"}}"#)
+                .unwrap();
+        let mut constraint = format.constraint(vocabulary());
+        let mut sampler = greedy();
+        let eos = HashSet::from([EOS]);
+        let vocabulary = vocabulary();
+        let mut answer = Vec::new();
+        let mut sample = |favoured: &[u32]| {
+            let token = constraint
+                .sample(&logits(favoured), &mut sampler, &eos)
+                .unwrap();
+            answer.extend(vocabulary.bytes(token).unwrap_or_default());
+            token
+        };
+
+        assert_eq!(sample(&[3]), 3);
+        // é as C3 then A9: mid-character, only a continuation byte fits
+        assert_eq!(sample(&[8]), 8);
+        assert_eq!(sample(&[EOS, 3, 13, 0, 9]), 9);
+        // 中 as E4 then B8 AD
+        assert_eq!(sample(&[11]), 11);
+        assert_eq!(sample(&[EOS, 3, 8, 12]), 12);
+        // 😀 as F0 9F 98 then 80
+        assert_eq!(sample(&[14]), 14);
+        assert_eq!(sample(&[3, 15]), 15);
+        assert_eq!(sample(&[EOS, 3]), 3);
+        assert_eq!(sample(&[2, EOS]), EOS);
+
+        let answer = String::from_utf8(answer).unwrap();
+        assert_eq!(answer, "\"é中😀\"");
+        format.validate(&answer).unwrap();
+    }
+
+    #[test]
+    fn partial_characters_outside_the_grammar() {
+        let mut constraint = constraint(r#"root ::= [a-z]+"#);
+        // C3 only leads to U+00C0..U+00FF, none of them in a-z
+        let token = constraint
+            .sample(&logits(&[8, 10, 0]), &mut greedy(), &HashSet::from([EOS]))
+            .unwrap();
+        assert_eq!(token, 0);
+    }
+
+    #[test]
+    fn samples_once_per_token() {
+        // Mirostat updates mu and draws from the RNG on every sample, so a
+        // second draw would send it down a different path
+        let chain = ChainConfig::new(None, None, None, Some(2), Some(3.0), Some(0.1)).unwrap();
+        let sampling = SamplingConfig::new(Some(1.0), Some(1.0), None, Some(7), false)
+            .unwrap()
+            .with_chain(chain);
+        let (mut constrained, mut reference) = (Sampler::new(&sampling), Sampler::new(&sampling));
+
+        let mut constraint = constraint(r#"root ::= ( "a" | "1" | "2" )*"#);
+        let eos = HashSet::from([EOS]);
+        let allowed = [0, 5, 6, EOS];
+        let raw: Vec<f32> = (0..VOCAB_SIZE).map(|i| (i * 7 % 11) as f32 * 0.4).collect();
+        let masked: Vec<f32> = raw
+            .iter()
+            .enumerate()
+            .map(|(i, &l)| {
+                if allowed.contains(&(i as u32)) {
+                    l
+                } else {
+                    f32::NEG_INFINITY
+                }
+            })
+            .collect();
+        let raw = Tensor::new(raw, &Device::Cpu).unwrap();
+        let masked = Tensor::new(masked, &Device::Cpu).unwrap();
+
+        for _ in 0..20 {
+            let token = constraint.sample(&raw, &mut constrained, &eos).unwrap();
+            assert_eq!(token, reference.sample(&masked).unwrap());
+        }
+    }
+}
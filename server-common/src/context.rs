@@ -0,0 +1,261 @@
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+use crate::chat_template::{ChatMessage, ChatTemplate};
+
+/// Encoded prompt that fits into the model's context window.
+pub struct PromptContext {
+    pub tokens: Vec<u32>,
+    /// Number of user/assistant turns dropped from the start of the history.
+    pub trimmed_turns: usize,
+    /// Whether the latest prompt itself had to be cut.
+    pub truncated: bool,
+}
+
+/// Tokens available for the prompt once `max_new_tokens` are set aside for the answer.
+pub fn prompt_budget(max_context: usize, max_new_tokens: usize) -> usize {
+    // Never let the reservation eat more than half of a small context
+    let reserve = max_new_tokens.min(max_context / 2);
+    max_context - reserve
+}
+
+pub fn encode(tokenizer: &Tokenizer, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+    let encoding = tokenizer
+        .encode(text, add_special_tokens)
+        .map_err(|e| anyhow::anyhow!("tokenizer encode error: {e}"))?;
+    Ok(encoding.get_ids().to_vec())
+}
+
+/// Plain completion: keep the most recent `budget` tokens of the prompt.
+pub fn fit_raw(tokenizer: &Tokenizer, prompt: &str, budget: usize) -> Result<PromptContext> {
+    let mut tokens = encode(tokenizer, prompt, true)?;
+    if tokens.is_empty() {
+        anyhow::bail!("prompt encodes to no tokens");
+    }
+    let truncated = tokens.len() > budget;
+    if truncated {
+        tokens.drain(..tokens.len() - budget);
+    }
+
+    Ok(PromptContext {
+        tokens,
+        trimmed_turns: 0,
+        truncated,
+    })
+}
+
+/// Tokens every chat prompt with this system message starts with.
+///
+/// The last token is left out, as it may merge with the text rendered after it.
+pub fn system_prefix(
+    tokenizer: &Tokenizer,
+    template: &ChatTemplate,
+    system: &str,
+) -> Result<Vec<u32>> {
+    let rendered = template.render(&[ChatMessage::new("system", system)], false)?;
+    let mut tokens = encode(tokenizer, &rendered, true)?;
+    tokens.pop();
+    Ok(tokens)
+}
+
+/// Render `messages` and drop the oldest turns until the prompt fits in `budget`.
+///
+/// A leading system message and the final user message are always kept; if
+/// those two alone are still too long, the start of the user message is cut.
+pub fn fit_chat(
+    tokenizer: &Tokenizer,
+    template: &ChatTemplate,
+    mut messages: Vec<ChatMessage>,
+    budget: usize,
+) -> Result<PromptContext> {
+    let first_turn = match messages.first() {
+        Some(m) if m.role == "system" => 1,
+        _ => 0,
+    };
+
+    let mut trimmed_turns = 0;
+    loop {
+        let tokens = encode(tokenizer, &template.render(&messages, true)?, true)?;
+        if tokens.len() <= budget {
+            return Ok(PromptContext {
+                tokens,
+                trimmed_turns,
+                truncated: false,
+            });
+        }
+
+        // Only the system prompt and the latest user message are left
+        if messages.len() <= first_turn + 1 {
+            break;
+        }
+
+        // Drop one user turn together with the assistant reply that follows it
+        messages.remove(first_turn);
+        if messages.len() > first_turn + 1 && messages[first_turn].role == "assistant" {
+            messages.remove(first_turn);
+        }
+        trimmed_turns += 1;
+    }
+
+    // Cut the front of the latest prompt, keeping its end (usually the actual question)
+    let last = messages.len() - 1;
+    loop {
+        let tokens = encode(tokenizer, &template.render(&messages, true)?, true)?;
+        if tokens.len() <= budget {
+            return Ok(PromptContext {
+                tokens,
+                trimmed_turns,
+                truncated: true,
+            });
+        }
+
+        let content_tokens = encode(tokenizer, &messages[last].content, false)?;
+        if content_tokens.is_empty() {
+            anyhow::bail!(
+                "system prompt alone ({} tokens) exceeds the prompt budget of {budget} tokens",
+                tokens.len()
+            );
+        }
+
+        let overflow = (tokens.len() - budget).min(content_tokens.len());
+        messages[last].content = tokenizer
+            .decode(&content_tokens[overflow..], false)
+            .map_err(|e| anyhow::anyhow!("tokenizer decode error: {e}"))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // One token per byte with the test tokenizer, so prompts are as long as they render
+    const TEMPLATE: &str = "{% for m in messages %}<{{ m.role }}>{{ m.content }}{% endfor %}\
+                            {% if add_generation_prompt %}<assistant>{% endif %}";
+
+    fn template() -> ChatTemplate {
+        ChatTemplate::load(std::path::Path::new("no-model-dir"), TEMPLATE).unwrap()
+    }
+
+    fn messages(turns: &[(&str, &str)]) -> Vec<ChatMessage> {
+        turns
+            .iter()
+            .map(|(role, content)| ChatMessage::new(role, content))
+            .collect()
+    }
+
+    fn rendered_len(turns: &[(&str, &str)]) -> usize {
+        template().render(&messages(turns), true).unwrap().len()
+    }
+
+    fn text(tokenizer: &Tokenizer, tokens: &[u32]) -> String {
+        tokenizer.decode(tokens, false).unwrap()
+    }
+
+    #[test]
+    fn budget_reserves_the_answer() {
+        assert_eq!(prompt_budget(2048, 256), 1792);
+        // At most half of a small context
+        assert_eq!(prompt_budget(64, 256), 32);
+        assert_eq!(prompt_budget(64, 0), 64);
+    }
+
+    #[test]
+    fn raw_keeps_the_end() {
+        let tokenizer = testing::byte_level();
+        let context = fit_raw(&tokenizer, "0123456789", 4).unwrap();
+        assert_eq!(text(&tokenizer, &context.tokens), "6789");
+        assert!(context.truncated);
+        assert_eq!(context.trimmed_turns, 0);
+
+        let context = fit_raw(&tokenizer, "0123", 4).unwrap();
+        assert_eq!(text(&tokenizer, &context.tokens), "0123");
+        assert!(!context.truncated);
+
+        assert!(fit_raw(&tokenizer, "", 4).is_err());
+    }
+
+    #[test]
+    fn chat_that_fits_is_untouched() {
+        let tokenizer = testing::byte_level();
+        let turns = [
+            ("system", "S"),
+            ("user", "hi"),
+            ("assistant", "yo"),
+            ("user", "q"),
+        ];
+        let context = fit_chat(&tokenizer, &template(), messages(&turns), 100).unwrap();
+        assert_eq!(
+            text(&tokenizer, &context.tokens),
+            "<system>S<user>hi<assistant>yo<user>q<assistant>"
+        );
+        assert_eq!(context.trimmed_turns, 0);
+        assert!(!context.truncated);
+    }
+
+    #[test]
+    fn drops_oldest_turns_and_keeps_the_system_message() {
+        let tokenizer = testing::byte_level();
+        let turns = [
+            ("system", "S"),
+            ("user", "first"),
+            ("assistant", "one"),
+            ("user", "second"),
+            ("assistant", "two"),
+            ("user", "q"),
+        ];
+        let kept = [
+            ("system", "S"),
+            ("user", "second"),
+            ("assistant", "two"),
+            ("user", "q"),
+        ];
+        let budget = rendered_len(&kept);
+        let context = fit_chat(&tokenizer, &template(), messages(&turns), budget).unwrap();
+        assert_eq!(
+            text(&tokenizer, &context.tokens),
+            "<system>S<user>second<assistant>two<user>q<assistant>"
+        );
+        assert_eq!(context.trimmed_turns, 1);
+        assert!(!context.truncated);
+
+        // One token less and the second pair goes too
+        let context = fit_chat(&tokenizer, &template(), messages(&turns), budget - 1).unwrap();
+        assert_eq!(
+            text(&tokenizer, &context.tokens),
+            "<system>S<user>q<assistant>"
+        );
+        assert_eq!(context.trimmed_turns, 2);
+    }
+
+    #[test]
+    fn cuts_the_front_of_the_last_message() {
+        let tokenizer = testing::byte_level();
+        let turns = [
+            ("system", "S"),
+            ("user", "old"),
+            ("assistant", "reply"),
+            ("user", "0123456789"),
+        ];
+        let budget = rendered_len(&[("system", "S"), ("user", "56789")]);
+        let context = fit_chat(&tokenizer, &template(), messages(&turns), budget).unwrap();
+        assert_eq!(
+            text(&tokenizer, &context.tokens),
+            "<system>S<user>56789<assistant>"
+        );
+        assert_eq!(context.tokens.len(), budget);
+        assert_eq!(context.trimmed_turns, 1);
+        assert!(context.truncated);
+    }
+
+    #[test]
+    fn system_prompt_alone_too_long() {
+        let tokenizer = testing::byte_level();
+        let turns = [("system", "a long system prompt"), ("user", "q")];
+        let budget = rendered_len(&[("system", "a long system prompt"), ("user", "")]) - 1;
+        let error = fit_chat(&tokenizer, &template(), messages(&turns), budget)
+            .err()
+            .unwrap();
+        assert!(error.to_string().starts_with("system prompt alone"));
+    }
+}
@@ -1,17 +1,19 @@
+use std::path::Path;
+
 use anyhow::Result;
-use serde::Serialize;
 use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
 
 pub type DbPool = SqlitePool;
 
 use sqlx::Row;
-use sqlx::{Pool, Sqlite};
 
 #[derive(Debug, serde::Serialize)]
 pub struct MessageRow {
     pub role: String,
     pub content: String,
     pub created_at: String,
+    /// Which of a turn's candidate answers this is; 0 for everything else.
+    pub candidate: i64,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -21,9 +23,10 @@ pub struct SessionWithMessages {
     pub messages: Vec<MessageRow>,
 }
 
-pub async fn init_db() -> Result<DbPool> {
-    let mut db_path = std::env::current_dir()?;
-    db_path.push("chat.db");
+/// Open (creating if needed) the SQLite database; relative paths are taken
+/// from the current directory.
+pub async fn init_db(db_path: &Path) -> Result<DbPool> {
+    let db_path = std::env::current_dir()?.join(db_path);
 
     if let Some(parent) = db_path.parent() {
         if !parent.exists() {
@@ -83,15 +86,29 @@ pub async fn init_db() -> Result<DbPool> {
     .execute(&pool)
     .await?;
 
+    // Databases created before answers could have sibling candidates
+    let has_candidate: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM pragma_table_info('messages') WHERE name = 'candidate'",
+    )
+    .fetch_one(&pool)
+    .await?;
+    if has_candidate == 0 {
+        sqlx::query("ALTER TABLE messages ADD COLUMN candidate INTEGER NOT NULL DEFAULT 0")
+            .execute(&pool)
+            .await?;
+    }
+
     println!("[DB] Database initialized successfully.");
     Ok(pool)
 }
 
+/// Save a user prompt and its answers, each with its candidate index. Several
+/// answers are stored as siblings right after the prompt.
 pub async fn save_chat_turn(
     pool: &DbPool,
     session_id: &str,
     user_prompt: &str,
-    assistant_reply: &str,
+    assistant_replies: &[(usize, &str)],
 ) -> Result<()> {
     sqlx::query(
         r#"
@@ -114,18 +131,54 @@ pub async fn save_chat_turn(
     .execute(pool)
     .await?;
 
-    sqlx::query(
+    for &(candidate, reply) in assistant_replies {
+        sqlx::query(
+            r#"
+            INSERT INTO messages (session_id, role, content, candidate)
+            VALUES (?1, 'assistant', ?2, ?3);
+            "#,
+        )
+        .bind(session_id)
+        .bind(reply)
+        .bind(candidate as i64)
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// The session's conversation. Of sibling candidate answers only the first
+/// is part of it.
+pub async fn load_session_messages(pool: &DbPool, session_id: &str) -> Result<Vec<MessageRow>> {
+    // Order by id: turns saved in the same second share a created_at
+    let rows = sqlx::query(
         r#"
-        INSERT INTO messages (session_id, role, content)
-        VALUES (?1, 'assistant', ?2);
+        SELECT role, content, created_at, candidate
+        FROM messages
+        WHERE session_id = ?
+        ORDER BY id ASC
         "#,
     )
     .bind(session_id)
-    .bind(assistant_reply)
-    .execute(pool)
+    .fetch_all(pool)
     .await?;
 
-    Ok(())
+    let mut messages: Vec<MessageRow> = Vec::new();
+    for row in rows {
+        let role: String = row.get("role");
+        let sibling =
+            role == "assistant" && messages.last().is_some_and(|last| last.role == "assistant");
+        if !sibling {
+            messages.push(MessageRow {
+                role,
+                content: row.get("content"),
+                created_at: row.get("created_at"),
+                candidate: row.get("candidate"),
+            });
+        }
+    }
+    Ok(messages)
 }
 
 pub async fn load_all_history(pool: &DbPool) -> Result<Vec<SessionWithMessages>> {
@@ -147,10 +200,10 @@ pub async fn load_all_history(pool: &DbPool) -> Result<Vec<SessionWithMessages>>
 
         let messages = sqlx::query(
             r#"
-            SELECT role, content, created_at
+            SELECT role, content, created_at, candidate
             FROM messages
             WHERE session_id = ?
-            ORDER BY created_at ASC
+            ORDER BY created_at ASC, id ASC
             "#,
         )
         .bind(&session_id)
@@ -163,6 +216,7 @@ pub async fn load_all_history(pool: &DbPool) -> Result<Vec<SessionWithMessages>>
                 role: row.get("role"),
                 content: row.get("content"),
                 created_at: row.get("created_at"),
+                candidate: row.get("candidate"),
             })
             .collect::<Vec<_>>();
 
@@ -0,0 +1,47 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+use serde_json::Value;
+use tokenizers::Tokenizer;
+
+// End-of-turn markers used by the chat formats we serve
+const SPECIAL_EOS_TOKENS: &[&str] = &["</s>", "<|im_end|>", "<|endoftext|>", "<|eot_id|>"];
+
+/// Collect every token id that should end generation.
+///
+/// `eos_token_id` (a single id or a list) is read from `generation_config.json`
+/// and `config.json`; known end-of-turn special tokens present in the
+/// tokenizer's vocab are added on top.
+pub fn load_eos_tokens(model_dir: &Path, tokenizer: &Tokenizer) -> Result<HashSet<u32>> {
+    let mut eos_tokens = HashSet::new();
+
+    for file in ["generation_config.json", "config.json"] {
+        let path = model_dir.join(file);
+        if !path.exists() {
+            continue;
+        }
+        let json: Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+        match json.get("eos_token_id") {
+            Some(Value::Number(id)) => eos_tokens.extend(id.as_u64().map(|id| id as u32)),
+            Some(Value::Array(ids)) => {
+                eos_tokens.extend(ids.iter().filter_map(|id| id.as_u64()).map(|id| id as u32))
+            }
+            _ => {}
+        }
+    }
+
+    let vocab = tokenizer.get_vocab(true);
+    eos_tokens.extend(
+        SPECIAL_EOS_TOKENS
+            .iter()
+            .filter_map(|t| vocab.get(*t).copied()),
+    );
+
+    if eos_tokens.is_empty() {
+        anyhow::bail!("no end-of-sequence token found for model in {model_dir:?}");
+    }
+
+    println!("[Model] End-of-sequence token ids: {eos_tokens:?}");
+    Ok(eos_tokens)
+}
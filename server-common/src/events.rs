@@ -0,0 +1,66 @@
+use axum::response::sse::Event;
+use serde_json::Value;
+
+/// What a generation reports while it runs. Each endpoint turns these into
+/// the SSE events of its own API.
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    /// A piece of answer text; `candidate` is set when there are several.
+    Text {
+        candidate: Option<usize>,
+        text: String,
+    },
+    /// A named JSON event: `queued`, `context`, `logprobs` or `summary`.
+    Json {
+        name: &'static str,
+        candidate: Option<usize>,
+        data: Value,
+    },
+    /// Every candidate has finished.
+    Done,
+}
+
+impl StreamEvent {
+    pub fn text(candidate: Option<usize>, text: String) -> Self {
+        Self::Text { candidate, text }
+    }
+
+    pub fn json(name: &'static str, candidate: Option<usize>, data: Value) -> Self {
+        Self::Json {
+            name,
+            candidate,
+            data,
+        }
+    }
+
+    /// The `/chat/stream` event. Text of a single candidate (`None`) is the
+    /// plain `message` event; otherwise a `candidate` event with `{"index", "text"}`.
+    /// JSON events of a candidate get its `index` added.
+    pub fn into_sse(self) -> Event {
+        match self {
+            Self::Text {
+                candidate: None,
+                text,
+            } => Event::default().data(text),
+            Self::Text {
+                candidate: Some(index),
+                text,
+            } => {
+                let data = serde_json::json!({ "index": index, "text": text });
+                Event::default().event("candidate").data(data.to_string())
+            }
+            Self::Json {
+                name,
+                candidate,
+                mut data,
+            } => {
+                if let (Some(index), Some(object)) = (candidate, data.as_object_mut()) {
+                    object.insert("index".to_string(), index.into());
+                }
+                Event::default().event(name).data(data.to_string())
+            }
+            // keep same event name as normal tokens so the frontend knows to stop
+            Self::Done => Event::default().event("message").data("[DONE]"),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+use crate::context::{self, PromptContext};
+
+// How tokenizers spell the prefix, suffix and middle tokens: Qwen2.5 style,
+// then StarCoder style
+const SPELLINGS: [[&str; 3]; 2] = [
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+];
+
+/// The special tokens of a fill-in-the-middle prompt.
+#[derive(Debug, Clone, Copy)]
+pub struct FimTokens {
+    prefix: u32,
+    suffix: u32,
+    middle: u32,
+}
+
+impl FimTokens {
+    /// `None` when the tokenizer has no fill-in-the-middle tokens.
+    pub fn detect(tokenizer: &Tokenizer) -> Option<Self> {
+        SPELLINGS.iter().find_map(|[prefix, suffix, middle]| {
+            Some(Self {
+                prefix: tokenizer.token_to_id(prefix)?,
+                suffix: tokenizer.token_to_id(suffix)?,
+                middle: tokenizer.token_to_id(middle)?,
+            })
+        })
+    }
+}
+
+/// Prefix-suffix-middle prompt: the model writes what goes between `prefix`
+/// and `suffix`. Over `budget`, the start of the prefix and the end of the
+/// suffix are cut, the suffix to no less than half of what is left.
+pub fn fit(
+    tokenizer: &Tokenizer,
+    prefix: &str,
+    suffix: &str,
+    budget: usize,
+) -> Result<PromptContext> {
+    let Some(fim) = FimTokens::detect(tokenizer) else {
+        anyhow::bail!("this model has no fill-in-the-middle tokens");
+    };
+    let mut prefix_tokens = context::encode(tokenizer, prefix, false)?;
+    let mut suffix_tokens = context::encode(tokenizer, suffix, false)?;
+
+    let available = budget.saturating_sub(3);
+    let truncated = prefix_tokens.len() + suffix_tokens.len() > available;
+    if truncated {
+        let suffix_len = suffix_tokens
+            .len()
+            .min((available / 2).max(available.saturating_sub(prefix_tokens.len())));
+        suffix_tokens.truncate(suffix_len);
+        let prefix_len = available - suffix_len;
+        prefix_tokens.drain(..prefix_tokens.len() - prefix_len);
+    }
+
+    let mut tokens = Vec::with_capacity(prefix_tokens.len() + suffix_tokens.len() + 3);
+    tokens.push(fim.prefix);
+    tokens.extend(prefix_tokens);
+    tokens.push(fim.suffix);
+    tokens.extend(suffix_tokens);
+    tokens.push(fim.middle);
+
+    Ok(PromptContext {
+        tokens,
+        trimmed_turns: 0,
+        truncated,
+    })
+}
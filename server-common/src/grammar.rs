@@ -0,0 +1,648 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
+/// Deepest a rule may nest inside itself without consuming a character;
+/// only left-recursive grammars get there.
+const MAX_EXPANSION_DEPTH: usize = 256;
+
+/// A context-free grammar in llama.cpp's GBNF notation:
+///
+/// ```text
+/// root   ::= object
+/// object ::= "{" pair ("," pair)* "}"
+/// pair   ::= [a-z]+ ":" [0-9]+   # comments run to the end of the line
+/// ```
+///
+/// Supported are `"literals"`, `[a-z]` / `[^"]` character classes, `.` for any
+/// character, rule references, `( ... )` groups, `|` alternatives and the
+/// `*`, `+` and `?` repetitions. Generation starts at the `root` rule.
+#[derive(Debug)]
+pub struct Grammar {
+    // rule -> alternatives -> sequence of elements
+    rules: Vec<Vec<Vec<Element>>>,
+}
+
+#[derive(Debug, Clone)]
+enum Element {
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> Option<bool> {
+        match self {
+            Element::Char { ranges, negated } => {
+                let inside = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                Some(inside != *negated)
+            }
+            Element::Rule(_) => None,
+        }
+    }
+
+    // Whether some character in `lo..=hi` matches
+    fn matches_any(&self, lo: u32, hi: u32) -> bool {
+        let Element::Char { ranges, negated } = self else {
+            return false;
+        };
+        let mut ranges: Vec<(u32, u32)> = ranges
+            .iter()
+            .map(|&(a, b)| (a as u32, b as u32))
+            .filter(|&(a, b)| a <= hi && lo <= b)
+            .collect();
+        if !negated {
+            return !ranges.is_empty();
+        }
+        // A negated class matches unless its ranges cover all of `lo..=hi`
+        ranges.sort_unstable();
+        let mut next = lo;
+        for (a, b) in ranges {
+            if a > next {
+                return true;
+            }
+            next = next.max(b.saturating_add(1));
+            if next > hi {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Where a parse continues: element `index` of alternative `alt` of `rule`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Position {
+    rule: u32,
+    alt: u32,
+    index: u32,
+}
+
+/// How far the text seen so far can be parsed: every way of continuing it,
+/// each as a stack of positions with the next character to match on top.
+/// An empty stack means the text is complete.
+#[derive(Debug, Clone)]
+pub struct GrammarState {
+    stacks: Vec<Vec<Position>>,
+}
+
+impl Grammar {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rules: Vec::new(),
+            defined: Vec::new(),
+        };
+        parser.parse_rules()?;
+
+        let Some(&root) = parser.names.get("root") else {
+            anyhow::bail!("grammar has no root rule");
+        };
+        if root != 0 {
+            // The root rule always goes first
+            parser.rules.swap(0, root);
+            parser.defined.swap(0, root);
+            for alts in &mut parser.rules {
+                for element in alts.iter_mut().flatten() {
+                    if let Element::Rule(r) = element {
+                        *r = match *r {
+                            r if r == root => 0,
+                            0 => root,
+                            r => r,
+                        };
+                    }
+                }
+            }
+            for id in parser.names.values_mut() {
+                *id = match *id {
+                    id if id == root => 0,
+                    0 => root,
+                    id => id,
+                };
+            }
+        }
+        if let Some((name, _)) = parser.names.iter().find(|(_, &id)| !parser.defined[id]) {
+            anyhow::bail!("grammar rule {name:?} is used but never defined");
+        }
+        Ok(Self {
+            rules: parser.rules,
+        })
+    }
+
+    /// The state before any text.
+    pub fn start(&self) -> GrammarState {
+        let mut stacks = Vec::new();
+        for alt in 0..self.rules[0].len() {
+            let position = Position {
+                rule: 0,
+                alt: alt as u32,
+                index: 0,
+            };
+            self.expand(vec![position], &mut stacks, 0);
+        }
+        GrammarState::new(stacks)
+    }
+
+    /// The state after `c`, or `None` if the grammar doesn't allow it here.
+    pub fn accept(&self, state: &GrammarState, c: char) -> Option<GrammarState> {
+        let mut stacks = Vec::new();
+        for stack in &state.stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if self.element(top).and_then(|e| e.matches(c)) != Some(true) {
+                continue;
+            }
+            let mut next = stack.clone();
+            next.pop();
+            next.push(Position {
+                index: top.index + 1,
+                ..top
+            });
+            self.expand(next, &mut stacks, 0);
+        }
+        (!stacks.is_empty()).then(|| GrammarState::new(stacks))
+    }
+
+    /// Whether some character with a code point in `lo..=hi` may come next,
+    /// for text that so far ends in part of a character.
+    pub fn accepts_any(&self, state: &GrammarState, lo: u32, hi: u32) -> bool {
+        state.stacks.iter().any(|stack| {
+            stack
+                .last()
+                .and_then(|&top| self.element(top))
+                .is_some_and(|element| element.matches_any(lo, hi))
+        })
+    }
+
+    /// The state after all of `text`.
+    pub fn accept_str(&self, state: &GrammarState, text: &str) -> Option<GrammarState> {
+        let mut state = state.clone();
+        for c in text.chars() {
+            state = self.accept(&state, c)?;
+        }
+        Some(state)
+    }
+
+    /// Whether all of `text` is a complete sentence of the grammar.
+    pub fn matches(&self, text: &str) -> bool {
+        self.accept_str(&self.start(), text)
+            .is_some_and(|state| state.is_complete())
+    }
+
+    fn element(&self, position: Position) -> Option<&Element> {
+        self.rules[position.rule as usize][position.alt as usize].get(position.index as usize)
+    }
+
+    // Resolve rule references on top of `stack` until a character element is
+    // on top, pushing every stack that results
+    fn expand(&self, mut stack: Vec<Position>, out: &mut Vec<Vec<Position>>, depth: usize) {
+        if depth > MAX_EXPANSION_DEPTH {
+            return;
+        }
+        loop {
+            let Some(&top) = stack.last() else {
+                out.push(stack);
+                return;
+            };
+            match self.element(top) {
+                // End of this alternative, continue in the rule that referenced it
+                None => {
+                    stack.pop();
+                }
+                Some(Element::Char { .. }) => {
+                    out.push(stack);
+                    return;
+                }
+                Some(&Element::Rule(rule)) => {
+                    stack.pop();
+                    // Nothing to come back to at the end of an alternative, which
+                    // keeps repetitions from growing the stack
+                    let next = Position {
+                        index: top.index + 1,
+                        ..top
+                    };
+                    if self.element(next).is_some() {
+                        stack.push(next);
+                    }
+                    for alt in 0..self.rules[rule].len() {
+                        let mut next = stack.clone();
+                        next.push(Position {
+                            rule: rule as u32,
+                            alt: alt as u32,
+                            index: 0,
+                        });
+                        self.expand(next, out, depth + 1);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl GrammarState {
+    fn new(mut stacks: Vec<Vec<Position>>) -> Self {
+        let mut seen = HashSet::new();
+        stacks.retain(|stack| seen.insert(stack.clone()));
+        Self { stacks }
+    }
+
+    /// Whether the text so far may end here.
+    pub fn is_complete(&self) -> bool {
+        self.stacks.iter().any(|stack| stack.is_empty())
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    rules: Vec<Vec<Vec<Element>>>,
+    defined: Vec<bool>,
+}
+
+impl Parser {
+    fn parse_rules(&mut self) -> Result<()> {
+        self.skip_space();
+        while self.pos < self.chars.len() {
+            let name = self.name()?;
+            self.skip_space();
+            if !self.eat_str("::=") {
+                anyhow::bail!("expected ::= after rule {name:?}");
+            }
+            let id = self.rule_id(&name);
+            if self.defined[id] {
+                anyhow::bail!("grammar rule {name:?} is defined twice");
+            }
+            let alts = self.alternatives()?;
+            self.rules[id] = alts;
+            self.defined[id] = true;
+            self.skip_space();
+        }
+        Ok(())
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Element>>> {
+        let mut alts = vec![self.sequence()?];
+        while self.eat('|') {
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Element>> {
+        let mut sequence = Vec::new();
+        loop {
+            self.skip_space();
+            let Some(c) = self.peek() else {
+                return Ok(sequence);
+            };
+            if c == '|' || c == ')' || self.at_rule_start() {
+                return Ok(sequence);
+            }
+
+            let start = sequence.len();
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    while !self.eat('"') {
+                        let c = self.literal_char()?;
+                        sequence.push(Element::Char {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        });
+                    }
+                }
+                '[' => {
+                    self.pos += 1;
+                    sequence.push(self.char_class()?);
+                }
+                '.' => {
+                    self.pos += 1;
+                    sequence.push(Element::Char {
+                        ranges: Vec::new(),
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    let alts = self.alternatives()?;
+                    if !self.eat(')') {
+                        anyhow::bail!("unclosed ( in grammar");
+                    }
+                    sequence.push(Element::Rule(self.new_rule(alts)));
+                }
+                c if is_name_char(c) => {
+                    let name = self.name()?;
+                    sequence.push(Element::Rule(self.rule_id(&name)));
+                }
+                c => anyhow::bail!("unexpected {c:?} in grammar"),
+            }
+
+            let repeat = match self.peek() {
+                Some(c @ ('*' | '+' | '?')) => c,
+                _ => continue,
+            };
+            self.pos += 1;
+            let item = sequence.split_off(start);
+            let item_rule = Element::Rule(self.new_rule(vec![item]));
+            match repeat {
+                // R ::= item R |
+                '*' | '+' => {
+                    let id = self.new_rule(Vec::new());
+                    self.rules[id] = vec![vec![item_rule.clone(), Element::Rule(id)], Vec::new()];
+                    if repeat == '+' {
+                        sequence.push(item_rule);
+                    }
+                    sequence.push(Element::Rule(id));
+                }
+                // R ::= item |
+                _ => {
+                    let id = self.new_rule(vec![vec![item_rule], Vec::new()]);
+                    sequence.push(Element::Rule(id));
+                }
+            }
+        }
+    }
+
+    fn char_class(&mut self) -> Result<Element> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        while !self.eat(']') {
+            let lo = self.literal_char()?;
+            let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                self.literal_char()?
+            } else {
+                lo
+            };
+            ranges.push((lo, hi));
+        }
+        Ok(Element::Char { ranges, negated })
+    }
+
+    // One character of a literal or class, with escapes resolved
+    fn literal_char(&mut self) -> Result<char> {
+        let Some(c) = self.peek() else {
+            anyhow::bail!("unterminated literal in grammar");
+        };
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let Some(escaped) = self.peek() else {
+            anyhow::bail!("unterminated escape in grammar");
+        };
+        self.pos += 1;
+        Ok(match escaped {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'x' => self.hex_char(2)?,
+            'u' => self.hex_char(4)?,
+            'U' => self.hex_char(8)?,
+            other => other,
+        })
+    }
+
+    fn hex_char(&mut self, digits: usize) -> Result<char> {
+        let end = self.pos + digits;
+        let hex: String = self
+            .chars
+            .get(self.pos..end)
+            .unwrap_or_default()
+            .iter()
+            .collect();
+        let code = u32::from_str_radix(&hex, 16)
+            .map_err(|_| anyhow::anyhow!("invalid escape \\{hex} in grammar"))?;
+        self.pos = end;
+        char::from_u32(code)
+            .ok_or_else(|| anyhow::anyhow!("invalid character {code:#x} in grammar"))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            anyhow::bail!("expected a rule name in grammar");
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    // A rule name followed by ::= starts the next rule
+    fn at_rule_start(&self) -> bool {
+        let mut pos = self.pos;
+        while self.chars.get(pos).copied().is_some_and(is_name_char) {
+            pos += 1;
+        }
+        if pos == self.pos {
+            return false;
+        }
+        while self.chars.get(pos).is_some_and(|c| c.is_whitespace()) {
+            pos += 1;
+        }
+        self.chars.get(pos..pos + 3) == Some(&[':', ':', '='])
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        let id = self.new_rule(Vec::new());
+        self.defined[id] = false;
+        self.names.insert(name.to_string(), id);
+        id
+    }
+
+    fn new_rule(&mut self, alts: Vec<Vec<Element>>) -> usize {
+        self.rules.push(alts);
+        self.defined.push(true);
+        self.rules.len() - 1
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        let expected: Vec<char> = s.chars().collect();
+        if self.chars.get(self.pos..self.pos + expected.len()) == Some(&expected[..]) {
+            self.pos += expected.len();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grammar(source: &str) -> Grammar {
+        Grammar::parse(source).unwrap()
+    }
+
+    #[test]
+    fn repetitions() {
+        let g = grammar(r#"root ::= "a"+ "b"* "c"?"#);
+        for text in ["a", "aaa", "ab", "abbb", "ac", "aabbc"] {
+            assert!(g.matches(text), "{text:?} should match");
+        }
+        for text in ["", "b", "bc", "acc", "aca", "abcb"] {
+            assert!(!g.matches(text), "{text:?} should not match");
+        }
+    }
+
+    #[test]
+    fn repeated_groups_and_rules() {
+        let g = grammar(
+            r#"
+            root ::= item ("," item)*   # comma separated
+            item ::= [0-9]+ | "x"
+            "#,
+        );
+        assert!(g.matches("1,x,23"));
+        assert!(g.matches("x"));
+        assert!(!g.matches("1,"));
+        assert!(!g.matches(",1"));
+        assert!(!g.matches("1,,2"));
+    }
+
+    #[test]
+    fn character_classes() {
+        let g = grammar(r#"root ::= [a-c_]+ [^0-9] [\x41-\x43] ."#);
+        assert!(g.matches("ab_xA?"));
+        assert!(g.matches("c-Cé"));
+        assert!(!g.matches("ab1A?"));
+        assert!(!g.matches("abxD?"));
+        assert!(!g.matches("dbxA?"));
+
+        // A trailing dash is a literal one
+        let g = grammar(r#"root ::= [+-]"#);
+        assert!(g.matches("-"));
+        assert!(g.matches("+"));
+        assert!(!g.matches(","));
+    }
+
+    #[test]
+    fn escapes_in_literals() {
+        let g = grammar(r#"root ::= "\"\\\n\té""#);
+        assert!(g.matches("\"\\\n\té"));
+    }
+
+    #[test]
+    fn empty_alternatives() {
+        let g = grammar(r#"root ::= ( "x" | ) "y" | "#);
+        assert!(g.matches(""));
+        assert!(g.matches("y"));
+        assert!(g.matches("xy"));
+        assert!(!g.matches("x"));
+    }
+
+    #[test]
+    fn root_need_not_come_first() {
+        let g = grammar(
+            r#"
+            digit ::= [0-9]
+            root ::= digit digit
+            "#,
+        );
+        assert!(g.matches("42"));
+        assert!(!g.matches("4"));
+    }
+
+    #[test]
+    fn invalid_grammars() {
+        let error = |source: &str| Grammar::parse(source).unwrap_err().to_string();
+        assert!(error(r#"root ::= value"#).contains("\"value\" is used but never defined"));
+        assert!(error(r#"item ::= "a""#).contains("no root rule"));
+        assert!(error("root ::= \"a\"\nroot ::= \"b\"").contains("defined twice"));
+        assert!(error(r#"root "a""#).contains("expected ::="));
+        assert!(error(r#"root ::= ( "a""#).contains("unclosed ("));
+        assert!(error(r#"root ::= "a"#).contains("unterminated literal"));
+        assert!(error(r#"root ::= "\u12""#).contains("invalid escape"));
+    }
+
+    #[test]
+    fn state_follows_the_text() {
+        let g = grammar(r#"root ::= "ab" | "a" [0-9]*"#);
+        let start = g.start();
+        assert!(!start.is_complete());
+        assert!(g.accept(&start, 'b').is_none());
+
+        let a = g.accept(&start, 'a').unwrap();
+        // "a" alone is a sentence, and both alternatives can go on
+        assert!(a.is_complete());
+        let ab = g.accept(&a, 'b').unwrap();
+        assert!(ab.is_complete());
+        assert!(g.accept(&ab, 'b').is_none());
+
+        let a12 = g.accept_str(&a, "12").unwrap();
+        assert!(a12.is_complete());
+        assert!(g.accept_str(&a, "1b").is_none());
+    }
+
+    #[test]
+    fn left_recursion_does_not_hang() {
+        let g = grammar(r#"root ::= root "a" | "b""#);
+        assert!(g.matches("b"));
+        assert!(!g.matches("a"));
+    }
+
+    #[test]
+    fn accepts_any_over_code_point_ranges() {
+        let g = grammar(r#"root ::= [^"a-c] | [x-z]"#);
+        let start = g.start();
+        let range = |lo: char, hi: char| (lo as u32, hi as u32);
+
+        let (lo, hi) = range('"', '"');
+        assert!(!g.accepts_any(&start, lo, hi));
+        let (lo, hi) = range('a', 'c');
+        assert!(!g.accepts_any(&start, lo, hi));
+        let (lo, hi) = range('a', 'd');
+        assert!(g.accepts_any(&start, lo, hi));
+        let (lo, hi) = range('\u{4e00}', '\u{4e3f}');
+        assert!(g.accepts_any(&start, lo, hi));
+
+        let g = grammar(r#"root ::= [x-z]"#);
+        let (lo, hi) = range('\u{c0}', '\u{ff}');
+        assert!(!g.accepts_any(&g.start(), lo, hi));
+        let (lo, hi) = range('w', 'x');
+        assert!(g.accepts_any(&g.start(), lo, hi));
+    }
+}
@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde_json::Value;
+
+// Building blocks every converted schema can refer to
+const BASE_RULES: &str = r#"
+ws ::= | " " | "\n" [ \t]*
+string ::= "\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\""
+integer ::= "-"? ( "0" | [1-9] [0-9]* )
+number ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+boolean ::= "true" | "false"
+null ::= "null"
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}"
+array ::= "[" ws ( value ws ( "," ws value ws )* )? "]"
+"#;
+
+/// Turn a JSON schema into a GBNF grammar for [`crate::grammar::Grammar`].
+///
+/// Covers the part of JSON schema that shapes output: `type` (also as a
+/// list), `properties` with `required`, `items`, `enum`, `const`, `anyOf`,
+/// `oneOf` and `$ref` into `#/definitions` or `#/$defs`. Object properties
+/// come out in the order the schema declares them and no others are allowed;
+/// other keywords, such as `minLength` or `pattern`, are ignored.
+pub fn to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = Converter {
+        root: schema,
+        rules: Vec::new(),
+        refs: HashMap::new(),
+    };
+    let body = converter.visit(schema, "root-value")?;
+
+    let mut grammar = format!("root ::= ws {body} ws\n");
+    for (name, rule) in &converter.rules {
+        grammar.push_str(&format!("{name} ::= {rule}\n"));
+    }
+    grammar.push_str(BASE_RULES);
+    Ok(grammar)
+}
+
+/// Check `value` against `schema`, for the same subset [`to_grammar`] covers.
+pub fn validate(value: &Value, schema: &Value) -> Result<()> {
+    validate_at(value, schema, schema, "$")
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+    // Rules in the order they were made
+    rules: Vec<(String, String)>,
+    // $ref -> rule name, so recursive schemas refer back to themselves
+    refs: HashMap<String, String>,
+}
+
+impl<'a> Converter<'a> {
+    // The right-hand side of a rule matching `schema`; `name` is a hint for
+    // the helper rules it needs
+    fn visit(&mut self, schema: &'a Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Bool(false) => anyhow::bail!("schema at {name} allows nothing"),
+            Value::Object(schema) => schema,
+            _ => anyhow::bail!("schema at {name} must be an object"),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Some(values) = values.as_array().filter(|v| !v.is_empty()) else {
+                anyhow::bail!("enum at {name} must be a non-empty array");
+            };
+            let alts: Vec<String> = values.iter().map(|v| literal(&v.to_string())).collect();
+            return Ok(group(&alts));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let Some(schemas) = schemas.as_array().filter(|s| !s.is_empty()) else {
+                    anyhow::bail!("{keyword} at {name} must be a non-empty array");
+                };
+                let mut alts = Vec::new();
+                for (i, schema) in schemas.iter().enumerate() {
+                    alts.push(self.visit(schema, &format!("{name}-{i}"))?);
+                }
+                return Ok(group(&alts));
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::String(ty)) => self.typed(schema, ty, name),
+            Some(Value::Array(types)) if !types.is_empty() => {
+                let mut alts = Vec::new();
+                for ty in types {
+                    let Some(ty) = ty.as_str() else {
+                        anyhow::bail!("type at {name} must be a string or a list of strings");
+                    };
+                    alts.push(self.typed(schema, ty, &format!("{name}-{ty}"))?);
+                }
+                Ok(group(&alts))
+            }
+            Some(_) => anyhow::bail!("type at {name} must be a string or a list of strings"),
+            None if schema.contains_key("properties") => self.typed(schema, "object", name),
+            None if schema.contains_key("items") => self.typed(schema, "array", name),
+            None => Ok("value".to_string()),
+        }
+    }
+
+    fn typed(
+        &mut self,
+        schema: &'a serde_json::Map<String, Value>,
+        ty: &str,
+        name: &str,
+    ) -> Result<String> {
+        match ty {
+            "string" | "integer" | "number" | "boolean" | "null" => Ok(ty.to_string()),
+            "object" => match schema.get("properties").and_then(Value::as_object) {
+                Some(properties) => self.object(schema, properties, name),
+                None => Ok("object".to_string()),
+            },
+            "array" => match schema.get("items") {
+                Some(items) => {
+                    let item = self.rule(&format!("{name}-item"), |c, n| c.visit(items, n))?;
+                    Ok(format!(
+                        r#""[" ws ( {item} ws ( "," ws {item} ws )* )? "]""#
+                    ))
+                }
+                None => Ok("array".to_string()),
+            },
+            other => anyhow::bail!("unsupported type {other:?} at {name}"),
+        }
+    }
+
+    // Properties in declared order. `rest` of property i matches properties
+    // i.. once an earlier one was written, so it starts with a comma; `first`
+    // matches them when none was, so it doesn't. Optional properties can be
+    // skipped in either.
+    fn object(
+        &mut self,
+        schema: &'a serde_json::Map<String, Value>,
+        properties: &'a serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|r| r.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut pairs = Vec::new();
+        for (key, property) in properties {
+            let key_name = format!("{name}-{}", sanitize(key));
+            let value = self.rule(&key_name, |c, n| c.visit(property, n))?;
+            let pair = format!(
+                r#"{} ws ":" ws {value} ws"#,
+                literal(&Value::String(key.clone()).to_string())
+            );
+            pairs.push((pair, required.contains(&key.as_str())));
+        }
+
+        // Built back to front, as each refers to the next
+        let mut first_next = String::new();
+        let mut rest_next = String::new();
+        for (i, (pair, required)) in pairs.iter().enumerate().rev() {
+            let mut first = format!("{pair} {rest_next}");
+            let mut rest = format!(r#""," ws {pair} {rest_next}"#);
+            if !required {
+                first = format!("{first} | {first_next}");
+                rest = format!("{rest} | {rest_next}");
+            }
+            first_next = self.add_rule(&format!("{name}-first-{i}"), first);
+            rest_next = self.add_rule(&format!("{name}-rest-{i}"), rest);
+        }
+        Ok(format!(r#""{{" ws {first_next} "}}""#))
+    }
+
+    fn reference(&mut self, reference: &str) -> Result<String> {
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let target = reference
+            .strip_prefix('#')
+            .filter(|path| path.starts_with("/definitions/") || path.starts_with("/$defs/"))
+            .and_then(|path| self.root.pointer(path));
+        let Some(target) = target else {
+            anyhow::bail!(
+                "unsupported $ref {reference:?}, expected #/definitions/... or #/$defs/..."
+            );
+        };
+
+        let hint = format!(
+            "ref-{}",
+            sanitize(reference.rsplit('/').next().unwrap_or(""))
+        );
+        let name = self.unique_name(&hint);
+        // Registered before visiting, so that a schema can refer to itself
+        self.refs.insert(reference.to_string(), name.clone());
+        self.rules.push((name.clone(), String::new()));
+        let body = self.visit(target, &name)?;
+        if let Some(rule) = self.rules.iter_mut().find(|(n, _)| *n == name) {
+            rule.1 = body;
+        }
+        Ok(name)
+    }
+
+    // A rule of its own for `visit`'s result, or the result itself when it
+    // is just a name
+    fn rule(
+        &mut self,
+        hint: &str,
+        visit: impl FnOnce(&mut Self, &str) -> Result<String>,
+    ) -> Result<String> {
+        let body = visit(self, hint)?;
+        if body.chars().all(is_name_char) {
+            return Ok(body);
+        }
+        Ok(self.add_rule(hint, body))
+    }
+
+    fn add_rule(&mut self, hint: &str, body: String) -> String {
+        let name = self.unique_name(hint);
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn unique_name(&self, hint: &str) -> String {
+        let taken = |name: &str| {
+            ["root", "ws", "value", "object", "array", "string"].contains(&name)
+                || ["integer", "number", "boolean", "null"].contains(&name)
+                || self.rules.iter().any(|(n, _)| n == name)
+        };
+        let mut name = hint.to_string();
+        let mut i = 1;
+        while taken(&name) {
+            name = format!("{hint}-{i}");
+            i += 1;
+        }
+        name
+    }
+}
+
+fn validate_at(value: &Value, schema: &Value, root: &Value, path: &str) -> Result<()> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => anyhow::bail!("{path} is not allowed by the schema"),
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let Some(target) = reference.strip_prefix('#').and_then(|p| root.pointer(p)) else {
+            anyhow::bail!("unresolved $ref {reference:?}");
+        };
+        return validate_at(value, target, root, path);
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            anyhow::bail!("{path} must be {expected}");
+        }
+        return Ok(());
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        if !values.contains(value) {
+            anyhow::bail!("{path} must be one of {}", Value::Array(values.clone()));
+        }
+        return Ok(());
+    }
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(schemas) = schema.get(keyword).and_then(Value::as_array) {
+            if !schemas
+                .iter()
+                .any(|s| validate_at(value, s, root, path).is_ok())
+            {
+                anyhow::bail!("{path} matches none of the {keyword} schemas");
+            }
+            return Ok(());
+        }
+    }
+
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(ty)) => vec![ty.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ if schema.contains_key("properties") => vec!["object"],
+        _ if schema.contains_key("items") => vec!["array"],
+        _ => return Ok(()),
+    };
+    let matches_type = |ty: &str| match ty {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        _ => false,
+    };
+    if !types.iter().any(|ty| matches_type(ty)) {
+        anyhow::bail!("{path} must be of type {}", types.join(" or "));
+    }
+
+    if let (Some(object), Some(properties)) = (
+        value.as_object(),
+        schema.get("properties").and_then(Value::as_object),
+    ) {
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(required) {
+                anyhow::bail!("{path} is missing required property {required:?}");
+            }
+        }
+        for (key, value) in object {
+            let Some(property) = properties.get(key) else {
+                anyhow::bail!("{path} has unexpected property {key:?}");
+            };
+            validate_at(value, property, root, &format!("{path}.{key}"))?;
+        }
+    }
+    if let (Some(items), Some(schema)) = (value.as_array(), schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            validate_at(item, schema, root, &format!("{path}[{i}]"))?;
+        }
+    }
+    Ok(())
+}
+
+// A GBNF literal for `text`
+fn literal(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn group(alts: &[String]) -> String {
+    match alts {
+        [single] => single.clone(),
+        alts => format!("( {} )", alts.join(" | ")),
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if is_name_char(c) { c } else { '-' })
+        .collect();
+    if name.is_empty() {
+        "property".to_string()
+    } else {
+        name
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use serde_json::json;
+
+    fn grammar(schema: &Value) -> Grammar {
+        Grammar::parse(&to_grammar(schema).unwrap()).unwrap()
+    }
+
+    // The grammar and the validator should agree on every sample
+    fn check(schema: &Value, accepted: &[&str], rejected: &[&str]) {
+        let grammar = grammar(schema);
+        for text in accepted {
+            assert!(grammar.matches(text), "grammar should accept {text}");
+            let value: Value = serde_json::from_str(text).unwrap();
+            validate(&value, schema).unwrap();
+        }
+        for text in rejected {
+            assert!(!grammar.matches(text), "grammar should reject {text}");
+        }
+    }
+
+    #[test]
+    fn properties_in_declared_order() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "b": {"type": "integer"},
+                "a": {"type": "string"},
+                "c": {"type": "boolean"}
+            },
+            "required": ["b", "c"]
+        });
+        check(
+            &schema,
+            &[
+                r#"{"b": 1, "a": "x", "c": true}"#,
+                r#"{"b":-20,"c":false}"#,
+                "{\n  \"b\": 0,\n  \"a\": \"\",\n  \"c\": true\n}",
+            ],
+            &[
+                r#"{"a": "x", "b": 1, "c": true}"#,
+                r#"{"b": 1, "a": "x"}"#,
+                r#"{"c": true}"#,
+                r#"{"b": 1, "c": true, "d": null}"#,
+                r#"{"b": 1, , "c": true}"#,
+                r#"{"b": 1.5, "c": true}"#,
+            ],
+        );
+
+        let error = |value: Value| validate(&value, &schema).unwrap_err().to_string();
+        assert!(error(json!({"b": 1})).contains("missing required property \"c\""));
+        assert!(error(json!({"b": 1, "c": true, "d": 0})).contains("unexpected property \"d\""));
+        assert!(error(json!({"b": "1", "c": true})).contains("$.b must be of type integer"));
+    }
+
+    #[test]
+    fn all_properties_optional() {
+        let schema = json!({
+            "properties": {
+                "x": {"type": "null"},
+                "y": {"type": "number"}
+            }
+        });
+        check(
+            &schema,
+            &[
+                "{}",
+                r#"{"x": null}"#,
+                r#"{"y": 2.5e3}"#,
+                r#"{"x": null, "y": 0}"#,
+            ],
+            &[r#"{,"y": 0}"#, r#"{"y": 0, "x": null}"#, r#"{"x": null,}"#],
+        );
+    }
+
+    #[test]
+    fn recursive_references() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "name": {"type": "string"},
+                        "children": {"type": "array", "items": {"$ref": "#/$defs/node"}}
+                    },
+                    "required": ["name"]
+                }
+            }
+        });
+        check(
+            &schema,
+            &[
+                r#"{"name": "root"}"#,
+                r#"{"name": "root", "children": []}"#,
+                r#"{"name": "a", "children": [{"name": "b", "children": [{"name": "c"}]}, {"name": "d"}]}"#,
+            ],
+            &[
+                r#"{"children": []}"#,
+                r#"{"name": "a", "children": [{"name": "b", "children": [{}]}]}"#,
+                r#"{"name": "a", "children": [1]}"#,
+            ],
+        );
+        assert!(validate(&json!({"name": "a", "children": [{"name": 1}]}), &schema).is_err());
+    }
+
+    #[test]
+    fn enum_and_const() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "kind": {"const": "point"},
+                "color": {"enum": ["red", "green", 3, null]}
+            },
+            "required": ["kind", "color"]
+        });
+        check(
+            &schema,
+            &[
+                r#"{"kind": "point", "color": "red"}"#,
+                r#"{"kind": "point", "color": 3}"#,
+                r#"{"kind": "point", "color": null}"#,
+            ],
+            &[
+                r#"{"kind": "line", "color": "red"}"#,
+                r#"{"kind": "point", "color": "blue"}"#,
+                r#"{"kind": "point", "color": "3"}"#,
+            ],
+        );
+        assert!(validate(&json!({"kind": "point", "color": "blue"}), &schema).is_err());
+    }
+
+    #[test]
+    fn unions_and_untyped_values() {
+        let schema = json!({
+            "type": "array",
+            "items": {"anyOf": [{"type": "integer"}, {"type": ["string", "null"]}]}
+        });
+        check(
+            &schema,
+            &["[]", r#"[1, "two", null]"#],
+            &["[true]", "[1,]", "[1.5]"],
+        );
+
+        check(
+            &json!({}),
+            &[r#"{"any": [1, {"thing": true}]}"#, "\"text\"", "-0.5"],
+            &["{", "[1 2]", "'text'"],
+        );
+    }
+
+    #[test]
+    fn unsupported_schemas() {
+        let error = |schema: Value| to_grammar(&schema).unwrap_err().to_string();
+        assert!(error(json!(false)).contains("allows nothing"));
+        assert!(error(json!({"$ref": "https://example.com/schema"})).contains("unsupported $ref"));
+        assert!(error(json!({"$ref": "#/$defs/missing"})).contains("unsupported $ref"));
+        assert!(error(json!({"enum": []})).contains("non-empty array"));
+        assert!(error(json!({"type": "date"})).contains("unsupported type"));
+    }
+
+    #[test]
+    fn property_names_become_rule_names() {
+        let schema = json!({
+            "properties": {"a key": {"type": "string"}, "a-key": {"type": "string"}},
+            "required": ["a key", "a-key"]
+        });
+        check(
+            &schema,
+            &[r#"{"a key": "x", "a-key": "y"}"#],
+            &[r#"{"a key": "x"}"#],
+        );
+    }
+}
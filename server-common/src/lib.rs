@@ -0,0 +1,30 @@
+//! What both servers share: request parsing, chat templates and context
+//! fitting, sampling, constrained and beam decoding, streaming and storage.
+//! Nothing here depends on the model, which each server brings itself.
+
+pub mod admission;
+pub mod beam;
+pub mod candidates;
+pub mod chat_template;
+pub mod constraint;
+pub mod context;
+pub mod db;
+pub mod eos;
+pub mod events;
+pub mod fim;
+pub mod grammar;
+pub mod json_schema;
+pub mod logit_bias;
+pub mod logprobs;
+pub mod openai;
+pub mod penalties;
+pub mod prefix_cache;
+pub mod prompt_lookup;
+pub mod request;
+pub mod samplers;
+pub mod sampling;
+pub mod session_cache;
+pub mod stop;
+#[cfg(test)]
+mod testing;
+pub mod token_output_stream;
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use candle_core::Tensor;
+use tokenizers::Tokenizer;
+
+/// Biases at or below this ban the token, as in the OpenAI API.
+const BAN_BIAS: f32 = -100.0;
+const MAX_BIASED_KEYS: usize = 300;
+const MAX_BANNED_STRINGS: usize = 32;
+
+/// Per-request changes to the logits: additive biases for single tokens, and
+/// token sequences the answer may not contain.
+#[derive(Debug, Clone)]
+pub struct LogitBias {
+    bias: BTreeMap<u32, f32>,
+    // Each banned string, as the tokens it encodes to
+    banned: Vec<Vec<u32>>,
+}
+
+impl LogitBias {
+    /// `logit_bias` is a JSON object from token ids or strings to a bias in
+    /// [-100, 100]; a string biases every token it encodes to. `banned_strings`
+    /// is a string or a JSON array of strings. `None` when neither is set.
+    pub fn new(
+        logit_bias: Option<&str>,
+        banned_strings: Option<&str>,
+        tokenizer: &Tokenizer,
+    ) -> Result<Option<Self>> {
+        if logit_bias.is_none() && banned_strings.is_none() {
+            return Ok(None);
+        }
+
+        let mut bias = BTreeMap::new();
+        if let Some(raw) = logit_bias {
+            let entries: BTreeMap<String, f32> = serde_json::from_str(raw)
+                .map_err(|e| anyhow::anyhow!("logit_bias must be a JSON object of numbers: {e}"))?;
+            if entries.len() > MAX_BIASED_KEYS {
+                anyhow::bail!("logit_bias may have at most {MAX_BIASED_KEYS} entries");
+            }
+            let vocab_size = tokenizer.get_vocab_size(true) as u32;
+            for (key, value) in entries {
+                if !(-100.0..=100.0).contains(&value) {
+                    anyhow::bail!("logit_bias for {key:?} must be between -100 and 100");
+                }
+                let tokens = match key.parse::<u32>() {
+                    Ok(token) if token < vocab_size => vec![token],
+                    Ok(token) => anyhow::bail!("logit_bias token {token} is out of the vocabulary"),
+                    Err(_) => encode(tokenizer, &key)?,
+                };
+                for token in tokens {
+                    bias.insert(token, value);
+                }
+            }
+        }
+
+        let mut banned = Vec::new();
+        if let Some(raw) = banned_strings {
+            let strings: Vec<String> = if raw.trim_start().starts_with('[') {
+                serde_json::from_str(raw).map_err(|e| {
+                    anyhow::anyhow!(
+                        "banned_strings must be a string or a JSON array of strings: {e}"
+                    )
+                })?
+            } else {
+                vec![raw.to_string()]
+            };
+            if strings.len() > MAX_BANNED_STRINGS {
+                anyhow::bail!("at most {MAX_BANNED_STRINGS} banned strings are allowed");
+            }
+            for string in strings {
+                // Mid-sentence, a word usually comes with its leading space
+                let mut variants = vec![string.clone()];
+                if !string.starts_with(' ') {
+                    variants.push(format!(" {string}"));
+                }
+                for variant in variants {
+                    let tokens = encode(tokenizer, &variant)?;
+                    if !banned.contains(&tokens) {
+                        banned.push(tokens);
+                    }
+                }
+            }
+        }
+
+        Ok(Some(Self { bias, banned }))
+    }
+
+    /// Add the biases and ban the last token of every banned string whose
+    /// other tokens the answer (`generated`) currently ends with.
+    pub fn apply(&self, logits: &Tensor, generated: &[u32]) -> candle_core::Result<Tensor> {
+        let mut values = logits.to_vec1::<f32>()?;
+        for (&token, &bias) in &self.bias {
+            if let Some(logit) = values.get_mut(token as usize) {
+                *logit = if bias <= BAN_BIAS {
+                    f32::NEG_INFINITY
+                } else {
+                    *logit + bias
+                };
+            }
+        }
+        for tokens in &self.banned {
+            let Some((&last, prefix)) = tokens.split_last() else {
+                continue;
+            };
+            if generated.ends_with(prefix) {
+                if let Some(logit) = values.get_mut(last as usize) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+
+        Tensor::from_vec(values, logits.shape(), logits.device())
+    }
+}
+
+fn encode(tokenizer: &Tokenizer, text: &str) -> Result<Vec<u32>> {
+    let encoding = tokenizer
+        .encode(text, false)
+        .map_err(|e| anyhow::anyhow!("tokenizer error: {e}"))?;
+    let tokens = encoding.get_ids().to_vec();
+    if tokens.is_empty() {
+        anyhow::bail!("{text:?} doesn't encode to any token");
+    }
+    Ok(tokens)
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use candle_core::{DType, Tensor};
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+/// Most alternatives a request may ask for per token, as in the OpenAI API.
+pub const MAX_TOP_LOGPROBS: usize = 20;
+
+/// A sampled token with its log-probability and the likeliest alternatives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: u32,
+    pub text: String,
+    pub logprob: f32,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: u32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+pub fn validate(top_n: Option<usize>) -> Result<Option<usize>> {
+    if let Some(n) = top_n {
+        if n > MAX_TOP_LOGPROBS {
+            anyhow::bail!("logprobs must be at most {MAX_TOP_LOGPROBS}, got {n}");
+        }
+    }
+    Ok(top_n)
+}
+
+/// Log-probabilities of every token, from the logits the sampler is about to see.
+///
+/// These are the model's own probabilities after penalties and logit bias;
+/// temperature and the samplers only shape the sampling.
+pub fn log_softmax(logits: &Tensor) -> Result<Vec<f32>> {
+    let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    Ok(logits.into_iter().map(|l| l - log_sum).collect())
+}
+
+/// The entry for `token`, with the `top_n` likeliest tokens.
+pub fn entry(
+    logprobs: &[f32],
+    token: u32,
+    top_n: usize,
+    tokenizer: &Tokenizer,
+) -> Result<TokenLogprob> {
+    let mut top: Vec<usize> = (0..logprobs.len()).collect();
+    if top_n < top.len() {
+        top.select_nth_unstable_by(top_n, |&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+        top.truncate(top_n);
+    }
+    top.sort_by(|&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+
+    let top_logprobs = top
+        .into_iter()
+        .map(|id| {
+            Ok(TopLogprob {
+                token: id as u32,
+                text: token_text(tokenizer, id as u32)?,
+                logprob: logprobs[id],
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(TokenLogprob {
+        token,
+        text: token_text(tokenizer, token)?,
+        logprob: logprobs
+            .get(token as usize)
+            .copied()
+            .unwrap_or(f32::NEG_INFINITY),
+        top_logprobs,
+    })
+}
+
+fn token_text(tokenizer: &Tokenizer, token: u32) -> Result<String> {
+    tokenizer
+        .decode(&[token], false)
+        .map_err(|e| anyhow::anyhow!("tokenizer decode error: {e}"))
+}
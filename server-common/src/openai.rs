@@ -0,0 +1,643 @@
+use std::convert::Infallible;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::chat_template::ChatMessage;
+use crate::events::StreamEvent;
+use crate::logprobs::TokenLogprob;
+use crate::request::{ChatStreamQuery, Generation};
+
+/// `POST /v1/chat/completions` body: the part of the OpenAI API this server
+/// understands, plus a few of its own sampling parameters. `model` is ignored;
+/// every answer comes from the loaded model.
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    pub messages: Vec<RequestMessage>,
+    #[serde(default)]
+    pub stream: bool,
+    pub stream_options: Option<StreamOptions>,
+    pub max_tokens: Option<usize>,
+    pub max_completion_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub n: Option<usize>,
+    pub seed: Option<u64>,
+    pub stop: Option<Stop>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// Token ids to a bias in [-100, 100].
+    pub logit_bias: Option<serde_json::Map<String, Value>>,
+    #[serde(default)]
+    pub logprobs: bool,
+    pub top_logprobs: Option<usize>,
+    pub response_format: Option<Value>,
+    /// Session the turn is saved to; a new one for every request when unset.
+    pub session_id: Option<String>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub repeat_penalty: Option<f32>,
+}
+
+#[derive(Deserialize)]
+pub struct RequestMessage {
+    pub role: String,
+    pub content: Option<Content>,
+}
+
+/// Message content: a string, or an array of parts of which only text is supported.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+pub struct ContentPart {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub text: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: bool,
+}
+
+impl ChatCompletionRequest {
+    /// The equivalent `/chat` request, and the conversation before its prompt.
+    ///
+    /// Leading system (or developer) messages become the system prompt, and
+    /// the last message must be the user's.
+    pub fn to_query(&self) -> Result<(ChatStreamQuery, Vec<ChatMessage>)> {
+        let mut messages = Vec::with_capacity(self.messages.len());
+        for message in &self.messages {
+            let role = match message.role.as_str() {
+                "developer" => "system",
+                role @ ("system" | "user" | "assistant") => role,
+                role => anyhow::bail!("unsupported message role {role:?}"),
+            };
+            messages.push(ChatMessage::new(role, &message.text()?));
+        }
+
+        let Some(last) = messages.pop() else {
+            anyhow::bail!("messages must not be empty");
+        };
+        if last.role != "user" {
+            anyhow::bail!("the last message must be from the user");
+        }
+        let system_len = messages.iter().take_while(|m| m.role == "system").count();
+        let history = messages.split_off(system_len);
+        let system = (system_len > 0).then(|| {
+            messages
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        });
+
+        if self.top_logprobs.is_some() && !self.logprobs {
+            anyhow::bail!("top_logprobs needs logprobs");
+        }
+        let response_format = self
+            .response_format
+            .as_ref()
+            .filter(|format| format.get("type").and_then(Value::as_str) != Some("text"))
+            .map(Value::to_string);
+
+        let query = ChatStreamQuery {
+            session_id: self
+                .session_id
+                .clone()
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            prompt: last.content,
+            max_tokens: self.max_completion_tokens.or(self.max_tokens),
+            system,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            seed: self.seed,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            repeat_penalty: self.repeat_penalty,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            logit_bias: logit_bias_param(self.logit_bias.as_ref()),
+            stop: stop_param(self.stop.as_ref())?,
+            response_format,
+            logprobs: self.logprobs.then(|| self.top_logprobs.unwrap_or(0)),
+            n: self.n,
+            ..ChatStreamQuery::default()
+        };
+        Ok((query, history))
+    }
+
+    pub fn include_usage(&self) -> bool {
+        self.stream_options
+            .as_ref()
+            .is_some_and(|options| options.include_usage)
+    }
+}
+
+impl RequestMessage {
+    fn text(&self) -> Result<String> {
+        match &self.content {
+            Some(Content::Text(text)) => Ok(text.clone()),
+            Some(Content::Parts(parts)) => parts
+                .iter()
+                .map(|part| match (part.kind.as_str(), &part.text) {
+                    ("text", Some(text)) => Ok(text.as_str()),
+                    (kind, _) => anyhow::bail!("unsupported content part {kind:?}"),
+                })
+                .collect::<Result<Vec<_>>>()
+                .map(|texts| texts.concat()),
+            None => anyhow::bail!("{} message has no content", self.role),
+        }
+    }
+}
+
+/// `POST /v1/completions` body. The prompt is continued as is, without the
+/// chat template, and nothing is saved.
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    pub prompt: Prompt,
+    /// Text after the insertion point, for models with fill-in-the-middle tokens.
+    pub suffix: Option<String>,
+    /// Start the answer with the prompt.
+    #[serde(default)]
+    pub echo: bool,
+    /// Report each token's log-probability with this many likeliest alternatives.
+    pub logprobs: Option<usize>,
+    #[serde(default)]
+    pub stream: bool,
+    pub stream_options: Option<StreamOptions>,
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub n: Option<usize>,
+    pub seed: Option<u64>,
+    pub stop: Option<Stop>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub logit_bias: Option<serde_json::Map<String, Value>>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub repeat_penalty: Option<f32>,
+}
+
+/// OpenAI also takes an array of prompts; here it must hold exactly one.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl CompletionRequest {
+    /// The equivalent raw `/chat` request, without a session.
+    pub fn to_query(&self) -> Result<ChatStreamQuery> {
+        let prompt = match &self.prompt {
+            Prompt::One(prompt) => prompt.clone(),
+            Prompt::Many(prompts) => match prompts.as_slice() {
+                [prompt] => prompt.clone(),
+                _ => anyhow::bail!("exactly one prompt is supported per request"),
+            },
+        };
+        if prompt.is_empty() && self.suffix.is_none() {
+            anyhow::bail!("prompt must not be empty");
+        }
+        // The prompt's own tokens are never scored, and an echoed prompt
+        // would hide where the suffix goes
+        if self.echo && (self.logprobs.is_some() || self.suffix.is_some()) {
+            anyhow::bail!("echo can't be combined with logprobs or suffix");
+        }
+
+        Ok(ChatStreamQuery {
+            session_id: String::new(),
+            prompt,
+            raw: true,
+            suffix: self.suffix.clone(),
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            seed: self.seed,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            repeat_penalty: self.repeat_penalty,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            logit_bias: logit_bias_param(self.logit_bias.as_ref()),
+            stop: stop_param(self.stop.as_ref())?,
+            logprobs: self.logprobs,
+            n: self.n,
+            ..ChatStreamQuery::default()
+        })
+    }
+
+    pub fn include_usage(&self) -> bool {
+        self.stream_options
+            .as_ref()
+            .is_some_and(|options| options.include_usage)
+    }
+}
+
+// `stop` as the `/chat` parameter: always a JSON array, so that a stop
+// string starting with `[` isn't taken for one
+fn stop_param(stop: Option<&Stop>) -> Result<Option<String>> {
+    Ok(match stop {
+        Some(Stop::One(stop)) => Some(serde_json::to_string(&[stop])?),
+        Some(Stop::Many(stops)) => Some(serde_json::to_string(stops)?),
+        None => None,
+    })
+}
+
+fn logit_bias_param(bias: Option<&serde_json::Map<String, Value>>) -> Option<String> {
+    bias.map(|bias| Value::Object(bias.clone()).to_string())
+}
+
+/// What every chunk or response of one completion shares.
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub id: String,
+    pub created: u64,
+    pub model: String,
+}
+
+impl Completion {
+    /// `prefix` is `chatcmpl` or `cmpl`, as in OpenAI's ids.
+    pub fn new(prefix: &str, model: &str) -> Self {
+        Self {
+            id: format!("{prefix}-{}", uuid::Uuid::new_v4().simple()),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            model: model.to_string(),
+        }
+    }
+
+    /// The `chat.completion` response for the finished candidates.
+    pub fn chat_response(&self, generations: Vec<Generation>, logprobs: bool) -> Value {
+        let usage = Usage::of(&generations);
+        let choices: Vec<Value> = generations
+            .into_iter()
+            .enumerate()
+            .map(|(index, generation)| {
+                serde_json::json!({
+                    "index": index,
+                    "message": { "role": "assistant", "content": generation.answer },
+                    "logprobs": logprobs.then(|| serde_json::json!({
+                        "content": chat_logprobs(&generation.logprobs),
+                    })),
+                    "finish_reason": generation.finish_reason,
+                })
+            })
+            .collect();
+        self.response("chat.completion", choices, usage)
+    }
+
+    /// The `text_completion` response for the finished candidates, each
+    /// starting with `echo` when given.
+    pub fn text_response(
+        &self,
+        generations: Vec<Generation>,
+        echo: Option<&str>,
+        logprobs: bool,
+    ) -> Value {
+        let usage = Usage::of(&generations);
+        let choices: Vec<Value> = generations
+            .into_iter()
+            .enumerate()
+            .map(|(index, generation)| {
+                serde_json::json!({
+                    "index": index,
+                    "text": format!("{}{}", echo.unwrap_or_default(), generation.answer),
+                    "logprobs": logprobs.then(|| text_logprobs(&generation.logprobs, &mut 0)),
+                    "finish_reason": generation.finish_reason,
+                })
+            })
+            .collect();
+        self.response("text_completion", choices, usage)
+    }
+
+    fn response(&self, object: &str, choices: Vec<Value>, usage: Usage) -> Value {
+        serde_json::json!({
+            "id": self.id,
+            "object": object,
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+            "usage": usage,
+        })
+    }
+
+    fn chunk(&self, object: &str, choices: Vec<Value>, usage: Option<Usage>) -> Event {
+        let mut chunk = serde_json::json!({
+            "id": self.id,
+            "object": object,
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        });
+        if let Some(usage) = usage {
+            chunk["usage"] = serde_json::json!(usage);
+        }
+        Event::default().data(chunk.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    // All candidates share the prompt
+    fn of(generations: &[Generation]) -> Self {
+        Self::new(
+            generations.first().map_or(0, |g| g.prompt_tokens),
+            generations.iter().map(|g| g.completion_tokens).sum(),
+        )
+    }
+}
+
+// OpenAI names the token by its text, with the UTF-8 bytes alongside
+fn chat_logprobs(logprobs: &[TokenLogprob]) -> Vec<Value> {
+    logprobs
+        .iter()
+        .map(|entry| {
+            let top: Vec<Value> = entry
+                .top_logprobs
+                .iter()
+                .map(|top| {
+                    serde_json::json!({
+                        "token": top.text,
+                        "logprob": top.logprob,
+                        "bytes": top.text.as_bytes(),
+                    })
+                })
+                .collect();
+            serde_json::json!({
+                "token": entry.text,
+                "logprob": entry.logprob,
+                "bytes": entry.text.as_bytes(),
+                "top_logprobs": top,
+            })
+        })
+        .collect()
+}
+
+// The legacy shape of `/v1/completions`: parallel arrays, each token's
+// alternatives as a map from text to log-probability, and where each token
+// starts in the text, counting on from `offset`
+fn text_logprobs(logprobs: &[TokenLogprob], offset: &mut usize) -> Value {
+    let mut text_offset = Vec::with_capacity(logprobs.len());
+    for entry in logprobs {
+        text_offset.push(*offset);
+        *offset += entry.text.chars().count();
+    }
+    let top: Vec<serde_json::Map<String, Value>> = logprobs
+        .iter()
+        .map(|entry| {
+            entry
+                .top_logprobs
+                .iter()
+                .map(|top| (top.text.clone(), top.logprob.into()))
+                .collect()
+        })
+        .collect();
+    serde_json::json!({
+        "tokens": logprobs.iter().map(|entry| entry.text.as_str()).collect::<Vec<_>>(),
+        "token_logprobs": logprobs.iter().map(|entry| entry.logprob).collect::<Vec<_>>(),
+        "top_logprobs": top,
+        "text_offset": text_offset,
+    })
+}
+
+/// Which API the chunks are for.
+pub enum ChunkKind {
+    Chat,
+    /// Raw completions, starting with the echoed prompt when there is one.
+    Text {
+        echo: Option<String>,
+    },
+}
+
+/// Turns generation events into `chat.completion.chunk`s, or streamed
+/// `text_completion`s.
+pub struct Chunks {
+    completion: Completion,
+    kind: ChunkKind,
+    n: usize,
+    include_usage: bool,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    // Characters of each choice's tokens so far, for raw `text_offset`s
+    offsets: Vec<usize>,
+}
+
+impl Chunks {
+    pub fn new(completion: Completion, kind: ChunkKind, n: usize, include_usage: bool) -> Self {
+        Self {
+            completion,
+            kind,
+            n,
+            include_usage,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            offsets: vec![0; n],
+        }
+    }
+
+    pub fn map(&mut self, event: StreamEvent) -> Vec<Event> {
+        match event {
+            StreamEvent::Text { candidate, text } => {
+                let choice = self.choice(candidate.unwrap_or(0), Some(&text));
+                vec![self.chunk(vec![choice], None)]
+            }
+            // The prompt is encoded: every chat candidate starts its message,
+            // and raw ones with the echoed prompt
+            StreamEvent::Json {
+                name: "context",
+                data,
+                ..
+            } => {
+                self.prompt_tokens = usize_field(&data, "prompt_tokens");
+                let choices = match &self.kind {
+                    ChunkKind::Chat => (0..self.n)
+                        .map(|index| {
+                            let mut choice = self.choice(index, None);
+                            choice["delta"] =
+                                serde_json::json!({ "role": "assistant", "content": "" });
+                            choice
+                        })
+                        .collect(),
+                    ChunkKind::Text { echo: Some(echo) } => (0..self.n)
+                        .map(|index| self.choice(index, Some(echo)))
+                        .collect(),
+                    ChunkKind::Text { echo: None } => return Vec::new(),
+                };
+                vec![self.chunk(choices, None)]
+            }
+            StreamEvent::Json {
+                name: "logprobs",
+                candidate,
+                data,
+            } => {
+                let Ok(entry) = serde_json::from_value::<TokenLogprob>(data) else {
+                    return Vec::new();
+                };
+                let index = candidate.unwrap_or(0);
+                let mut choice = self.choice(index, None);
+                choice["logprobs"] = match &self.kind {
+                    ChunkKind::Chat => serde_json::json!({ "content": chat_logprobs(&[entry]) }),
+                    ChunkKind::Text { .. } => match self.offsets.get_mut(index) {
+                        Some(offset) => text_logprobs(&[entry], offset),
+                        None => return Vec::new(),
+                    },
+                };
+                vec![self.chunk(vec![choice], None)]
+            }
+            StreamEvent::Json {
+                name: "summary",
+                candidate,
+                data,
+            } => {
+                self.completion_tokens += usize_field(&data, "completion_tokens");
+                let mut choice = self.choice(candidate.unwrap_or(0), None);
+                choice["finish_reason"] = data["finish_reason"].clone();
+                vec![self.chunk(vec![choice], None)]
+            }
+            StreamEvent::Json { .. } => Vec::new(),
+            StreamEvent::Done => {
+                let mut events = Vec::new();
+                if self.include_usage {
+                    let usage = Usage::new(self.prompt_tokens, self.completion_tokens);
+                    events.push(self.chunk(Vec::new(), Some(usage)));
+                }
+                events.push(Event::default().data("[DONE]"));
+                events
+            }
+        }
+    }
+
+    // Chat choices carry their text in `delta`, raw ones in `text`
+    fn choice(&self, index: usize, text: Option<&str>) -> Value {
+        match self.kind {
+            ChunkKind::Chat => serde_json::json!({
+                "index": index,
+                "delta": text.map_or_else(
+                    || serde_json::json!({}),
+                    |text| serde_json::json!({ "content": text }),
+                ),
+                "logprobs": null,
+                "finish_reason": null,
+            }),
+            ChunkKind::Text { .. } => serde_json::json!({
+                "index": index,
+                "text": text.unwrap_or_default(),
+                "logprobs": null,
+                "finish_reason": null,
+            }),
+        }
+    }
+
+    fn chunk(&self, choices: Vec<Value>, usage: Option<Usage>) -> Event {
+        let object = match self.kind {
+            ChunkKind::Chat => "chat.completion.chunk",
+            ChunkKind::Text { .. } => "text_completion",
+        };
+        self.completion.chunk(object, choices, usage)
+    }
+}
+
+fn usize_field(data: &Value, name: &str) -> usize {
+    data[name].as_u64().unwrap_or(0) as usize
+}
+
+/// Relay `events` through `chunks` as the SSE stream of an OpenAI endpoint.
+/// Drops `events` as soon as the client leaves, so the generation stops too.
+pub fn relay<S>(mut events: S, mut chunks: Chunks) -> impl Stream<Item = Result<Event, Infallible>>
+where
+    S: Stream<Item = StreamEvent> + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = events.next() => event,
+                _ = tx.closed() => return,
+            };
+            let Some(event) = event else {
+                return;
+            };
+            for chunk in chunks.map(event) {
+                if tx.send(chunk).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    UnboundedReceiverStream::new(rx).map(Ok)
+}
+
+/// An error in OpenAI's shape, which its client libraries know how to report.
+pub fn error_response(status: StatusCode, message: impl std::fmt::Display) -> Response {
+    let kind = if status.is_client_error() {
+        "invalid_request_error"
+    } else {
+        "server_error"
+    };
+    let body = serde_json::json!({
+        "error": {
+            "message": message.to_string(),
+            "type": kind,
+            "param": null,
+            "code": null,
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+/// `GET /v1/models`: the one model this server has loaded.
+pub fn model_list(model: &str) -> Value {
+    serde_json::json!({
+        "object": "list",
+        "data": [{
+            "id": model,
+            "object": "model",
+            "created": 0,
+            "owned_by": "ruschat",
+        }],
+    })
+}
@@ -0,0 +1,120 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use candle_core::Tensor;
+use serde::Serialize;
+
+// Off unless asked for, as OpenAI clients expect
+const DEFAULT_REPEAT_PENALTY: f32 = 1.0;
+const DEFAULT_REPEAT_LAST_N: usize = 64;
+
+/// Penalties applied to the logits before sampling to discourage repetition.
+#[derive(Debug, Clone, Serialize)]
+pub struct PenaltyConfig {
+    /// Multiplicative penalty (as in candle's `apply_repeat_penalty`), 1.0 disables it.
+    pub repeat_penalty: f32,
+    /// How many of the most recent tokens the repeat penalty looks at.
+    pub repeat_last_n: usize,
+    /// OpenAI-style flat penalty for any token already generated.
+    pub presence_penalty: f32,
+    /// OpenAI-style penalty scaled by how often a token was generated.
+    pub frequency_penalty: f32,
+}
+
+impl PenaltyConfig {
+    pub fn new(
+        repeat_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
+        presence_penalty: Option<f32>,
+        frequency_penalty: Option<f32>,
+    ) -> Result<Self> {
+        let repeat_penalty = repeat_penalty.unwrap_or(DEFAULT_REPEAT_PENALTY);
+        if !(repeat_penalty > 0.0 && repeat_penalty <= 2.0) {
+            anyhow::bail!("repeat_penalty must be in (0, 2], got {repeat_penalty}");
+        }
+
+        let presence_penalty = presence_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&presence_penalty) {
+            anyhow::bail!("presence_penalty must be between -2 and 2, got {presence_penalty}");
+        }
+
+        let frequency_penalty = frequency_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&frequency_penalty) {
+            anyhow::bail!("frequency_penalty must be between -2 and 2, got {frequency_penalty}");
+        }
+
+        Ok(Self {
+            repeat_penalty,
+            repeat_last_n: repeat_last_n.unwrap_or(DEFAULT_REPEAT_LAST_N),
+            presence_penalty,
+            frequency_penalty,
+        })
+    }
+
+    fn is_noop(&self) -> bool {
+        (self.repeat_penalty == 1.0 || self.repeat_last_n == 0)
+            && self.presence_penalty == 0.0
+            && self.frequency_penalty == 0.0
+    }
+
+    /// `tokens` is the whole sequence so far (prompt included), `generated` only the answer.
+    pub fn apply(
+        &self,
+        logits: &Tensor,
+        tokens: &[u32],
+        generated: &[u32],
+    ) -> candle_core::Result<Tensor> {
+        if self.is_noop() {
+            return Ok(logits.clone());
+        }
+
+        let device = logits.device();
+        let mut values = logits.to_vec1::<f32>()?;
+
+        if self.repeat_penalty != 1.0 {
+            let start_at = tokens.len().saturating_sub(self.repeat_last_n);
+            let mut seen = HashSet::new();
+            for &token in &tokens[start_at..] {
+                if !seen.insert(token) {
+                    continue;
+                }
+                if let Some(logit) = values.get_mut(token as usize) {
+                    if *logit >= 0.0 {
+                        *logit /= self.repeat_penalty;
+                    } else {
+                        *logit *= self.repeat_penalty;
+                    }
+                }
+            }
+        }
+
+        if self.presence_penalty != 0.0 || self.frequency_penalty != 0.0 {
+            let mut counts: HashMap<u32, usize> = HashMap::new();
+            for &token in generated {
+                *counts.entry(token).or_default() += 1;
+            }
+            for (token, count) in counts {
+                if let Some(logit) = values.get_mut(token as usize) {
+                    *logit -= self.presence_penalty + self.frequency_penalty * count as f32;
+                }
+            }
+        }
+
+        Tensor::from_vec(values, logits.shape(), device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn off_by_default() {
+        let penalties = PenaltyConfig::new(None, None, None, None).unwrap();
+        assert_eq!(penalties.repeat_penalty, 1.0);
+        assert!(penalties.is_noop());
+        assert!(!PenaltyConfig::new(Some(1.1), None, None, None)
+            .unwrap()
+            .is_noop());
+    }
+}
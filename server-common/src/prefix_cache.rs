@@ -0,0 +1,210 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Precomputed KV states of registered prompt prefixes, such as a long system
+/// prompt shared by many sessions, keyed by a hash of their tokens.
+///
+/// New sequences starting with a registered prefix get a copy of its cache
+/// and only prefill the rest of the prompt. Least recently used prefixes are
+/// evicted to stay within `budget_bytes`.
+pub struct PrefixCache<C> {
+    entries: HashMap<u64, Prefix<C>>,
+    // Prefixes of each length, to know which slices of a prompt to hash
+    lengths: BTreeMap<usize, usize>,
+    budget_bytes: usize,
+    used_bytes: usize,
+    clock: u64,
+}
+
+struct Prefix<C> {
+    tokens: Vec<u32>,
+    cache: C,
+    bytes: usize,
+    last_used: u64,
+}
+
+impl<C: Clone> PrefixCache<C> {
+    pub fn new(budget_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lengths: BTreeMap::new(),
+            budget_bytes,
+            used_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    pub fn contains(&self, tokens: &[u32]) -> bool {
+        self.entries
+            .get(&token_hash(tokens))
+            .is_some_and(|prefix| prefix.tokens == tokens)
+    }
+
+    /// Register `tokens` with the cache holding them, `bytes` in size; returns
+    /// the prefix's hash, or `None` if it is larger than the whole budget.
+    pub fn insert(&mut self, tokens: Vec<u32>, cache: C, bytes: usize) -> Option<u64> {
+        let hash = token_hash(&tokens);
+        self.remove(hash);
+        if bytes > self.budget_bytes {
+            return None;
+        }
+        while self.used_bytes + bytes > self.budget_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, prefix)| prefix.last_used)
+                .map(|(&hash, _)| hash)
+            else {
+                break;
+            };
+            self.remove(oldest);
+        }
+
+        self.clock += 1;
+        self.used_bytes += bytes;
+        *self.lengths.entry(tokens.len()).or_default() += 1;
+        self.entries.insert(
+            hash,
+            Prefix {
+                tokens,
+                cache,
+                bytes,
+                last_used: self.clock,
+            },
+        );
+        Some(hash)
+    }
+
+    /// The longest registered prefix of `tokens` that still leaves at least one
+    /// token to prefill, as its length and a copy of its cache.
+    pub fn lookup(&mut self, tokens: &[u32]) -> Option<(usize, C)> {
+        let (len, hash) = self
+            .lengths
+            .range(..tokens.len())
+            .rev()
+            .find_map(|(&len, _)| {
+                let hash = token_hash(&tokens[..len]);
+                let prefix = self.entries.get(&hash)?;
+                (prefix.tokens == tokens[..len]).then_some((len, hash))
+            })?;
+        self.clock += 1;
+        let prefix = self.entries.get_mut(&hash)?;
+        prefix.last_used = self.clock;
+        Some((len, prefix.cache.clone()))
+    }
+
+    fn remove(&mut self, hash: u64) {
+        let Some(prefix) = self.entries.remove(&hash) else {
+            return;
+        };
+        self.used_bytes -= prefix.bytes;
+        let len = prefix.tokens.len();
+        if let Some(count) = self.lengths.get_mut(&len) {
+            *count -= 1;
+            if *count == 0 {
+                self.lengths.remove(&len);
+            }
+        }
+    }
+}
+
+pub fn token_hash(tokens: &[u32]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    tokens.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut prefixes = PrefixCache::new(100);
+        prefixes.insert(vec![1, 2], "short", 10);
+        prefixes.insert(vec![1, 2, 3, 4], "long", 10);
+        prefixes.insert(vec![9, 9, 9], "other", 10);
+
+        assert_eq!(prefixes.lookup(&[1, 2, 3, 4, 5]), Some((4, "long")));
+        assert_eq!(prefixes.lookup(&[1, 2, 3, 7, 5]), Some((2, "short")));
+        assert_eq!(prefixes.lookup(&[9, 9, 9, 1]), Some((3, "other")));
+        assert_eq!(prefixes.lookup(&[1, 3, 2]), None);
+        assert_eq!(prefixes.lookup(&[]), None);
+    }
+
+    #[test]
+    fn leaves_a_token_to_prefill() {
+        let mut prefixes = PrefixCache::new(100);
+        prefixes.insert(vec![1, 2], "short", 10);
+        prefixes.insert(vec![1, 2, 3], "long", 10);
+
+        // The whole prompt is a prefix, but one token must still go through the model
+        assert_eq!(prefixes.lookup(&[1, 2, 3]), Some((2, "short")));
+        assert_eq!(prefixes.lookup(&[1, 2]), None);
+    }
+
+    #[test]
+    fn same_length_prefixes() {
+        let mut prefixes = PrefixCache::new(100);
+        prefixes.insert(vec![1, 2], "a", 10);
+        prefixes.insert(vec![3, 4], "b", 10);
+        assert_eq!(prefixes.lookup(&[3, 4, 5]), Some((2, "b")));
+        assert_eq!(prefixes.lookup(&[1, 2, 5]), Some((2, "a")));
+        assert_eq!(prefixes.lookup(&[1, 4, 5]), None);
+    }
+
+    #[test]
+    fn contains_and_hash() {
+        let mut prefixes = PrefixCache::new(100);
+        let hash = prefixes.insert(vec![1, 2, 3], (), 10).unwrap();
+        assert_eq!(hash, token_hash(&[1, 2, 3]));
+        assert_ne!(hash, token_hash(&[3, 2, 1]));
+        assert!(prefixes.contains(&[1, 2, 3]));
+        assert!(!prefixes.contains(&[1, 2]));
+        assert!(!prefixes.contains(&[]));
+    }
+
+    #[test]
+    fn reinsert_replaces_the_cache() {
+        let mut prefixes = PrefixCache::new(100);
+        prefixes.insert(vec![1, 2], "old", 10);
+        prefixes.insert(vec![1, 2], "new", 10);
+        assert_eq!(prefixes.lookup(&[1, 2, 3]), Some((2, "new")));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut prefixes = PrefixCache::new(25);
+        prefixes.insert(vec![1, 2], "a", 10);
+        prefixes.insert(vec![3, 4], "b", 10);
+        // Using "a" makes "b" the one to go
+        assert!(prefixes.lookup(&[1, 2, 5]).is_some());
+        prefixes.insert(vec![5, 6, 7], "c", 10);
+
+        assert!(prefixes.contains(&[1, 2]));
+        assert!(!prefixes.contains(&[3, 4]));
+        assert!(prefixes.contains(&[5, 6, 7]));
+        assert_eq!(prefixes.lookup(&[3, 4, 5]), None);
+    }
+
+    #[test]
+    fn over_budget_is_not_kept() {
+        let mut prefixes = PrefixCache::new(25);
+        prefixes.insert(vec![1, 2], "a", 10);
+        assert_eq!(prefixes.insert(vec![3, 4], "big", 30), None);
+        assert!(!prefixes.contains(&[3, 4]));
+        assert_eq!(prefixes.lookup(&[1, 2, 3]), Some((2, "a")));
+    }
+
+    #[test]
+    fn evicted_lengths_are_forgotten() {
+        let mut prefixes = PrefixCache::new(10);
+        prefixes.insert(vec![1, 2], "a", 10);
+        prefixes.insert(vec![1, 2, 3], "b", 10);
+        assert_eq!(prefixes.lengths.keys().copied().collect::<Vec<_>>(), [3]);
+        prefixes.insert(vec![1, 2, 3], "c", 10);
+        assert_eq!(prefixes.lookup(&[1, 2, 3, 4]), Some((3, "c")));
+        assert_eq!(prefixes.used_bytes, 10);
+    }
+}
@@ -0,0 +1,33 @@
+/// Longest n-gram matched against the prompt; shorter ones are tried after it.
+const MAX_NGRAM: usize = 3;
+/// Most tokens proposed per step.
+pub const MAX_DRAFT_TOKENS: usize = 10;
+
+/// Draft tokens for prompt-lookup decoding: find the last few tokens of
+/// `tokens` earlier in the prompt, `tokens[..prompt_len]`, and propose what
+/// followed them there, up to `max_tokens`. Empty when nothing matches.
+///
+/// The model checks the proposal in one forward pass. Sampling at each
+/// position and keeping drafts only while they match what was sampled leaves
+/// the output exactly as it would have been without them, so only speed
+/// depends on how well the proposal guesses.
+pub fn propose(tokens: &[u32], prompt_len: usize, max_tokens: usize) -> Vec<u32> {
+    let prompt = &tokens[..prompt_len.min(tokens.len())];
+    for n in (1..=MAX_NGRAM).rev() {
+        if tokens.len() <= n || prompt.len() <= n {
+            continue;
+        }
+        let tail = &tokens[tokens.len() - n..];
+        // The latest occurrence is the likeliest to continue the same way;
+        // it needs at least one prompt token after it
+        let found = (0..prompt.len() - n)
+            .rev()
+            .find(|&start| &prompt[start..start + n] == tail);
+        if let Some(start) = found {
+            let from = start + n;
+            let to = (from + max_tokens.min(MAX_DRAFT_TOKENS)).min(prompt.len());
+            return prompt[from..to].to_vec();
+        }
+    }
+    Vec::new()
+}
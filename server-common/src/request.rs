@@ -0,0 +1,238 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+use crate::beam::BeamConfig;
+use crate::constraint::ResponseFormat;
+use crate::fim::FimTokens;
+use crate::logit_bias::LogitBias;
+use crate::logprobs::TokenLogprob;
+use crate::penalties::PenaltyConfig;
+use crate::samplers::ChainConfig;
+use crate::sampling::SamplingConfig;
+use crate::{candidates, logprobs, stop};
+
+/// Query parameters of `/chat/stream`, also the body of `/chat`.
+#[derive(Deserialize, Clone, Default)]
+pub struct ChatStreamQuery {
+    pub session_id: String,
+    pub prompt: String,
+    pub max_tokens: Option<usize>,
+    /// Optional system prompt placed before the user turn.
+    pub system: Option<String>,
+    /// Skip the chat template and feed `prompt` to the model as-is.
+    #[serde(default)]
+    pub raw: bool,
+    /// With `raw`, text after the insertion point: the model fills in what goes
+    /// between `prompt` and this. Needs fill-in-the-middle tokens.
+    pub suffix: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub seed: Option<u64>,
+    /// Always pick the most likely token (ignores temperature/top_p/top_k).
+    #[serde(default)]
+    pub greedy: bool,
+    /// Order of the sampler chain, e.g. `top_k,min_p,temperature`.
+    pub samplers: Option<String>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    /// 2 samples with Mirostat v2 instead of top-k/top-p (0 = off).
+    pub mirostat: Option<u32>,
+    pub mirostat_tau: Option<f32>,
+    pub mirostat_eta: Option<f32>,
+    /// Penalty for tokens seen in the last `repeat_last_n` tokens (default 1.0, off).
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    /// JSON object from token ids or strings to a bias in [-100, 100]; -100 bans.
+    pub logit_bias: Option<String>,
+    /// A string, or a JSON array of strings, the answer must not contain.
+    pub banned_strings: Option<String>,
+    /// A stop string, or a JSON array of up to 4 stop strings.
+    pub stop: Option<String>,
+    /// Speed up answers that copy from the prompt by drafting tokens from it.
+    #[serde(default)]
+    pub prompt_lookup: bool,
+    /// JSON object: `{"type": "json_object"}`, `{"type": "json_schema", ...}` or
+    /// `{"type": "grammar", "grammar": "..."}`. Answers are kept to that shape.
+    pub response_format: Option<String>,
+    /// Report each token's log-probability with this many likeliest alternatives (0–20).
+    pub logprobs: Option<usize>,
+    /// How many candidate answers to generate (1–8, default 1).
+    pub n: Option<usize>,
+    /// Decode with beam search over this many beams (1–8) instead of sampling.
+    pub beam_width: Option<usize>,
+    /// Exponent of the answer length that beam scores are divided by (default 1).
+    pub length_penalty: Option<f32>,
+    /// End the beam search as soon as `beam_width` answers are finished.
+    #[serde(default)]
+    pub early_stopping: bool,
+}
+
+/// Validated per-request generation settings.
+#[derive(Clone)]
+pub struct GenerationOptions {
+    pub sampling: SamplingConfig,
+    pub penalties: PenaltyConfig,
+    pub logit_bias: Option<Arc<LogitBias>>,
+    pub stop: Vec<String>,
+    pub prompt_lookup: bool,
+    pub response_format: Option<ResponseFormat>,
+    pub logprobs: Option<usize>,
+    pub n: usize,
+    pub beam: Option<BeamConfig>,
+}
+
+impl GenerationOptions {
+    pub fn from_query(params: &ChatStreamQuery, tokenizer: &Tokenizer) -> anyhow::Result<Self> {
+        let options = Self {
+            sampling: SamplingConfig::new(
+                params.temperature,
+                params.top_p,
+                params.top_k,
+                params.seed,
+                params.greedy,
+            )?
+            .with_chain(ChainConfig::new(
+                params.samplers.as_deref(),
+                params.min_p,
+                params.typical_p,
+                params.mirostat,
+                params.mirostat_tau,
+                params.mirostat_eta,
+            )?),
+            penalties: PenaltyConfig::new(
+                params.repeat_penalty,
+                params.repeat_last_n,
+                params.presence_penalty,
+                params.frequency_penalty,
+            )?,
+            logit_bias: LogitBias::new(
+                params.logit_bias.as_deref(),
+                params.banned_strings.as_deref(),
+                tokenizer,
+            )?
+            .map(Arc::new),
+            stop: stop::parse_stop_param(params.stop.as_deref())?,
+            prompt_lookup: params.prompt_lookup,
+            response_format: params
+                .response_format
+                .as_deref()
+                .map(ResponseFormat::parse)
+                .transpose()?,
+            logprobs: logprobs::validate(params.logprobs)?,
+            n: candidates::validate(params.n)?,
+            beam: BeamConfig::new(
+                params.beam_width,
+                params.length_penalty,
+                params.early_stopping,
+            )?,
+        };
+        // Beam search picks tokens without the sampler, so nothing that hooks into it applies
+        if options.beam.is_some()
+            && (options.n > 1
+                || options.response_format.is_some()
+                || options.logprobs.is_some()
+                || options.prompt_lookup)
+        {
+            anyhow::bail!(
+                "beam_width can't be combined with n, response_format, logprobs or prompt_lookup"
+            );
+        }
+        // A raw prompt is the whole input; the model needs at least one token of it
+        if params.raw && params.prompt.is_empty() && params.suffix.is_none() {
+            anyhow::bail!("prompt must not be empty");
+        }
+        if params.suffix.is_some() {
+            if !params.raw {
+                anyhow::bail!("suffix needs raw");
+            }
+            if FimTokens::detect(tokenizer).is_none() {
+                anyhow::bail!(
+                    "this model has no fill-in-the-middle tokens, so suffix isn't supported"
+                );
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// What a finished generation hands back besides the streamed events.
+pub struct Generation {
+    pub answer: String,
+    pub finish_reason: &'static str,
+    /// One entry per generated token when the request asked for `logprobs`.
+    pub logprobs: Vec<TokenLogprob>,
+    /// Length-normalized log-probability of the answer, from beam search.
+    pub beam_score: Option<f32>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+#[derive(Serialize)]
+pub struct ChatResponse {
+    pub response: String,
+    pub finish_reason: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Vec<TokenLogprob>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beam_score: Option<f32>,
+    /// Every candidate in index order, the first one included, when the
+    /// request asked for several.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<Vec<ChatResponse>>,
+}
+
+impl ChatResponse {
+    pub fn new(generation: Generation, logprobs: bool) -> Self {
+        Self {
+            response: generation.answer,
+            finish_reason: generation.finish_reason,
+            logprobs: logprobs.then_some(generation.logprobs),
+            beam_score: generation.beam_score,
+            candidates: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PrefixRequest {
+    /// System prompt whose rendered tokens become the shared prefix.
+    pub system: String,
+}
+
+#[derive(Serialize)]
+pub struct PrefixResponse {
+    pub hash: String,
+    pub tokens: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn query(prompt: &str, raw: bool) -> ChatStreamQuery {
+        ChatStreamQuery {
+            session_id: "s".to_string(),
+            prompt: prompt.to_string(),
+            raw,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn raw_prompt_must_not_be_empty() {
+        let tokenizer = testing::byte_level();
+        let error = GenerationOptions::from_query(&query("", true), &tokenizer)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "prompt must not be empty");
+        assert!(GenerationOptions::from_query(&query("a", true), &tokenizer).is_ok());
+        // The chat template adds tokens of its own
+        assert!(GenerationOptions::from_query(&query("", false), &tokenizer).is_ok());
+    }
+}
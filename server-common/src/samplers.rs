@@ -0,0 +1,588 @@
+use anyhow::Result;
+use candle_core::{DType, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use rand::distr::weighted::WeightedIndex;
+use rand::distr::Distribution;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::sampling::SamplingConfig;
+
+const DEFAULT_MIROSTAT_TAU: f32 = 5.0;
+const DEFAULT_MIROSTAT_ETA: f32 = 0.1;
+
+/// A stage of the sampler chain, named as in the `samplers` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    TopK,
+    TypicalP,
+    TopP,
+    MinP,
+    Temperature,
+}
+
+/// The order llama.cpp applies its samplers in.
+const DEFAULT_ORDER: [Stage; 5] = [
+    Stage::TopK,
+    Stage::TypicalP,
+    Stage::TopP,
+    Stage::MinP,
+    Stage::Temperature,
+];
+
+/// Mirostat v2 settings: `tau` is the target surprise in bits, `eta` how fast
+/// the cut-off follows it.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MirostatConfig {
+    pub tau: f32,
+    pub eta: f32,
+}
+
+/// Samplers beyond what `LogitsProcessor` offers, and the order of the chain.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainConfig {
+    pub order: Vec<Stage>,
+    /// Drop tokens less likely than `min_p` times the likeliest one.
+    pub min_p: Option<f64>,
+    /// Keep the tokens whose surprise is closest to the distribution's entropy,
+    /// up to this much probability mass.
+    pub typical_p: Option<f64>,
+    /// Replaces top-k, typical-p, top-p and min-p when set.
+    pub mirostat: Option<MirostatConfig>,
+}
+
+impl ChainConfig {
+    /// `None` when the request uses none of these, so that it samples through
+    /// `LogitsProcessor` exactly as before.
+    pub fn new(
+        samplers: Option<&str>,
+        min_p: Option<f64>,
+        typical_p: Option<f64>,
+        mirostat: Option<u32>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+    ) -> Result<Option<Self>> {
+        let order = samplers.map(parse_order).transpose()?;
+
+        if let Some(p) = min_p {
+            if !(0.0..=1.0).contains(&p) {
+                anyhow::bail!("min_p must be between 0 and 1, got {p}");
+            }
+        }
+        if let Some(p) = typical_p {
+            if !(p > 0.0 && p <= 1.0) {
+                anyhow::bail!("typical_p must be in (0, 1], got {p}");
+            }
+        }
+
+        let mirostat = match mirostat.unwrap_or(0) {
+            0 => {
+                if mirostat_tau.is_some() || mirostat_eta.is_some() {
+                    anyhow::bail!("mirostat_tau and mirostat_eta need mirostat=2");
+                }
+                None
+            }
+            2 => {
+                let tau = mirostat_tau.unwrap_or(DEFAULT_MIROSTAT_TAU);
+                if !(tau > 0.0 && tau <= 20.0) {
+                    anyhow::bail!("mirostat_tau must be in (0, 20], got {tau}");
+                }
+                let eta = mirostat_eta.unwrap_or(DEFAULT_MIROSTAT_ETA);
+                if !(eta > 0.0 && eta <= 1.0) {
+                    anyhow::bail!("mirostat_eta must be in (0, 1], got {eta}");
+                }
+                Some(MirostatConfig { tau, eta })
+            }
+            v => anyhow::bail!("mirostat must be 0 (off) or 2 (Mirostat v2), got {v}"),
+        };
+
+        if order.is_none() && min_p.is_none() && typical_p.is_none() && mirostat.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            order: order.unwrap_or_else(|| DEFAULT_ORDER.to_vec()),
+            // Both are no-ops at their limits
+            min_p: min_p.filter(|&p| p > 0.0),
+            typical_p: typical_p.filter(|&p| p < 1.0),
+            mirostat,
+        }))
+    }
+}
+
+// "top_k,min_p,temperature": stages in the order they run; each at most once
+fn parse_order(raw: &str) -> Result<Vec<Stage>> {
+    let mut order = Vec::new();
+    for name in raw
+        .split([',', ';'])
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let stage: Stage = serde_json::from_value(serde_json::Value::String(name.to_string()))
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "unknown sampler {name:?}, expected top_k, typical_p, top_p, min_p or temperature"
+                )
+            })?;
+        if order.contains(&stage) {
+            anyhow::bail!("sampler {name:?} is listed twice");
+        }
+        order.push(stage);
+    }
+    Ok(order)
+}
+
+/// Picks the next token from the logits: candle's `LogitsProcessor` for plain
+/// temperature/top-k/top-p sampling, or the sampler chain.
+pub enum Sampler {
+    Processor(LogitsProcessor),
+    Chain(SamplerChain),
+}
+
+impl Sampler {
+    pub fn new(sampling: &SamplingConfig) -> Self {
+        match &sampling.chain {
+            Some(chain) => Self::Chain(SamplerChain::new(sampling, chain.clone())),
+            None => Self::Processor(sampling.logits_processor()),
+        }
+    }
+
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        match self {
+            Self::Processor(processor) => Ok(processor.sample(logits)?),
+            Self::Chain(chain) => chain.sample(logits),
+        }
+    }
+}
+
+/// Runs the configured stages over the candidate tokens, then draws from what
+/// is left. Each stage only acts when its parameter is set.
+pub struct SamplerChain {
+    config: ChainConfig,
+    temperature: f32,
+    top_k: Option<usize>,
+    top_p: Option<f64>,
+    greedy: bool,
+    // Mirostat's current cut-off surprise, starting at 2 * tau
+    mu: f32,
+    rng: StdRng,
+}
+
+// A token and its logit, after whatever temperature has been applied
+type Candidate = (u32, f32);
+
+impl SamplerChain {
+    fn new(sampling: &SamplingConfig, config: ChainConfig) -> Self {
+        Self {
+            mu: config.mirostat.map_or(0.0, |m| 2.0 * m.tau),
+            config,
+            temperature: sampling.temperature as f32,
+            top_k: sampling.top_k,
+            top_p: sampling.top_p,
+            greedy: sampling.greedy,
+            rng: StdRng::seed_from_u64(sampling.seed),
+        }
+    }
+
+    pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        let mut candidates: Vec<Candidate> = logits
+            .into_iter()
+            .enumerate()
+            .map(|(token, logit)| (token as u32, logit))
+            .collect();
+        sort_by_logit(&mut candidates);
+        if candidates.is_empty() {
+            anyhow::bail!("no logits to sample from");
+        }
+        if self.greedy {
+            return Ok(candidates[0].0);
+        }
+
+        if let Some(mirostat) = self.config.mirostat {
+            return self.sample_mirostat(candidates, mirostat);
+        }
+
+        for stage in self.config.order.clone() {
+            match stage {
+                Stage::TopK => {
+                    if let Some(k) = self.top_k {
+                        candidates.truncate(k.max(1));
+                    }
+                }
+                Stage::TypicalP => {
+                    if let Some(p) = self.config.typical_p {
+                        typical(&mut candidates, p as f32);
+                    }
+                }
+                Stage::TopP => {
+                    if let Some(p) = self.top_p {
+                        top_p(&mut candidates, p as f32);
+                    }
+                }
+                Stage::MinP => {
+                    if let Some(p) = self.config.min_p {
+                        min_p(&mut candidates, p as f32);
+                    }
+                }
+                Stage::Temperature => {
+                    candidates
+                        .iter_mut()
+                        .for_each(|(_, logit)| *logit /= self.temperature);
+                }
+            }
+        }
+
+        let probs = softmax(&candidates);
+        let index = self.draw(&probs)?;
+        Ok(candidates[index].0)
+    }
+
+    // Mirostat v2: drop tokens more surprising than `mu`, sample, and move `mu`
+    // by how far the sampled token's surprise was from the target
+    fn sample_mirostat(
+        &mut self,
+        mut candidates: Vec<Candidate>,
+        mirostat: MirostatConfig,
+    ) -> Result<u32> {
+        candidates
+            .iter_mut()
+            .for_each(|(_, logit)| *logit /= self.temperature);
+        let probs = softmax(&candidates);
+        let keep = probs
+            .iter()
+            .position(|&p| -p.log2() > self.mu)
+            .unwrap_or(probs.len())
+            .max(1);
+        candidates.truncate(keep);
+
+        let probs = softmax(&candidates);
+        let index = self.draw(&probs)?;
+        let surprise = -probs[index].log2();
+        self.mu -= mirostat.eta * (surprise - mirostat.tau);
+        Ok(candidates[index].0)
+    }
+
+    fn draw(&mut self, probs: &[f32]) -> Result<usize> {
+        let index = WeightedIndex::new(probs).map_err(|e| anyhow::anyhow!("cannot sample: {e}"))?;
+        Ok(index.sample(&mut self.rng))
+    }
+}
+
+fn sort_by_logit(candidates: &mut [Candidate]) {
+    candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+}
+
+// Probabilities of the candidates among themselves
+fn softmax(candidates: &[Candidate]) -> Vec<f32> {
+    let max = candidates
+        .iter()
+        .map(|&(_, logit)| logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = candidates
+        .iter()
+        .map(|&(_, logit)| (logit - max).exp())
+        .collect();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|e| e / sum).collect()
+}
+
+// Keep the likeliest tokens until they hold `p` of the probability mass
+fn top_p(candidates: &mut Vec<Candidate>, p: f32) {
+    let probs = softmax(candidates);
+    let mut cumsum = 0.0;
+    let mut keep = probs.len();
+    for (i, prob) in probs.iter().enumerate() {
+        cumsum += prob;
+        if cumsum >= p {
+            keep = i + 1;
+            break;
+        }
+    }
+    candidates.truncate(keep);
+}
+
+fn min_p(candidates: &mut Vec<Candidate>, p: f32) {
+    let probs = softmax(candidates);
+    let threshold = p * probs[0];
+    let keep = probs.iter().take_while(|&&prob| prob >= threshold).count();
+    candidates.truncate(keep.max(1));
+}
+
+// Locally typical sampling: order tokens by how far their surprise is from the
+// entropy, keep the closest until they hold `p` of the mass
+fn typical(candidates: &mut Vec<Candidate>, p: f32) {
+    let probs = softmax(candidates);
+    let entropy: f32 = probs
+        .iter()
+        .filter(|&&prob| prob > 0.0)
+        .map(|&prob| -prob * prob.ln())
+        .sum();
+    let mut order: Vec<usize> = (0..probs.len()).collect();
+    let distance = |i: usize| (-probs[i].ln() - entropy).abs();
+    order.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+
+    let mut cumsum = 0.0;
+    let mut keep = order.len();
+    for (i, &index) in order.iter().enumerate() {
+        cumsum += probs[index];
+        if cumsum >= p {
+            keep = i + 1;
+            break;
+        }
+    }
+    let mut kept: Vec<Candidate> = order[..keep].iter().map(|&i| candidates[i]).collect();
+    sort_by_logit(&mut kept);
+    *candidates = kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use std::collections::BTreeSet;
+
+    // Candidates whose probabilities among themselves are `probs`
+    fn candidates(probs: &[f32]) -> Vec<Candidate> {
+        probs
+            .iter()
+            .enumerate()
+            .map(|(token, p)| (token as u32, p.ln()))
+            .collect()
+    }
+
+    fn tokens(candidates: &[Candidate]) -> Vec<u32> {
+        candidates.iter().map(|&(token, _)| token).collect()
+    }
+
+    fn logits(probs: &[f32]) -> Tensor {
+        let logits: Vec<f32> = probs.iter().map(|p| p.ln()).collect();
+        Tensor::new(logits, &Device::Cpu).unwrap()
+    }
+
+    fn chain(samplers: &str, min_p: f64, temperature: f64, seed: u64) -> SamplerChain {
+        let config = ChainConfig::new(Some(samplers), Some(min_p), None, None, None, None)
+            .unwrap()
+            .unwrap();
+        let sampling =
+            SamplingConfig::new(Some(temperature), Some(1.0), None, Some(seed), false).unwrap();
+        SamplerChain::new(&sampling, config)
+    }
+
+    // Every token drawn in `draws` samples
+    fn drawn(chain: &mut SamplerChain, logits: &Tensor, draws: usize) -> BTreeSet<u32> {
+        (0..draws).map(|_| chain.sample(logits).unwrap()).collect()
+    }
+
+    #[test]
+    fn top_p_keeps_the_likeliest_mass() {
+        let mut kept = candidates(&[0.5, 0.3, 0.2]);
+        top_p(&mut kept, 0.75);
+        assert_eq!(tokens(&kept), [0, 1]);
+
+        let mut kept = candidates(&[0.5, 0.3, 0.2]);
+        top_p(&mut kept, 0.4);
+        assert_eq!(tokens(&kept), [0]);
+
+        let mut kept = candidates(&[0.5, 0.3, 0.2]);
+        top_p(&mut kept, 1.0);
+        assert_eq!(tokens(&kept), [0, 1, 2]);
+    }
+
+    #[test]
+    fn min_p_is_relative_to_the_likeliest() {
+        let mut kept = candidates(&[0.5, 0.3, 0.2]);
+        min_p(&mut kept, 0.5);
+        assert_eq!(tokens(&kept), [0, 1]);
+
+        let mut kept = candidates(&[0.5, 0.3, 0.2]);
+        min_p(&mut kept, 0.3);
+        assert_eq!(tokens(&kept), [0, 1, 2]);
+
+        // The likeliest token always stays
+        let mut kept = candidates(&[0.5, 0.3, 0.2]);
+        min_p(&mut kept, 1.0);
+        assert_eq!(tokens(&kept), [0]);
+    }
+
+    #[test]
+    fn typical_keeps_tokens_near_the_entropy() {
+        // The entropy is 1.33 nats: 0.2 (1.61 nats of surprise) is closer to
+        // it than 0.4 (0.92 nats), so the likeliest token goes first
+        let mut kept = candidates(&[0.4, 0.2, 0.2, 0.2]);
+        typical(&mut kept, 0.5);
+        assert_eq!(tokens(&kept), [1, 2, 3]);
+
+        // Here the likeliest one is the typical one
+        let mut kept = candidates(&[0.7, 0.1, 0.1, 0.1]);
+        typical(&mut kept, 0.5);
+        assert_eq!(tokens(&kept), [0]);
+    }
+
+    #[test]
+    fn softmax_of_candidates() {
+        let probs = softmax(&candidates(&[0.5, 0.3, 0.2]));
+        for (p, expected) in probs.iter().zip([0.5, 0.3, 0.2]) {
+            assert!((p - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn top_k_in_the_chain() {
+        let config = ChainConfig::new(Some("top_k"), None, None, None, None, None)
+            .unwrap()
+            .unwrap();
+        let sampling = SamplingConfig::new(Some(1.0), Some(1.0), Some(2), Some(3), false).unwrap();
+        let mut chain = SamplerChain::new(&sampling, config);
+        let logits = logits(&[0.1, 0.4, 0.2, 0.3]);
+        assert_eq!(drawn(&mut chain, &logits, 200), BTreeSet::from([1, 3]));
+    }
+
+    #[test]
+    fn chain_order_matters() {
+        let logits = logits(&[0.5, 0.3, 0.2]);
+
+        // min_p = 0.5 drops 0.2 as it stands...
+        let mut chain = self::chain("min_p,temperature", 0.5, 2.0, 1);
+        assert_eq!(drawn(&mut chain, &logits, 200), BTreeSet::from([0, 1]));
+
+        // ...but not once temperature 2 has flattened it to 0.26 against 0.41
+        let mut chain = self::chain("temperature,min_p", 0.5, 2.0, 1);
+        assert_eq!(drawn(&mut chain, &logits, 200), BTreeSet::from([0, 1, 2]));
+
+        // A stage left out of the order doesn't run
+        let mut chain = self::chain("temperature", 0.5, 1.0, 1);
+        assert_eq!(drawn(&mut chain, &logits, 200), BTreeSet::from([0, 1, 2]));
+    }
+
+    #[test]
+    fn temperature_zero_is_greedy() {
+        let mut chain = chain("temperature,min_p", 0.1, 0.0, 1);
+        let logits = logits(&[0.2, 0.5, 0.3]);
+        assert_eq!(drawn(&mut chain, &logits, 20), BTreeSet::from([1]));
+    }
+
+    #[test]
+    fn same_seed_same_draws() {
+        let logits = logits(&[0.25, 0.25, 0.25, 0.25]);
+        let draws = |seed| {
+            let mut chain = chain("min_p", 0.1, 1.0, seed);
+            (0..50)
+                .map(|_| chain.sample(&logits).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(5), draws(5));
+        assert_ne!(draws(5), draws(6));
+    }
+
+    #[test]
+    fn mirostat_holds_the_surprise_at_tau() {
+        let (tau, eta) = (3.0, 0.1);
+        let config = ChainConfig::new(None, None, None, Some(2), Some(tau), Some(eta))
+            .unwrap()
+            .unwrap();
+        let sampling = SamplingConfig::new(Some(1.0), Some(1.0), None, Some(11), false).unwrap();
+        let mut chain = SamplerChain::new(&sampling, config);
+        assert_eq!(chain.mu, 2.0 * tau);
+
+        // Zipf-like, so that the cut-off has plenty of tokens to choose from
+        let weights: Vec<f32> = (1..=500).map(|rank| 1.0 / rank as f32).collect();
+        let total: f32 = weights.iter().sum();
+        let probs: Vec<f32> = weights.iter().map(|w| w / total).collect();
+        let logits = logits(&probs);
+
+        for _ in 0..500 {
+            chain.sample(&logits).unwrap();
+        }
+        // Each step moves mu by eta * (tau - surprise), so the average
+        // surprise over a run is tau less the drift of mu over eta * steps
+        let (start, steps) = (chain.mu, 2000);
+        for _ in 0..steps {
+            chain.sample(&logits).unwrap();
+            assert!(chain.mu.is_finite() && chain.mu > 0.0);
+        }
+        let mean_surprise = tau - (chain.mu - start) / (eta * steps as f32);
+        assert!(
+            (mean_surprise - tau).abs() < 0.05,
+            "mean surprise {mean_surprise} should be near {tau}"
+        );
+        // and mu settles around 2 * tau
+        assert!((chain.mu - 2.0 * tau).abs() < tau, "mu = {}", chain.mu);
+    }
+
+    #[test]
+    fn mirostat_keeps_at_least_one_token() {
+        let config = ChainConfig::new(None, None, None, Some(2), Some(0.1), Some(1.0))
+            .unwrap()
+            .unwrap();
+        let sampling = SamplingConfig::new(Some(1.0), Some(1.0), None, Some(2), false).unwrap();
+        let mut chain = SamplerChain::new(&sampling, config);
+        chain.mu = 0.0;
+        let logits = logits(&[0.25, 0.25, 0.25, 0.25]);
+        assert_eq!(chain.sample(&logits).unwrap(), 0);
+    }
+
+    #[test]
+    fn parse_stage_order() {
+        assert_eq!(
+            parse_order(" top_k, min_p;temperature ").unwrap(),
+            [Stage::TopK, Stage::MinP, Stage::Temperature]
+        );
+        assert_eq!(
+            parse_order("temperature,typical_p,top_p").unwrap(),
+            [Stage::Temperature, Stage::TypicalP, Stage::TopP]
+        );
+        assert!(parse_order("").unwrap().is_empty());
+
+        let error = |raw: &str| parse_order(raw).unwrap_err().to_string();
+        assert!(error("top_k,top_a").contains("unknown sampler \"top_a\""));
+        assert!(error("TopK").contains("unknown sampler"));
+        assert!(error("min_p, top_k, min_p").contains("listed twice"));
+    }
+
+    #[test]
+    fn chain_config_validation() {
+        let config = |samplers, min_p, typical_p, mirostat, tau| {
+            ChainConfig::new(samplers, min_p, typical_p, mirostat, tau, None)
+        };
+        // Nothing beyond LogitsProcessor asked for
+        assert!(config(None, None, None, None, None).unwrap().is_none());
+        assert!(config(None, None, None, Some(0), None).unwrap().is_none());
+
+        let chain = config(None, Some(0.05), None, None, None).unwrap().unwrap();
+        assert_eq!(chain.order, DEFAULT_ORDER);
+        assert_eq!(chain.min_p, Some(0.05));
+        // No-ops at their limits
+        let chain = config(None, Some(0.0), Some(1.0), None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!((chain.min_p, chain.typical_p), (None, None));
+
+        let chain = config(None, None, None, Some(2), None).unwrap().unwrap();
+        let mirostat = chain.mirostat.unwrap();
+        assert_eq!(
+            (mirostat.tau, mirostat.eta),
+            (DEFAULT_MIROSTAT_TAU, DEFAULT_MIROSTAT_ETA)
+        );
+
+        assert!(config(None, Some(1.5), None, None, None).is_err());
+        assert!(config(None, None, Some(0.0), None, None).is_err());
+        assert!(config(None, None, None, Some(1), None).is_err());
+        assert!(config(None, None, None, None, Some(5.0)).is_err());
+        assert!(config(None, None, None, Some(2), Some(0.0)).is_err());
+        assert!(config(Some("top_q"), None, None, None, None).is_err());
+    }
+
+    #[test]
+    fn sampler_without_a_chain_uses_the_logits_processor() {
+        let sampling = SamplingConfig::new(Some(0.0), None, None, Some(0), false).unwrap();
+        let mut sampler = Sampler::new(&sampling);
+        assert!(matches!(sampler, Sampler::Processor(_)));
+        assert_eq!(sampler.sample(&logits(&[0.2, 0.5, 0.3])).unwrap(), 1);
+
+        let sampling =
+            sampling.with_chain(ChainConfig::new(None, Some(0.1), None, None, None, None).unwrap());
+        assert!(matches!(Sampler::new(&sampling), Sampler::Chain(_)));
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use serde::Serialize;
+
+use crate::samplers::ChainConfig;
+
+const DEFAULT_TEMPERATURE: f64 = 0.7;
+const DEFAULT_TOP_P: f64 = 0.9;
+
+/// Effective sampling settings for one request, after defaults and validation.
+#[derive(Debug, Clone, Serialize)]
+pub struct SamplingConfig {
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub seed: u64,
+    pub greedy: bool,
+    /// min-p, typical-p or Mirostat, which `LogitsProcessor` doesn't offer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain: Option<ChainConfig>,
+}
+
+impl SamplingConfig {
+    pub fn new(
+        temperature: Option<f64>,
+        top_p: Option<f64>,
+        top_k: Option<usize>,
+        seed: Option<u64>,
+        greedy: bool,
+    ) -> Result<Self> {
+        let temperature = temperature.unwrap_or(DEFAULT_TEMPERATURE);
+        if !(0.0..=2.0).contains(&temperature) {
+            anyhow::bail!("temperature must be between 0 and 2, got {temperature}");
+        }
+
+        let top_p = top_p.unwrap_or(DEFAULT_TOP_P);
+        if !(top_p > 0.0 && top_p <= 1.0) {
+            anyhow::bail!("top_p must be in (0, 1], got {top_p}");
+        }
+
+        if top_k == Some(0) {
+            anyhow::bail!("top_k must be at least 1");
+        }
+
+        Ok(Self {
+            temperature,
+            // top_p = 1 keeps every token, so there is nothing to filter
+            top_p: (top_p < 1.0).then_some(top_p),
+            top_k,
+            seed: seed.unwrap_or_else(random_seed),
+            // Temperature 0 is the usual way of asking for deterministic output
+            greedy: greedy || temperature < 1e-7,
+            chain: None,
+        })
+    }
+
+    pub fn with_chain(mut self, chain: Option<ChainConfig>) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        if self.greedy {
+            return Sampling::ArgMax;
+        }
+
+        let temperature = self.temperature;
+        match (self.top_k, self.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+
+    pub fn logits_processor(&self) -> LogitsProcessor {
+        LogitsProcessor::from_sampling(self.seed, self.sampling())
+    }
+}
+
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(42)
+}
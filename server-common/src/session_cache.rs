@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+/// KV caches of recently active sessions, so a follow-up turn only has to
+/// feed the tokens that weren't seen before.
+///
+/// Least recently used sessions are evicted to stay within `budget_bytes`.
+pub struct SessionCache<C> {
+    entries: HashMap<String, Entry<C>>,
+    budget_bytes: usize,
+    used_bytes: usize,
+    clock: u64,
+}
+
+struct Entry<C> {
+    cache: C,
+    tokens: Vec<u32>,
+    bytes: usize,
+    last_used: u64,
+}
+
+impl<C> SessionCache<C> {
+    pub fn new(budget_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            budget_bytes,
+            used_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// Remove and return the session's cache and the tokens it holds.
+    /// The caller owns it while generating and hands it back with [`insert`].
+    ///
+    /// [`insert`]: SessionCache::insert
+    pub fn take(&mut self, session_id: &str) -> Option<(C, Vec<u32>)> {
+        let entry = self.entries.remove(session_id)?;
+        self.used_bytes -= entry.bytes;
+        Some((entry.cache, entry.tokens))
+    }
+
+    /// Keep `cache`, holding `tokens`, for the session's next turn. An empty
+    /// `session_id` is no session, as for `/v1/completions`, and isn't kept.
+    pub fn insert(&mut self, session_id: String, cache: C, tokens: Vec<u32>, bytes: usize) {
+        if session_id.is_empty() {
+            return;
+        }
+        if let Some(old) = self.entries.remove(&session_id) {
+            self.used_bytes -= old.bytes;
+        }
+        if bytes > self.budget_bytes {
+            return;
+        }
+        while self.used_bytes + bytes > self.budget_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.used_bytes -= evicted.bytes;
+            }
+        }
+
+        self.clock += 1;
+        self.used_bytes += bytes;
+        self.entries.insert(
+            session_id,
+            Entry {
+                cache,
+                tokens,
+                bytes,
+                last_used: self.clock,
+            },
+        );
+    }
+}
+
+impl<C: Clone> SessionCache<C> {
+    /// A copy of the session's cache and the tokens it holds, leaving the
+    /// entry in place for whoever continues the session.
+    pub fn get(&mut self, session_id: &str) -> Option<(C, Vec<u32>)> {
+        let entry = self.entries.get_mut(session_id)?;
+        self.clock += 1;
+        entry.last_used = self.clock;
+        Some((entry.cache.clone(), entry.tokens.clone()))
+    }
+}
+
+/// Number of leading tokens `a` and `b` have in common.
+pub fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether each session still has an entry; takes them out to find out
+    fn kept(cache: &mut SessionCache<&'static str>, sessions: &[&str]) -> Vec<bool> {
+        sessions.iter().map(|id| cache.take(id).is_some()).collect()
+    }
+
+    #[test]
+    fn take_hands_the_entry_over() {
+        let mut cache = SessionCache::new(100);
+        cache.insert("a".into(), "cache a", vec![1, 2, 3], 60);
+        assert_eq!(cache.take("a"), Some(("cache a", vec![1, 2, 3])));
+        assert_eq!(cache.take("a"), None);
+
+        // Its bytes are free again
+        cache.insert("b".into(), "cache b", vec![], 100);
+        assert_eq!(cache.take("b"), Some(("cache b", vec![])));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = SessionCache::new(100);
+        cache.insert("a".into(), "a", vec![], 40);
+        cache.insert("b".into(), "b", vec![], 40);
+        // Handed back after a turn, "a" is now the most recent
+        let (a, tokens) = cache.take("a").unwrap();
+        cache.insert("a".into(), a, tokens, 40);
+
+        cache.insert("c".into(), "c", vec![], 40);
+        assert_eq!(kept(&mut cache, &["a", "b", "c"]), [true, false, true]);
+    }
+
+    #[test]
+    fn evicts_as_many_as_needed() {
+        let mut cache = SessionCache::new(100);
+        cache.insert("a".into(), "a", vec![], 30);
+        cache.insert("b".into(), "b", vec![], 30);
+        cache.insert("c".into(), "c", vec![], 30);
+        cache.insert("d".into(), "d", vec![], 70);
+        assert_eq!(
+            kept(&mut cache, &["a", "b", "c", "d"]),
+            [false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn entry_over_the_budget_is_not_kept() {
+        let mut cache = SessionCache::new(100);
+        cache.insert("a".into(), "a", vec![], 50);
+        cache.insert("b".into(), "b", vec![], 50);
+        cache.insert("c".into(), "c", vec![], 101);
+        // Nothing else was evicted for it
+        assert_eq!(kept(&mut cache, &["a", "b", "c"]), [true, true, false]);
+
+        // An older cache of the same session is dropped, being out of date
+        cache.insert("a".into(), "a", vec![], 50);
+        cache.insert("a".into(), "a, longer", vec![], 200);
+        assert_eq!(cache.take("a"), None);
+    }
+
+    #[test]
+    fn reinsert_replaces_the_entry() {
+        let mut cache = SessionCache::new(100);
+        cache.insert("a".into(), "old", vec![1], 60);
+        cache.insert("b".into(), "b", vec![], 40);
+        // The old entry's bytes don't count against the new one
+        cache.insert("a".into(), "new", vec![1, 2], 60);
+        assert_eq!(cache.take("a"), Some(("new", vec![1, 2])));
+        assert!(cache.take("b").is_some());
+    }
+
+    #[test]
+    fn empty_session_id_is_not_kept() {
+        let mut cache = SessionCache::new(100);
+        cache.insert(String::new(), "cache", vec![1], 10);
+        assert_eq!(cache.take(""), None);
+    }
+
+    #[test]
+    fn common_prefix() {
+        assert_eq!(common_prefix_len(&[], &[]), 0);
+        assert_eq!(common_prefix_len(&[], &[1, 2]), 0);
+        assert_eq!(common_prefix_len(&[1, 2, 3], &[1, 2, 3]), 3);
+        assert_eq!(common_prefix_len(&[1, 2], &[1, 2, 3]), 2);
+        assert_eq!(common_prefix_len(&[1, 2, 3], &[1, 2]), 2);
+        assert_eq!(common_prefix_len(&[1, 5, 3], &[1, 2, 3]), 1);
+        assert_eq!(common_prefix_len(&[4, 2, 3], &[1, 2, 3]), 0);
+    }
+
+    #[test]
+    fn get_leaves_the_entry_in_place() {
+        let mut cache = SessionCache::new(100);
+        cache.insert("a".into(), "a", vec![1], 40);
+        cache.insert("b".into(), "b", vec![], 40);
+        assert_eq!(cache.get("a"), Some(("a", vec![1])));
+        assert_eq!(cache.get("missing"), None);
+
+        // and counts as a use
+        cache.insert("c".into(), "c", vec![], 40);
+        assert_eq!(kept(&mut cache, &["a", "b", "c"]), [true, false, true]);
+    }
+}
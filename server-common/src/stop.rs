@@ -0,0 +1,178 @@
+use anyhow::Result;
+
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Parse the `stop` query parameter: either a JSON array of strings or one plain string.
+pub fn parse_stop_param(raw: Option<&str>) -> Result<Vec<String>> {
+    let Some(raw) = raw else {
+        return Ok(Vec::new());
+    };
+
+    let stops: Vec<String> = if raw.trim_start().starts_with('[') {
+        serde_json::from_str(raw)
+            .map_err(|e| anyhow::anyhow!("stop must be a string or a JSON array of strings: {e}"))?
+    } else {
+        vec![raw.to_string()]
+    };
+
+    validate_stops(stops)
+}
+
+pub fn validate_stops(stops: Vec<String>) -> Result<Vec<String>> {
+    if stops.len() > MAX_STOP_SEQUENCES {
+        anyhow::bail!("at most {MAX_STOP_SEQUENCES} stop sequences are allowed");
+    }
+    if stops.iter().any(|s| s.is_empty()) {
+        anyhow::bail!("stop sequences must not be empty");
+    }
+    Ok(stops)
+}
+
+/// Watches the streamed text for user-supplied stop strings.
+///
+/// Text that could be the start of a stop string is held back until it either
+/// completes the stop string (and is dropped) or turns out to be ordinary
+/// output, so clients never see a partial stop sequence.
+pub struct StopSequences {
+    stops: Vec<String>,
+    pending: String,
+    stopped: bool,
+}
+
+impl StopSequences {
+    pub fn new(stops: Vec<String>) -> Self {
+        Self {
+            stops,
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// Feed newly decoded text; returns the text that is safe to emit and
+    /// whether a stop string was hit. Once stopped, all further text is dropped.
+    pub fn push(&mut self, text: &str) -> (String, bool) {
+        if self.stopped {
+            return (String::new(), true);
+        }
+        if self.stops.is_empty() {
+            return (text.to_string(), false);
+        }
+
+        self.pending.push_str(text);
+
+        let first_match = self
+            .stops
+            .iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(at) = first_match {
+            let emit = self.pending[..at].to_string();
+            self.pending.clear();
+            self.stopped = true;
+            return (emit, true);
+        }
+
+        let hold = self.partial_match_len();
+        let emit_len = self.pending.len() - hold;
+        let emit = self.pending[..emit_len].to_string();
+        self.pending.drain(..emit_len);
+        (emit, false)
+    }
+
+    /// Release whatever is still held back once generation is over.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    // Length of the longest suffix of `pending` that is a prefix of some stop string
+    fn partial_match_len(&self) -> usize {
+        self.stops
+            .iter()
+            .flat_map(|stop| {
+                stop.char_indices()
+                    .skip(1)
+                    .map(|(i, _)| &stop[..i])
+                    .filter(|prefix| self.pending.ends_with(prefix))
+                    .map(|prefix| prefix.len())
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stops(stops: &[&str]) -> StopSequences {
+        StopSequences::new(stops.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn stop_string_split_across_chunks() {
+        let mut stop = stops(&["</answer>"]);
+        assert_eq!(stop.push("42 </an"), ("42 ".to_string(), false));
+        assert_eq!(stop.push("swer> more"), (String::new(), true));
+        assert_eq!(stop.push("ignored"), (String::new(), true));
+        assert_eq!(stop.flush(), "");
+    }
+
+    #[test]
+    fn held_back_text_is_released_when_the_match_fails() {
+        let mut stop = stops(&["STOP"]);
+        assert_eq!(stop.push("ab ST"), ("ab ".to_string(), false));
+        assert_eq!(stop.push("ART"), ("START".to_string(), false));
+
+        assert_eq!(stop.push("S"), (String::new(), false));
+        assert_eq!(stop.flush(), "S");
+    }
+
+    #[test]
+    fn stop_string_at_the_very_start() {
+        let mut stop = stops(&["\n\n"]);
+        assert_eq!(stop.push("\n\nHello"), (String::new(), true));
+
+        let mut stop = stops(&["User:"]);
+        assert_eq!(stop.push("Us"), (String::new(), false));
+        assert_eq!(stop.push("er:"), (String::new(), true));
+    }
+
+    #[test]
+    fn earliest_of_overlapping_stop_strings_wins() {
+        let mut stop = stops(&["bc", "abcd"]);
+        assert_eq!(stop.push("xab"), ("x".to_string(), false));
+        assert_eq!(stop.push("cd"), (String::new(), true));
+
+        // The longest partial match decides how much is held back
+        let mut stop = stops(&["aab", "ab"]);
+        assert_eq!(stop.push("xaa"), ("x".to_string(), false));
+        assert_eq!(stop.push("a"), ("a".to_string(), false));
+        assert_eq!(stop.push("b"), (String::new(), true));
+    }
+
+    #[test]
+    fn multibyte_prefixes_are_held_on_char_boundaries() {
+        let mut stop = stops(&["é!"]);
+        assert_eq!(stop.push("caf"), ("caf".to_string(), false));
+        assert_eq!(stop.push("é"), (String::new(), false));
+        assert_eq!(stop.push("?"), ("é?".to_string(), false));
+    }
+
+    #[test]
+    fn without_stop_strings_everything_passes_through() {
+        let mut stop = stops(&[]);
+        assert_eq!(stop.push("anything"), ("anything".to_string(), false));
+    }
+
+    #[test]
+    fn parse_stop_param_accepts_a_string_or_an_array() {
+        assert_eq!(parse_stop_param(None).unwrap(), Vec::<String>::new());
+        assert_eq!(parse_stop_param(Some("END")).unwrap(), vec!["END"]);
+        assert_eq!(
+            parse_stop_param(Some(r#"["a", "b"]"#)).unwrap(),
+            vec!["a", "b"]
+        );
+        assert!(parse_stop_param(Some(r#"["a", ""]"#)).is_err());
+        assert!(parse_stop_param(Some(r#"["a", "b", "c", "d", "e"]"#)).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Tokenizers for unit tests, small enough to reason about token by token.
+
+use std::str::FromStr;
+
+use serde_json::{json, Map, Value};
+use tokenizers::Tokenizer;
+
+/// Multi-byte and commonly used pieces of [`byte_level`], at fixed ids. "Ã" is
+/// the byte C3 and "©" the byte A9, so é comes as one token or as two.
+const BYTE_LEVEL_PIECES: [&str; 16] = [
+    "a", "{", "}", "\"", ":", "1", "2", "Ġ", "Ã", "©", "Ã©", "ä", "¸Ń", "\"}", "ðŁĺ", "Ģ",
+];
+
+/// End-of-sequence token of [`byte_level`], after every byte.
+pub const BYTE_LEVEL_EOS: u32 = 260;
+pub const BYTE_LEVEL_VOCAB_SIZE: usize = 261;
+
+/// A byte-level BPE tokenizer, like GPT-2's or Qwen's: the pieces above, then
+/// every other byte, then `<eos>`. Without merges, text encodes to one token
+/// per byte.
+pub fn byte_level() -> Tokenizer {
+    let mut vocab = Map::new();
+    for piece in BYTE_LEVEL_PIECES {
+        vocab.insert(piece.to_string(), json!(vocab.len()));
+    }
+    for byte in 0..=u8::MAX {
+        let piece = byte_level_char(byte).to_string();
+        if !vocab.contains_key(&piece) {
+            vocab.insert(piece, json!(vocab.len()));
+        }
+    }
+    vocab.insert("<eos>".to_string(), json!(vocab.len()));
+    assert_eq!(vocab.len(), BYTE_LEVEL_VOCAB_SIZE);
+
+    let byte_level = json!({"type": "ByteLevel", "add_prefix_space": false,
+                            "trim_offsets": true, "use_regex": true});
+    tokenizer(json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [special(BYTE_LEVEL_EOS, "<eos>")],
+        "normalizer": null,
+        "pre_tokenizer": byte_level,
+        "post_processor": null,
+        "decoder": byte_level,
+        "model": bpe(vocab, false),
+    }))
+}
+
+/// A SentencePiece-style tokenizer with byte fallback, like Llama's: `<unk>`,
+/// `<s>` and `</s>`, the bytes `<0x00>` to `<0xFF>`, then a few word pieces.
+/// Spaces are "▁"; text outside the pieces comes as byte tokens.
+pub fn byte_fallback() -> Tokenizer {
+    let mut vocab = Map::new();
+    for piece in ["<unk>", "<s>", "</s>"] {
+        vocab.insert(piece.to_string(), json!(vocab.len()));
+    }
+    for byte in 0..=u8::MAX {
+        vocab.insert(format!("<0x{byte:02X}>"), json!(vocab.len()));
+    }
+    for piece in ["▁", "a", "b", "▁a", "▁b"] {
+        vocab.insert(piece.to_string(), json!(vocab.len()));
+    }
+
+    let mut model = bpe(vocab, true);
+    model["unk_token"] = json!("<unk>");
+    tokenizer(json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [special(0, "<unk>"), special(1, "<s>"), special(2, "</s>")],
+        "normalizer": {"type": "Sequence", "normalizers": [
+            {"type": "Prepend", "prepend": "▁"},
+            {"type": "Replace", "pattern": {"String": " "}, "content": "▁"},
+        ]},
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": {"type": "Sequence", "decoders": [
+            {"type": "Replace", "pattern": {"String": "▁"}, "content": " "},
+            {"type": "ByteFallback"},
+            {"type": "Fuse"},
+            {"type": "Strip", "content": " ", "start": 1, "stop": 0},
+        ]},
+        "model": model,
+    }))
+}
+
+// GPT-2's byte-to-character table: printable Latin-1 characters stand for
+// themselves, the other bytes for U+0100 onwards in order
+fn byte_level_char(byte: u8) -> char {
+    let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+    if printable(byte) {
+        return char::from(byte);
+    }
+    let n = (0..byte).filter(|&b| !printable(b)).count() as u32;
+    char::from_u32(0x100 + n).unwrap()
+}
+
+fn special(id: u32, content: &str) -> Value {
+    json!({"id": id, "content": content, "single_word": false, "lstrip": false,
+           "rstrip": false, "normalized": false, "special": true})
+}
+
+fn bpe(vocab: Map<String, Value>, byte_fallback: bool) -> Value {
+    json!({"type": "BPE", "dropout": null, "unk_token": null,
+           "continuing_subword_prefix": null, "end_of_word_suffix": null,
+           "fuse_unk": false, "byte_fallback": byte_fallback,
+           "vocab": vocab, "merges": []})
+}
+
+fn tokenizer(json: Value) -> Tokenizer {
+    Tokenizer::from_str(&json.to_string()).unwrap()
+}
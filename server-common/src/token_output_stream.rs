@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+/// Incremental detokenizer for streaming, modelled on candle's `TokenOutputStream`.
+///
+/// Only a small window of tokens (the last emitted chunk plus the tokens not
+/// yet emitted) is decoded at each step, and text is released only once it is
+/// stable: it must extend what was decoded before and must not end in an
+/// incomplete UTF-8 sequence (shown by the tokenizer as U+FFFD).
+pub struct TokenOutputStream {
+    tokenizer: Arc<Tokenizer>,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenOutputStream {
+    /// The last prompt token is kept as decoding context so that the first
+    /// generated token keeps its leading space.
+    pub fn new(tokenizer: Arc<Tokenizer>, prompt_tokens: &[u32]) -> Self {
+        let tokens: Vec<u32> = prompt_tokens.last().copied().into_iter().collect();
+        let current_index = tokens.len();
+        Self {
+            tokenizer,
+            tokens,
+            prev_index: 0,
+            current_index,
+        }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(|e| anyhow::anyhow!("tokenizer decode error: {e}"))
+    }
+
+    /// Push a generated token; returns newly stable text, if any.
+    pub fn next_token(&mut self, token: u32) -> Result<Option<String>> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        self.tokens.push(token);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+
+        let new_text = match text.strip_prefix(prev_text.as_str()) {
+            Some(new_text) => new_text.to_string(),
+            // The new token changed how the previous chunk decodes (e.g. merged
+            // spaces); that chunk was already sent, so decode the rest on its own.
+            None => self.decode(&self.tokens[self.current_index..])?,
+        };
+
+        if new_text.is_empty() || new_text.ends_with('\u{FFFD}') {
+            return Ok(None);
+        }
+
+        self.prev_index = self.current_index;
+        self.current_index = self.tokens.len();
+        Ok(Some(new_text))
+    }
+
+    /// Text for tokens that were pushed but not emitted yet.
+    pub fn decode_rest(&self) -> Result<String> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..self.current_index])?;
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        Ok(match text.strip_prefix(prev_text.as_str()) {
+            Some(rest) => rest.to_string(),
+            None => self.decode(&self.tokens[self.current_index..])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn stream(prompt: &[&str]) -> (TokenOutputStream, impl Fn(&str) -> u32) {
+        let tokenizer = Arc::new(testing::byte_fallback());
+        let ids = {
+            let tokenizer = Arc::clone(&tokenizer);
+            move |piece: &str| tokenizer.token_to_id(piece).unwrap()
+        };
+        let prompt: Vec<u32> = prompt.iter().map(|piece| ids(piece)).collect();
+        (TokenOutputStream::new(tokenizer, &prompt), ids)
+    }
+
+    #[test]
+    fn waits_for_split_characters() {
+        let (mut stream, id) = stream(&["<s>", "▁a"]);
+        // 中 is E4 B8 AD
+        assert_eq!(stream.next_token(id("<0xE4>")).unwrap(), None);
+        assert_eq!(stream.next_token(id("<0xB8>")).unwrap(), None);
+        assert_eq!(
+            stream.next_token(id("<0xAD>")).unwrap().as_deref(),
+            Some("中")
+        );
+        // The space isn't stripped from a token after the first
+        assert_eq!(stream.next_token(id("▁b")).unwrap().as_deref(), Some(" b"));
+        assert_eq!(stream.decode_rest().unwrap(), "");
+    }
+
+    #[test]
+    fn keeps_the_leading_space() {
+        let (mut stream, id) = stream(&["▁a"]);
+        assert_eq!(stream.next_token(id("▁b")).unwrap().as_deref(), Some(" b"));
+        assert_eq!(stream.next_token(id("a")).unwrap().as_deref(), Some("a"));
+        // Special tokens decode to nothing
+        assert_eq!(stream.next_token(id("</s>")).unwrap(), None);
+    }
+
+    #[test]
+    fn previous_chunk_decoding_differently() {
+        // The prompt ends inside 中. Once the rest of it comes, the prompt's
+        // last token no longer decodes to the "\u{FFFD}" it did on its own, so
+        // the new tokens are decoded by themselves.
+        let (mut stream, id) = stream(&["▁a", "<0xE4>"]);
+        assert_eq!(stream.next_token(id("<0xB8>")).unwrap(), None);
+        assert_eq!(stream.next_token(id("<0xAD>")).unwrap(), None);
+        assert_eq!(stream.decode_rest().unwrap(), "\u{FFFD}\u{FFFD}");
+        assert_eq!(
+            stream.next_token(id("b")).unwrap().as_deref(),
+            Some("\u{FFFD}\u{FFFD}b")
+        );
+    }
+
+    #[test]
+    fn decode_rest_flushes_held_back_text() {
+        let (mut stream, id) = stream(&["▁a"]);
+        assert_eq!(stream.next_token(id("▁b")).unwrap().as_deref(), Some(" b"));
+        assert_eq!(stream.next_token(id("<0xE4>")).unwrap(), None);
+        assert_eq!(stream.next_token(id("<0xB8>")).unwrap(), None);
+        // Generation stopped inside a character
+        assert_eq!(stream.decode_rest().unwrap(), "\u{FFFD}\u{FFFD}");
+    }
+}
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use candle_core::DType;
+use serde::Deserialize;
+
+use crate::quantize::QuantFormat;
+
+/// One layer of server settings, from the config file or from flags and
+/// environment variables. Unset fields fall through to the layer below.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartialConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub model_dir: Option<PathBuf>,
+    pub dtype: Option<String>,
+    pub threads: Option<usize>,
+    pub db_path: Option<PathBuf>,
+    pub quantize: Option<String>,
+    pub default_max_tokens: Option<usize>,
+    pub max_tokens_cap: Option<usize>,
+    pub max_concurrent: Option<usize>,
+    pub max_queue: Option<usize>,
+    pub session_cache_mb: Option<usize>,
+    pub prefix_cache_mb: Option<usize>,
+    pub system_prefixes: Option<Vec<String>>,
+}
+
+impl PartialConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read config {path:?}: {e}"))?;
+        toml::from_str(&text).map_err(|e| anyhow::anyhow!("invalid config {path:?}: {e}"))
+    }
+
+    /// Stack `over` on top of `self`; fields set in `over` win.
+    pub fn merge(self, over: PartialConfig) -> Self {
+        Self {
+            host: over.host.or(self.host),
+            port: over.port.or(self.port),
+            model_dir: over.model_dir.or(self.model_dir),
+            dtype: over.dtype.or(self.dtype),
+            threads: over.threads.or(self.threads),
+            db_path: over.db_path.or(self.db_path),
+            quantize: over.quantize.or(self.quantize),
+            default_max_tokens: over.default_max_tokens.or(self.default_max_tokens),
+            max_tokens_cap: over.max_tokens_cap.or(self.max_tokens_cap),
+            max_concurrent: over.max_concurrent.or(self.max_concurrent),
+            max_queue: over.max_queue.or(self.max_queue),
+            session_cache_mb: over.session_cache_mb.or(self.session_cache_mb),
+            prefix_cache_mb: over.prefix_cache_mb.or(self.prefix_cache_mb),
+            system_prefixes: over.system_prefixes.or(self.system_prefixes),
+        }
+    }
+}
+
+/// Fully resolved server settings.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub model_dir: PathBuf,
+    /// Weight dtype for safetensors models (GGUF files keep their own).
+    pub dtype: DType,
+    /// CPU threads for the model; `None` uses every core.
+    pub threads: Option<usize>,
+    pub db_path: PathBuf,
+    pub quantize: Option<QuantFormat>,
+    /// `max_tokens` for requests that don't set one.
+    pub default_max_tokens: usize,
+    /// Upper bound on `max_tokens`, whatever the request asks for.
+    pub max_tokens_cap: usize,
+    /// Generations running at once; further requests wait in the queue.
+    pub max_concurrent: usize,
+    /// Requests allowed to wait; beyond that they get a 503.
+    pub max_queue: usize,
+    /// Memory for KV caches kept between turns of recent sessions; 0 turns it off.
+    pub session_cache_mb: usize,
+    /// Memory for the KV states of registered system prefixes; least recently used go first.
+    pub prefix_cache_mb: usize,
+    /// System prompts whose KV state is computed at startup and shared by every session using them.
+    pub system_prefixes: Vec<String>,
+}
+
+impl ServerConfig {
+    /// Fill the unset fields of `layer` from `defaults` and validate the result.
+    pub fn resolve(layer: PartialConfig, defaults: ServerConfig) -> Result<Self> {
+        let dtype = match layer.dtype.as_deref() {
+            Some(dtype) => parse_dtype(dtype)?,
+            None => defaults.dtype,
+        };
+        let quantize = match layer.quantize.as_deref() {
+            Some(format) => Some(format.parse::<QuantFormat>().map_err(anyhow::Error::msg)?),
+            None => defaults.quantize,
+        };
+
+        let config = Self {
+            host: layer.host.unwrap_or(defaults.host),
+            port: layer.port.unwrap_or(defaults.port),
+            model_dir: layer.model_dir.unwrap_or(defaults.model_dir),
+            dtype,
+            threads: layer.threads.or(defaults.threads),
+            db_path: layer.db_path.unwrap_or(defaults.db_path),
+            quantize,
+            default_max_tokens: layer
+                .default_max_tokens
+                .unwrap_or(defaults.default_max_tokens),
+            max_tokens_cap: layer.max_tokens_cap.unwrap_or(defaults.max_tokens_cap),
+            max_concurrent: layer.max_concurrent.unwrap_or(defaults.max_concurrent),
+            max_queue: layer.max_queue.unwrap_or(defaults.max_queue),
+            session_cache_mb: layer.session_cache_mb.unwrap_or(defaults.session_cache_mb),
+            prefix_cache_mb: layer.prefix_cache_mb.unwrap_or(defaults.prefix_cache_mb),
+            system_prefixes: layer.system_prefixes.unwrap_or(defaults.system_prefixes),
+        };
+
+        if config.threads == Some(0) {
+            anyhow::bail!("threads must be at least 1");
+        }
+        if config.max_concurrent == 0 {
+            anyhow::bail!("max_concurrent must be at least 1");
+        }
+        if config.max_tokens_cap == 0 {
+            anyhow::bail!("max_tokens_cap must be at least 1");
+        }
+        if config.default_max_tokens == 0 || config.default_max_tokens > config.max_tokens_cap {
+            anyhow::bail!(
+                "default_max_tokens must be between 1 and max_tokens_cap ({})",
+                config.max_tokens_cap
+            );
+        }
+        Ok(config)
+    }
+
+    pub fn bind_addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn parse_dtype(dtype: &str) -> Result<DType> {
+    match dtype.to_ascii_lowercase().as_str() {
+        "f32" => Ok(DType::F32),
+        "bf16" => Ok(DType::BF16),
+        "f16" => Ok(DType::F16),
+        other => anyhow::bail!("unsupported dtype {other:?}, expected f32, bf16 or f16"),
+    }
+}
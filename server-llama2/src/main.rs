@@ -1,68 +1,214 @@
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
 use axum::{extract::State, routing::post, Json, Router};
-use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::models::llama::{Cache as LlamaCache, Config, Llama, LlamaConfig};
-use serde::{Deserialize, Serialize};
+use candle_core::{DType, Device};
+use clap::{Parser, Subcommand};
 use tokenizers::Tokenizer;
 use tokio::net::TcpListener;
 
 use axum::extract::Query;
 use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use std::convert::Infallible;
 use tokio::sync::mpsc;
 use tokio::task::spawn_blocking;
 use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 
-use candle_core::{Error as CandleError, IndexOp, Result as CandleResult};
+use candle_core::IndexOp;
 use tower_http::cors::{Any, CorsLayer};
 
-mod db;
-use crate::db::{load_all_history, save_chat_turn, SessionWithMessages};
-use db::{init_db, DbPool};
+mod config;
+mod model;
+mod quantize;
+
+use server_common::admission::{Admission, Ticket};
+use server_common::beam::{BeamConfig, BeamSearch, Hypothesis};
+use server_common::chat_template::{ChatMessage, ChatTemplate};
+use server_common::constraint::{ResponseFormat, Vocabulary};
+use server_common::context::PromptContext;
+use server_common::db::{
+    init_db, load_all_history, load_session_messages, save_chat_turn, DbPool, SessionWithMessages,
+};
+use server_common::events::StreamEvent;
+use server_common::openai::{
+    ChatCompletionRequest, ChunkKind, Chunks, Completion, CompletionRequest,
+};
+use server_common::prefix_cache::PrefixCache;
+use server_common::request::{
+    ChatResponse, ChatStreamQuery, Generation, GenerationOptions, PrefixRequest, PrefixResponse,
+};
+use server_common::samplers::Sampler;
+use server_common::session_cache::SessionCache;
+use server_common::stop::StopSequences;
+use server_common::token_output_stream::TokenOutputStream;
+use server_common::{
+    admission, beam, candidates, context, eos, fim, logprobs, openai, prefix_cache, prompt_lookup,
+    session_cache,
+};
+
+use crate::config::{PartialConfig, ServerConfig};
+use crate::model::{KvCache, LlamaModel};
+use crate::quantize::QuantFormat;
+
+/// Command-line flags; each one can also be set through its environment
+/// variable and overrides the config file.
+#[derive(Parser)]
+#[command(about = "Candle TinyLlama chat server")]
+struct Cli {
+    /// TOML config file
+    #[arg(long, env = "TINYLLAMA_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long, env = "TINYLLAMA_HOST")]
+    host: Option<String>,
+    /// Port to listen on [default: 8000]
+    #[arg(long, env = "TINYLLAMA_PORT")]
+    port: Option<u16>,
+    /// Model directory [default: models/tinyllama]
+    #[arg(long, env = "TINYLLAMA_MODEL_DIR")]
+    model_dir: Option<PathBuf>,
+    /// Weight dtype for safetensors models: f32, bf16 or f16 [default: f32]
+    #[arg(long, env = "TINYLLAMA_DTYPE")]
+    dtype: Option<String>,
+    /// CPU threads used by the model [default: all cores]
+    #[arg(long, env = "TINYLLAMA_THREADS")]
+    threads: Option<usize>,
+    /// SQLite database file [default: chat.db]
+    #[arg(long, env = "TINYLLAMA_DB_PATH")]
+    db_path: Option<PathBuf>,
+    /// Quantize the safetensors weights while loading (q8_0 or q4_k)
+    #[arg(long, env = "TINYLLAMA_QUANTIZE")]
+    quantize: Option<String>,
+    /// max_tokens for requests that don't set it [default: 64]
+    #[arg(long, env = "TINYLLAMA_DEFAULT_MAX_TOKENS")]
+    default_max_tokens: Option<usize>,
+    /// Upper bound on max_tokens [default: 256]
+    #[arg(long, env = "TINYLLAMA_MAX_TOKENS_CAP")]
+    max_tokens_cap: Option<usize>,
+    /// Generations running at once [default: 1]
+    #[arg(long, env = "TINYLLAMA_MAX_CONCURRENT")]
+    max_concurrent: Option<usize>,
+    /// Requests waiting for a slot before new ones get a 503 [default: 16]
+    #[arg(long, env = "TINYLLAMA_MAX_QUEUE")]
+    max_queue: Option<usize>,
+    /// Memory for KV caches kept between turns of a session, in MB; 0 disables [default: 512]
+    #[arg(long, env = "TINYLLAMA_SESSION_CACHE_MB")]
+    session_cache_mb: Option<usize>,
+    /// Memory for the KV states of registered system prefixes, in MB [default: 128]
+    #[arg(long, env = "TINYLLAMA_PREFIX_CACHE_MB")]
+    prefix_cache_mb: Option<usize>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    /// Defaults, then the config file, then environment variables and flags.
+    fn server_config(&self) -> Result<ServerConfig> {
+        let file = match &self.config {
+            Some(path) => PartialConfig::from_file(path)?,
+            None => PartialConfig::default(),
+        };
+        let overrides = PartialConfig {
+            host: self.host.clone(),
+            port: self.port,
+            model_dir: self.model_dir.clone(),
+            dtype: self.dtype.clone(),
+            threads: self.threads,
+            db_path: self.db_path.clone(),
+            quantize: self.quantize.clone(),
+            default_max_tokens: self.default_max_tokens,
+            max_tokens_cap: self.max_tokens_cap,
+            max_concurrent: self.max_concurrent,
+            max_queue: self.max_queue,
+            session_cache_mb: self.session_cache_mb,
+            prefix_cache_mb: self.prefix_cache_mb,
+            // Only set in the config file
+            system_prefixes: None,
+        };
+        let defaults = ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 8000,
+            model_dir: PathBuf::from("models/tinyllama"),
+            dtype: DType::F32,
+            threads: None,
+            db_path: PathBuf::from("chat.db"),
+            quantize: None,
+            default_max_tokens: 64,
+            max_tokens_cap: 256,
+            max_concurrent: 1,
+            max_queue: 16,
+            session_cache_mb: 512,
+            prefix_cache_mb: 128,
+            system_prefixes: Vec::new(),
+        };
+        ServerConfig::resolve(file.merge(overrides), defaults)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Quantize the safetensors weights and write them to a GGUF file
+    Quantize {
+        /// q8_0 or q4_k
+        #[arg(long, default_value = "q8_0")]
+        format: QuantFormat,
+        /// Output file (default: <model dir>/model-<format>.gguf)
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
 
 #[derive(Clone)]
 struct AppState {
-    model: Arc<Llama>,
-    config: Config,
-    dtype: DType,
-    device: Device,
+    model: Arc<LlamaModel>,
+    /// Context window of the loaded model, in tokens.
+    max_context: usize,
+    default_max_tokens: usize,
+    max_tokens_cap: usize,
+    admission: Admission,
+    /// Name of the model directory, reported as the model id by `/v1/models`.
+    model_id: String,
+    /// KV caches of recent sessions, to continue from on their next turn.
+    sessions: Arc<Mutex<SessionCache<KvCache>>>,
+    /// KV states of registered system prompts, copied into new sequences.
+    prefixes: Arc<Mutex<PrefixCache<KvCache>>>,
     tokenizer: Arc<Tokenizer>,
+    chat_template: Arc<ChatTemplate>,
+    eos_tokens: Arc<HashSet<u32>>,
+    /// Token texts for constrained generation, built on first use.
+    vocabulary: Arc<Mutex<Option<Arc<Vocabulary>>>>,
     db_pool: DbPool,
 }
 
-#[derive(Deserialize)]
-struct ChatRequest {
-    prompt: String,
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
-}
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = cli.server_config()?;
 
-#[derive(Deserialize, Clone)]
-struct ChatStreamQuery {
-    pub session_id: String,
-    pub prompt: String,
-    #[serde(default = "default_max_tokens")]
-    pub max_tokens: usize,
-}
+    if let Some(threads) = config.threads {
+        // candle sizes its CPU thread pool from this; set it before any model code runs
+        std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+    }
 
-#[derive(Serialize)]
-struct ChatResponse {
-    response: String,
-}
+    if let Some(Command::Quantize { format, output }) = cli.command {
+        return write_quantized_gguf(&config.model_dir, format, output);
+    }
 
-fn default_max_tokens() -> usize {
-    64
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(config))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let db_pool = init_db().await?;
-    let state = load_tinyllama_state(db_pool)?;
+async fn serve(config: ServerConfig) -> Result<()> {
+    let db_pool = init_db(&config.db_path).await?;
+    let state = load_tinyllama_state(db_pool, &config)?;
+    for system in &config.system_prefixes {
+        register_system_prefix(&state, system)?;
+    }
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -73,13 +219,17 @@ async fn main() -> Result<()> {
         .route("/chat", post(chat_handler))
         .route("/chat/stream", axum::routing::get(chat_stream_handler))
         .route("/history", axum::routing::get(history_handler))
+        .route("/prefixes", post(prefix_handler))
+        .route("/v1/chat/completions", post(chat_completions_handler))
+        .route("/v1/completions", post(completions_handler))
+        .route("/v1/models", axum::routing::get(models_handler))
         .layer(cors)
         .with_state(state);
 
-    let addr: std::net::SocketAddr = "0.0.0.0:8000".parse().unwrap();
+    let addr = config.bind_addr();
     println!("🚀 Candle TinyLlama server running on http://{addr}");
 
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = TcpListener::bind(&addr).await?;
     axum::serve(listener, app).await.unwrap();
 
     Ok(())
@@ -88,38 +238,66 @@ async fn main() -> Result<()> {
 async fn chat_stream_handler(
     State(state): State<AppState>,
     Query(params): Query<ChatStreamQuery>,
-) -> Sse<ReceiverStream<Result<Event, Infallible>>> {
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
     println!(
         "[TinyLlama] Received frontend request. Prompt: {}",
         params.prompt
     );
 
-    let (tx, rx) = mpsc::channel::<Result<Event, Infallible>>(16);
+    let options = GenerationOptions::from_query(&params, &state.tokenizer)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[TinyLlama] Queue is full, rejecting request");
+        return Err(admission::queue_full_response());
+    };
+
+    let history = load_history(&state, &params.session_id).await;
+    let events = spawn_generation(state, params, history, options, ticket);
+
+    let stream = ReceiverStream::new(events).map(|event| Ok(event.into_sse()));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// Waits for a free slot, runs the generation, then saves to DB, in a task of
+// its own; the returned receiver gets the generation's events
+fn spawn_generation(
+    state: AppState,
+    params: ChatStreamQuery,
+    history: Vec<ChatMessage>,
+    options: GenerationOptions,
+    ticket: Ticket,
+) -> mpsc::Receiver<StreamEvent> {
+    let (tx, rx) = mpsc::channel::<StreamEvent>(16);
 
     let state_for_gen = state.clone();
     let params_for_gen = params.clone();
-    let state_for_db = state.clone();
-    let params_for_db = params.clone();
-
-    let handle =
-        spawn_blocking(move || run_streaming_generation(state_for_gen, params_for_gen, tx));
+    let response_format = options.response_format.clone();
 
     tokio::spawn(async move {
-        match handle.await {
-            // handle.await : Result<anyhow::Result<String>, JoinError>
-            Ok(Ok(final_answer)) => {
-                if let Err(e) = save_chat_turn(
-                    &state_for_db.db_pool,
-                    &params_for_db.session_id,
-                    &params_for_db.prompt,
-                    &final_answer,
-                )
-                .await
-                {
-                    eprintln!("[TinyLlama] Failed to save chat turn: {e}");
-                } else {
-                    println!("[TinyLlama] Chat turn saved to DB.");
-                }
+        // Tell the client where it stands while it waits
+        let queue_tx = tx.clone();
+        let permit = tokio::select! {
+            permit = ticket.wait(|position| {
+                let data = serde_json::json!({ "position": position });
+                let _ = queue_tx.try_send(StreamEvent::json("queued", None, data));
+            }) => permit,
+            _ = tx.closed() => {
+                println!("[TinyLlama] Client left the queue");
+                return;
+            }
+        };
+
+        let handle = spawn_blocking(move || {
+            run_streaming_generation(state_for_gen, params_for_gen, history, options, tx)
+        });
+        let result = handle.await;
+        drop(permit);
+
+        match result {
+            // handle.await : Result<anyhow::Result<Vec<Generation>>, JoinError>
+            Ok(Ok(generations)) => {
+                save_answer(&state, &params, response_format.as_ref(), &generations).await;
             }
             Ok(Err(e)) => {
                 eprintln!("[TinyLlama] Generation error: {e}");
@@ -130,106 +308,737 @@ async fn chat_stream_handler(
         }
     });
 
-    let stream = ReceiverStream::new(rx);
-    Sse::new(stream).keep_alive(KeepAlive::default())
+    rx
+}
+
+// Waits for a free slot and runs the generation without streaming, then saves to DB
+async fn generate_answer(
+    state: &AppState,
+    params: &ChatStreamQuery,
+    history: Vec<ChatMessage>,
+    options: GenerationOptions,
+    ticket: Ticket,
+) -> anyhow::Result<Vec<Generation>> {
+    let response_format = options.response_format.clone();
+    let permit = ticket.wait(|_| {}).await;
+
+    // Nobody reads the events, but a closed channel would look like a
+    // disconnected client, so they are drained
+    let (tx, mut rx) = mpsc::channel::<StreamEvent>(16);
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+    let state_for_gen = state.clone();
+    let params_for_gen = params.clone();
+    let result = spawn_blocking(move || {
+        run_streaming_generation(state_for_gen, params_for_gen, history, options, tx)
+    })
+    .await;
+    drop(permit);
+
+    let generations = match result {
+        Ok(Ok(generations)) => generations,
+        Ok(Err(e)) => {
+            eprintln!("[TinyLlama] Generation error: {e}");
+            return Err(e);
+        }
+        Err(join_err) => {
+            eprintln!("[TinyLlama] Join error in spawn_blocking: {join_err}");
+            return Err(join_err.into());
+        }
+    };
+    save_answer(state, params, response_format.as_ref(), &generations).await;
+    Ok(generations)
+}
+
+// Earlier turns of this session, so the model sees the whole conversation
+async fn load_history(state: &AppState, session_id: &str) -> Vec<ChatMessage> {
+    match load_session_messages(&state.db_pool, session_id).await {
+        Ok(rows) => rows
+            .into_iter()
+            .map(|row| ChatMessage::new(&row.role, &row.content))
+            .collect(),
+        Err(e) => {
+            eprintln!("[TinyLlama] Failed to load session history: {e}");
+            Vec::new()
+        }
+    }
+}
+
+// Every candidate answer becomes a sibling assistant message of the prompt
+async fn save_answer(
+    state: &AppState,
+    params: &ChatStreamQuery,
+    response_format: Option<&ResponseFormat>,
+    generations: &[Generation],
+) {
+    // Raw `/v1/completions` have no session to save to
+    if params.session_id.is_empty() {
+        return;
+    }
+    let mut replies = Vec::new();
+    for (index, generation) in generations.iter().enumerate() {
+        // A stop sequence or the token limit can cut a constrained answer short
+        match response_format.map(|f| f.validate(&generation.answer)) {
+            Some(Err(e)) => eprintln!(
+                "[TinyLlama] Answer {index} doesn't match the response format, not saving it: {e}"
+            ),
+            _ => replies.push((index, generation.answer.as_str())),
+        }
+    }
+    if replies.is_empty() {
+        return;
+    }
+
+    if let Err(e) =
+        save_chat_turn(&state.db_pool, &params.session_id, &params.prompt, &replies).await
+    {
+        eprintln!("[TinyLlama] Failed to save chat turn: {e}");
+    } else {
+        println!("[TinyLlama] Chat turn saved to DB.");
+    }
 }
 
 fn run_streaming_generation(
     state: AppState,
     params: ChatStreamQuery,
-    tx: mpsc::Sender<Result<Event, Infallible>>,
-) -> anyhow::Result<String> {
+    history: Vec<ChatMessage>,
+    options: GenerationOptions,
+    tx: mpsc::Sender<StreamEvent>,
+) -> anyhow::Result<Vec<Generation>> {
+    // Hard cap to avoid insane values from frontend
+    let max_steps = params
+        .max_tokens
+        .unwrap_or(state.default_max_tokens)
+        .min(state.max_tokens_cap);
+
+    println!("--> [TinyLlama] Encoding prompt...");
+    let context = build_context(&state, &params, &history, max_steps)?;
+    // A short context may leave less room than was reserved for the answer
+    let max_steps = max_steps.min(state.max_context.saturating_sub(context.tokens.len()));
+    if context.trimmed_turns > 0 || context.truncated {
+        println!(
+            "--> [TinyLlama] Prompt over budget: trimmed {} turns, truncated = {}",
+            context.trimmed_turns, context.truncated
+        );
+    }
+    let context_event = serde_json::json!({
+        "prompt_tokens": context.tokens.len(),
+        "trimmed_turns": context.trimmed_turns,
+        "truncated": context.truncated,
+    });
+    let _ = tx.blocking_send(StreamEvent::json("context", None, context_event));
+
+    // Candidates run one after another. The first keeps its KV cache for the
+    // session; the others start from a copy of it, which holds the whole prompt
+    let mut generations = Vec::with_capacity(options.n);
+    for index in 0..options.n {
+        if tx.is_closed() {
+            break;
+        }
+        let mut options = options.clone();
+        options.sampling.seed = candidates::seed(options.sampling.seed, index);
+        let candidate = (options.n > 1).then_some(index);
+        let generation = match options.beam.clone() {
+            Some(config) => beam_search(
+                &state,
+                &params.session_id,
+                context.tokens.clone(),
+                max_steps,
+                options,
+                config,
+                &tx,
+            )?,
+            None => generate(
+                &state,
+                &params.session_id,
+                context.tokens.clone(),
+                max_steps,
+                options,
+                candidate,
+                &tx,
+            )?,
+        };
+        generations.push(generation);
+    }
+
+    let _ = tx.blocking_send(StreamEvent::Done);
+    println!("--> [TinyLlama] Generation finished, sent [DONE]");
+
+    Ok(generations)
+}
+
+// Generate one answer to `tokens`, streaming it into `tx`
+fn generate(
+    state: &AppState,
+    session_id: &str,
+    mut tokens: Vec<u32>,
+    max_steps: usize,
+    options: GenerationOptions,
+    candidate: Option<usize>,
+    tx: &mpsc::Sender<StreamEvent>,
+) -> anyhow::Result<Generation> {
     let model = Arc::clone(&state.model);
     let tokenizer = Arc::clone(&state.tokenizer);
-    let device = state.device.clone();
+    let prompt_len = tokens.len();
 
-    println!("--> [TinyLlama] Creating KV cache...");
-    let mut cache = LlamaCache::new(true, state.dtype, &state.config, &device)?;
+    // Of several candidates the first is the one the conversation continues from
+    let continues_session = candidate.unwrap_or(0) == 0;
+    let mut cache = restore_cache(state, session_id, &tokens, continues_session)?;
+    let cached_tokens = cache.len();
 
-    println!("--> [TinyLlama] Encoding prompt...");
-    let encoding = tokenizer
-        .encode(params.prompt.clone(), true)
-        .map_err(candle_core::Error::msg)?;
-    let mut tokens: Vec<u32> = encoding.get_ids().to_vec();
-
-    let eos_token = tokenizer.get_vocab(true).get("</s>").copied().unwrap_or(2);
+    let eos_tokens = Arc::clone(&state.eos_tokens);
 
-    let mut start_pos: usize = 0;
-    let seed = 42;
-    let temperature = Some(0.7);
-    let top_p = Some(0.9);
-    let mut logits_processor = LogitsProcessor::new(seed, temperature, top_p);
+    let GenerationOptions {
+        sampling,
+        penalties,
+        logit_bias,
+        stop,
+        prompt_lookup,
+        response_format,
+        logprobs: top_logprobs,
+        n: _,
+        beam: _,
+    } = options;
+    let mut sampler = Sampler::new(&sampling);
+    let mut constraint = match response_format {
+        Some(format) => Some(format.constraint(vocabulary(state)?)),
+        None => None,
+    };
+    let mut stop_sequences = StopSequences::new(stop);
 
-    let mut prev_text_len = 0usize;
+    // Only stream what comes after the prompt
+    let mut token_stream = TokenOutputStream::new(Arc::clone(&tokenizer), &tokens);
     let mut final_answer = String::new();
 
-    let max_steps = params.max_tokens.min(256);
+    let mut finish_reason = "length";
+    let mut finished = max_steps == 0;
+    let mut drafted = 0;
+    let mut accepted = 0;
+    let mut token_logprobs = Vec::new();
     println!("--> [TinyLlama] Entering generation loop (max_steps = {max_steps})...");
 
-    for step in 0..max_steps {
-        let context_size = if step > 0 { 1 } else { tokens.len() };
-        let start_at = tokens.len().saturating_sub(context_size);
-        let ctx = &tokens[start_at..];
+    while !finished {
+        // Everything not in the KV cache yet: the prompt, then the last sampled token
+        let ctx = &tokens[cache.len()..];
 
-        let input = Tensor::new(ctx, &device)?.reshape((1, ctx.len()))?;
+        // Tokens copied from the prompt, checked in the same forward pass
+        let generated = tokens.len() - prompt_len;
+        let drafts = if prompt_lookup && generated > 0 {
+            prompt_lookup::propose(&tokens, prompt_len, max_steps - generated)
+        } else {
+            Vec::new()
+        };
+        let logits = if drafts.is_empty() {
+            model.forward(ctx, &mut cache)?.unsqueeze(0)?
+        } else {
+            model.score(&[ctx, &drafts].concat(), &mut cache)?
+        };
 
-        let logits = model.forward(&input, start_pos, &mut cache)?;
-        start_pos += ctx.len();
+        // Sample after the last token, then after each draft for as long as
+        // the drafts match what was sampled
+        for i in 0..=drafts.len() {
+            let logits = logits.i(logits.dim(0)? - drafts.len() - 1 + i)?;
+            let logits = penalties.apply(&logits, &tokens, &tokens[prompt_len..])?;
+            let logits = match &logit_bias {
+                Some(bias) => bias.apply(&logits, &tokens[prompt_len..])?,
+                None => logits,
+            };
+            let distribution = match top_logprobs {
+                Some(_) => Some(logprobs::log_softmax(&logits)?),
+                None => None,
+            };
+            let next_token = match &mut constraint {
+                Some(constraint) => constraint.sample(&logits, &mut sampler, &eos_tokens)?,
+                None => sampler.sample(&logits)?,
+            };
+            tokens.push(next_token);
 
-        let logits = logits.i(0)?.to_dtype(DType::F32)?;
-        let next_token = logits_processor.sample(&logits)?;
-        tokens.push(next_token);
+            if let (Some(top_n), Some(distribution)) = (top_logprobs, distribution) {
+                let entry = logprobs::entry(&distribution, next_token, top_n, &tokenizer)?;
+                let event = StreamEvent::json("logprobs", candidate, serde_json::to_value(&entry)?);
+                let _ = tx.blocking_send(event);
+                token_logprobs.push(entry);
+            }
 
-        let full_text = tokenizer
-            .decode(&tokens, true)
-            .map_err(candle_core::Error::msg)?;
+            println!(
+                "--> [TinyLlama] step {}, sampled token {next_token}",
+                tokens.len() - prompt_len - 1
+            );
 
-        println!(
-            "--> [TinyLlama] step {step}, current text length {}",
-            full_text.len()
-        );
+            if let Some(new_part) = token_stream.next_token(next_token)? {
+                // Hold back anything that might be the start of a stop string
+                let (new_part, hit_stop) = stop_sequences.push(&new_part);
 
-        if full_text.len() > prev_text_len {
-            let new_part = &full_text[prev_text_len..];
+                if !new_part.is_empty() {
+                    final_answer.push_str(&new_part);
 
-            if !new_part.is_empty() {
-                final_answer.push_str(new_part);
+                    let event = StreamEvent::text(candidate, new_part);
 
-                let event = Event::default().event("message").data(new_part.to_string());
+                    if tx.blocking_send(event).is_err() {
+                        println!("--> [TinyLlama] Client disconnected, stopping generation");
+                        finished = true;
+                        break;
+                    }
+                }
 
-                if tx.blocking_send(Ok(event)).is_err() {
-                    println!("--> [TinyLlama] Client disconnected, stopping generation");
+                if hit_stop {
+                    println!("--> [TinyLlama] Hit stop sequence, stopping generation");
+                    finish_reason = "stop";
+                    finished = true;
                     break;
                 }
-                prev_text_len = full_text.len();
             }
+
+            if eos_tokens.contains(&next_token) {
+                println!("--> [TinyLlama] Hit EOS, stopping generation");
+                finish_reason = "stop";
+                finished = true;
+                break;
+            }
+
+            if tokens.len() - prompt_len >= max_steps {
+                println!("--> [TinyLlama] Reached max_steps = {max_steps}, stopping generation");
+                finished = true;
+                break;
+            }
+
+            if drafts.get(i) != Some(&next_token) {
+                break;
+            }
+            accepted += 1;
         }
+        drafted += drafts.len();
+        // Forget the positions of rejected drafts
+        cache.truncate(tokens.len() - 1)?;
+    }
 
-        if next_token == eos_token {
-            println!("--> [TinyLlama] Hit EOS, stopping generation");
-            break;
+    // Flush text still buffered in the decoder or held back as a possible stop string
+    let (mut rest, _) = stop_sequences.push(&token_stream.decode_rest()?);
+    rest.push_str(&stop_sequences.flush());
+    if !rest.is_empty() {
+        final_answer.push_str(&rest);
+        let _ = tx.blocking_send(StreamEvent::text(candidate, rest));
+    }
+
+    let mut summary = serde_json::json!({
+        "sampling": sampling,
+        "penalties": penalties,
+        "completion_tokens": tokens.len() - prompt_len,
+        "cached_tokens": cached_tokens,
+        "finish_reason": finish_reason,
+    });
+    if prompt_lookup {
+        let acceptance_rate = if drafted > 0 {
+            accepted as f64 / drafted as f64
+        } else {
+            0.0
+        };
+        summary["speculative"] = serde_json::json!({
+            "draft_tokens": drafted,
+            "accepted_tokens": accepted,
+            "acceptance_rate": acceptance_rate,
+        });
+    }
+    let _ = tx.blocking_send(StreamEvent::json("summary", candidate, summary));
+
+    if continues_session {
+        let bytes = cache.size_in_bytes();
+        let cached = tokens[..cache.len()].to_vec();
+        state
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(session_id.to_string(), cache, cached, bytes);
+    }
+
+    Ok(Generation {
+        answer: final_answer,
+        finish_reason,
+        logprobs: token_logprobs,
+        beam_score: None,
+        prompt_tokens: prompt_len,
+        completion_tokens: tokens.len() - prompt_len,
+    })
+}
+
+// Start from whichever covers more of the prompt: a registered prefix, or the
+// session's previous cache cut back to the part the prompt still shares.
+// At least one prompt token is always left to feed. Unless `take_session`,
+// the session's cache is copied and stays for the answer that continues it.
+fn restore_cache(
+    state: &AppState,
+    session_id: &str,
+    tokens: &[u32],
+    take_session: bool,
+) -> anyhow::Result<KvCache> {
+    let mut restored = state.prefixes.lock().unwrap().lookup(tokens);
+    let session = {
+        let mut sessions = state.sessions.lock().unwrap();
+        if take_session {
+            sessions.take(session_id)
+        } else {
+            sessions.get(session_id)
+        }
+    };
+    if let Some((cache, cached_tokens)) = session {
+        let shared = session_cache::common_prefix_len(&cached_tokens, tokens)
+            .min(tokens.len().saturating_sub(1));
+        if shared > restored.as_ref().map_or(0, |(len, _)| *len) {
+            restored = Some((shared, cache));
+        }
+    }
+    match restored {
+        Some((len, mut cache)) => {
+            cache.truncate(len)?;
+            println!("--> [TinyLlama] Reusing {len} cached prompt tokens");
+            Ok(cache)
         }
+        None => {
+            println!("--> [TinyLlama] Creating KV cache...");
+            Ok(state.model.new_cache())
+        }
+    }
+}
 
-        if step + 1 == max_steps {
-            println!("--> [TinyLlama] Reached max_steps = {max_steps}, stopping generation");
+// Find the likeliest answer to `tokens` with beam search. Nothing streams until
+// the search is over; then the best beam goes out in one piece.
+fn beam_search(
+    state: &AppState,
+    session_id: &str,
+    tokens: Vec<u32>,
+    max_steps: usize,
+    options: GenerationOptions,
+    config: BeamConfig,
+    tx: &mpsc::Sender<StreamEvent>,
+) -> anyhow::Result<Generation> {
+    let cache = restore_cache(state, session_id, &tokens, true)?;
+    let cached_tokens = cache.len();
+    let penalties = options.penalties;
+
+    println!(
+        "--> [TinyLlama] Entering beam search ({} beams, max_steps = {max_steps})...",
+        config.width
+    );
+    let mut search = BeamSearch::new(config, max_steps, cache);
+    let mut prompt_cache = None;
+    while !search.is_done() {
+        if tx.is_closed() {
+            println!("--> [TinyLlama] Client disconnected, stopping beam search");
+            break;
+        }
+        let mut distributions = Vec::with_capacity(search.beams().len());
+        for beam in search.beams_mut() {
+            let history = [&tokens[..], &beam.tokens].concat();
+            let logits = state
+                .model
+                .forward(&history[beam.cache.len()..], &mut beam.cache)?;
+            if beam.tokens.is_empty() {
+                prompt_cache = Some(beam.cache.clone());
+            }
+            let logits = penalties.apply(&logits, &history, &beam.tokens)?;
+            let logits = match &options.logit_bias {
+                Some(bias) => bias.apply(&logits, &beam.tokens)?,
+                None => logits,
+            };
+            distributions.push(logprobs::log_softmax(&logits)?);
         }
+        search.step(&distributions, &state.eos_tokens)?;
     }
 
-    let _ = tx.blocking_send(Ok(Event::default().event("message").data("[DONE]")));
-    println!("--> [TinyLlama] Generation finished, sent [DONE]");
+    // The beams' caches end in different answers; the session keeps the prompt's
+    if let Some(cache) = prompt_cache {
+        let bytes = cache.size_in_bytes();
+        let cached = tokens[..cache.len()].to_vec();
+        state
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(session_id.to_string(), cache, cached, bytes);
+    }
 
-    Ok(final_answer)
+    let config = search.config().clone();
+    let best = search.best().unwrap_or(Hypothesis {
+        tokens: Vec::new(),
+        score: 0.0,
+        stopped: false,
+    });
+    let (answer, hit_stop) = beam::answer_text(
+        Arc::clone(&state.tokenizer),
+        &tokens,
+        &best.tokens,
+        options.stop,
+    )?;
+    let finish_reason = if best.stopped || hit_stop {
+        "stop"
+    } else {
+        "length"
+    };
+    println!(
+        "--> [TinyLlama] Beam search finished: {} tokens, score {:.4}",
+        best.tokens.len(),
+        best.score
+    );
+
+    if !answer.is_empty() {
+        let _ = tx.blocking_send(StreamEvent::text(None, answer.clone()));
+    }
+    // The end-of-sequence token counts, as when sampling
+    let completion_tokens = best.tokens.len() + usize::from(best.stopped);
+    let summary = serde_json::json!({
+        "beam": {
+            "width": config.width,
+            "length_penalty": config.length_penalty,
+            "early_stopping": config.early_stopping,
+            "score": best.score,
+        },
+        "penalties": penalties,
+        "completion_tokens": completion_tokens,
+        "cached_tokens": cached_tokens,
+        "finish_reason": finish_reason,
+    });
+    let _ = tx.blocking_send(StreamEvent::json("summary", None, summary));
+
+    Ok(Generation {
+        answer,
+        finish_reason,
+        logprobs: Vec::new(),
+        beam_score: Some(best.score),
+        prompt_tokens: tokens.len(),
+        completion_tokens,
+    })
 }
 
+fn vocabulary(state: &AppState) -> anyhow::Result<Arc<Vocabulary>> {
+    let mut vocabulary = state.vocabulary.lock().unwrap();
+    if let Some(vocabulary) = vocabulary.as_ref() {
+        return Ok(Arc::clone(vocabulary));
+    }
+    println!("--> [TinyLlama] Indexing the vocabulary for constrained generation...");
+    let built = Arc::new(Vocabulary::new(&state.tokenizer)?);
+    *vocabulary = Some(Arc::clone(&built));
+    Ok(built)
+}
+
+fn build_context(
+    state: &AppState,
+    params: &ChatStreamQuery,
+    history: &[ChatMessage],
+    max_new_tokens: usize,
+) -> anyhow::Result<PromptContext> {
+    let budget = context::prompt_budget(state.max_context, max_new_tokens);
+    if params.raw {
+        return match &params.suffix {
+            Some(suffix) => fim::fit(&state.tokenizer, &params.prompt, suffix, budget),
+            None => context::fit_raw(&state.tokenizer, &params.prompt, budget),
+        };
+    }
+
+    let mut messages = Vec::new();
+    if let Some(system) = &params.system {
+        messages.push(ChatMessage::new("system", system));
+    }
+    messages.extend_from_slice(history);
+    messages.push(ChatMessage::new("user", &params.prompt));
+
+    context::fit_chat(&state.tokenizer, &state.chat_template, messages, budget)
+}
+
+/// Same parameters as `/chat/stream` as a JSON body, answered in one response.
 async fn chat_handler(
-    State(_state): State<AppState>,
-    Json(_req): Json<ChatRequest>,
-) -> Result<Json<ChatResponse>, axum::http::StatusCode> {
+    State(state): State<AppState>,
+    Json(params): Json<ChatStreamQuery>,
+) -> Result<Json<ChatResponse>, Response> {
+    println!("[TinyLlama] Received request. Prompt: {}", params.prompt);
+
+    let options = GenerationOptions::from_query(&params, &state.tokenizer)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[TinyLlama] Queue is full, rejecting request");
+        return Err(admission::queue_full_response());
+    };
+
+    let history = load_history(&state, &params.session_id).await;
+    let generations = generate_answer(&state, &params, history, options, ticket)
+        .await
+        .map_err(|e| {
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        })?;
+
+    let logprobs = params.logprobs.is_some();
+    let mut responses: Vec<ChatResponse> = generations
+        .into_iter()
+        .map(|generation| ChatResponse::new(generation, logprobs))
+        .collect();
+    if responses.len() == 1 {
+        return Ok(Json(responses.remove(0)));
+    }
+    let first = &responses[0];
     Ok(Json(ChatResponse {
-        response: "Please use /chat/stream for now.".to_string(),
+        response: first.response.clone(),
+        finish_reason: first.finish_reason,
+        logprobs: first.logprobs.clone(),
+        beam_score: first.beam_score,
+        candidates: Some(responses),
     }))
 }
 
+/// OpenAI-compatible chat completions, streamed as `chat.completion.chunk`s
+/// when `stream` is set. The turn is saved like a `/chat` one.
+async fn chat_completions_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Response {
+    let (params, history) = match request.to_query() {
+        Ok(query) => query,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+    println!(
+        "[TinyLlama] Received OpenAI chat request. Prompt: {}",
+        params.prompt
+    );
+
+    let options = match GenerationOptions::from_query(&params, &state.tokenizer) {
+        Ok(options) => options,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[TinyLlama] Queue is full, rejecting request");
+        return admission::queue_full_response();
+    };
+
+    let completion = Completion::new("chatcmpl", &state.model_id);
+    if request.stream {
+        let chunks = Chunks::new(
+            completion,
+            ChunkKind::Chat,
+            options.n,
+            request.include_usage(),
+        );
+        let events = spawn_generation(state, params, history, options, ticket);
+        let stream = openai::relay(ReceiverStream::new(events), chunks);
+        return Sse::new(stream)
+            .keep_alive(KeepAlive::default())
+            .into_response();
+    }
+
+    match generate_answer(&state, &params, history, options, ticket).await {
+        Ok(generations) => {
+            Json(completion.chat_response(generations, request.logprobs)).into_response()
+        }
+        Err(e) => openai::error_response(axum::http::StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// OpenAI-compatible raw completions: the prompt is continued without the
+/// chat template, or filled in up to `suffix`, and nothing is saved.
+async fn completions_handler(
+    State(state): State<AppState>,
+    Json(request): Json<CompletionRequest>,
+) -> Response {
+    let params = match request.to_query() {
+        Ok(params) => params,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+    println!(
+        "[TinyLlama] Received OpenAI completion request. Prompt: {}",
+        params.prompt
+    );
+
+    let options = match GenerationOptions::from_query(&params, &state.tokenizer) {
+        Ok(options) => options,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[TinyLlama] Queue is full, rejecting request");
+        return admission::queue_full_response();
+    };
+
+    let completion = Completion::new("cmpl", &state.model_id);
+    let echo = request.echo.then(|| params.prompt.clone());
+    if request.stream {
+        let chunks = Chunks::new(
+            completion,
+            ChunkKind::Text { echo },
+            options.n,
+            request.include_usage(),
+        );
+        let events = spawn_generation(state, params, Vec::new(), options, ticket);
+        let stream = openai::relay(ReceiverStream::new(events), chunks);
+        return Sse::new(stream)
+            .keep_alive(KeepAlive::default())
+            .into_response();
+    }
+
+    let logprobs = request.logprobs.is_some();
+    match generate_answer(&state, &params, Vec::new(), options, ticket).await {
+        Ok(generations) => {
+            Json(completion.text_response(generations, echo.as_deref(), logprobs)).into_response()
+        }
+        Err(e) => openai::error_response(axum::http::StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn models_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(openai::model_list(&state.model_id))
+}
+
+/// Precompute the KV state of a system prompt so that sessions using it skip its prefill.
+fn register_system_prefix(state: &AppState, system: &str) -> Result<PrefixResponse> {
+    let tokens = context::system_prefix(&state.tokenizer, &state.chat_template, system)?;
+    if tokens.is_empty() {
+        anyhow::bail!("prefix is empty");
+    }
+    let len = tokens.len();
+    // A prompt starting with it needs at least one more token
+    if len >= state.max_context {
+        anyhow::bail!(
+            "prefix is {len} tokens, the context window only {}",
+            state.max_context
+        );
+    }
+    let hash = prefix_cache::token_hash(&tokens);
+
+    if !state.prefixes.lock().unwrap().contains(&tokens) {
+        let mut cache = state.model.new_cache();
+        state.model.forward(&tokens, &mut cache)?;
+        let bytes = cache.size_in_bytes();
+        if state
+            .prefixes
+            .lock()
+            .unwrap()
+            .insert(tokens, cache, bytes)
+            .is_none()
+        {
+            anyhow::bail!("prefix needs {bytes} bytes of KV cache, more than prefix_cache_mb");
+        }
+        println!("[TinyLlama] Registered prefix {hash:016x} ({len} tokens)");
+    }
+
+    Ok(PrefixResponse {
+        hash: format!("{hash:016x}"),
+        tokens: len,
+    })
+}
+
+async fn prefix_handler(
+    State(state): State<AppState>,
+    Json(req): Json<PrefixRequest>,
+) -> Result<Json<PrefixResponse>, Response> {
+    let result = spawn_blocking(move || register_system_prefix(&state, &req.system))
+        .await
+        .map_err(|e| anyhow::anyhow!("join error in spawn_blocking: {e}"))
+        .and_then(|result| result);
+    match result {
+        Ok(prefix) => Ok(Json(prefix)),
+        Err(e) => {
+            eprintln!("[TinyLlama] Failed to register prefix: {e}");
+            Err((axum::http::StatusCode::BAD_REQUEST, e.to_string()).into_response())
+        }
+    }
+}
+
 async fn history_handler(
     State(state): State<AppState>,
 ) -> Result<Json<Vec<SessionWithMessages>>, axum::http::StatusCode> {
@@ -242,30 +1051,66 @@ async fn history_handler(
     }
 }
 
-fn load_tinyllama_state(db_pool: DbPool) -> Result<AppState> {
-    let model_dir = PathBuf::from("models/tinyllama");
+// Fallback Zephyr-style template used by TinyLlama-Chat
+const ZEPHYR_TEMPLATE: &str = r#"{%- for message in messages -%}
+{{ '<|' + message['role'] + '|>\n' + message['content'] + eos_token + '\n' }}
+{%- endfor -%}
+{%- if add_generation_prompt -%}{{ '<|assistant|>\n' }}{%- endif -%}"#;
+
+fn write_quantized_gguf(
+    model_dir: &Path,
+    format: QuantFormat,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let output = output.unwrap_or_else(|| model_dir.join(format!("model-{format}.gguf")));
+
+    println!("[Quantize] Writing {format} weights to {output:?}...");
+    let mut file = std::fs::File::create(&output)?;
+    quantize::write_gguf(model_dir, format, &mut file)?;
+    println!("[Quantize] Done. A .gguf in the model directory is picked up on the next start.");
+
+    Ok(())
+}
+
+// The model directory's name, reported as the model id
+fn model_id(model_dir: &Path) -> String {
+    model_dir.file_name().map_or_else(
+        || model_dir.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+fn load_tinyllama_state(db_pool: DbPool, config: &ServerConfig) -> Result<AppState> {
+    let model_dir = &config.model_dir;
     let tokenizer_path = model_dir.join("tokenizer.json");
     let tokenizer = Tokenizer::from_file(&tokenizer_path)
         .map_err(|e| anyhow::anyhow!("tokenizer error: {e}"))?;
 
-    let config_bytes = std::fs::read(model_dir.join("config.json"))?;
-    let llama_config: LlamaConfig = serde_json::from_slice(&config_bytes)?;
+    let eos_tokens = eos::load_eos_tokens(model_dir, &tokenizer)?;
+    let chat_template = ChatTemplate::load(model_dir, ZEPHYR_TEMPLATE)?;
 
-    let config = llama_config.into_config(false);
-
-    let filenames = vec![model_dir.join("model.safetensors")];
     let device = Device::Cpu;
-    let dtype = DType::F32;
-
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-    let model = Llama::load(vb, &config)?;
+    // Uses a .gguf file from the model directory when there is one
+    let (model, max_context) =
+        model::load_model(model_dir, config.dtype, &device, config.quantize)?;
 
     Ok(AppState {
         model: Arc::new(model),
-        config,
-        dtype,
-        device,
+        max_context,
+        default_max_tokens: config.default_max_tokens,
+        max_tokens_cap: config.max_tokens_cap,
+        admission: Admission::new(config.max_concurrent, config.max_queue),
+        model_id: model_id(model_dir),
+        sessions: Arc::new(Mutex::new(SessionCache::new(
+            config.session_cache_mb * 1024 * 1024,
+        ))),
+        prefixes: Arc::new(Mutex::new(PrefixCache::new(
+            config.prefix_cache_mb * 1024 * 1024,
+        ))),
         tokenizer: Arc::new(tokenizer),
+        chat_template: Arc::new(chat_template),
+        eos_tokens: Arc::new(eos_tokens),
+        vocabulary: Arc::new(Mutex::new(None)),
         db_pool,
     })
 }
@@ -0,0 +1,671 @@
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{Embedding, RmsNorm, VarBuilder};
+use candle_transformers::models::llama::LlamaConfig;
+use candle_transformers::utils::repeat_kv;
+
+use crate::quantize::{self, QuantFormat};
+
+/// TinyLlama decoder whose KV cache lives outside the model, so that a cache
+/// can be kept between turns, shared as a registered prefix, or cut back.
+///
+/// Weights come either from full precision safetensors or a quantized GGUF
+/// file. They are shared read-only between requests; each generation brings
+/// its own [`KvCache`].
+pub struct LlamaModel {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: QMatMul,
+    rotary: RotaryEmbedding,
+    dtype: DType,
+    device: Device,
+}
+
+/// Keys and values of one sequence, per layer, shaped `[1, kv_heads, len, head_dim]`.
+#[derive(Clone)]
+pub struct KvCache {
+    layers: Vec<Option<(Tensor, Tensor)>>,
+    len: usize,
+}
+
+impl KvCache {
+    /// Number of positions held.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Drop every position from `len` on, keeping the prefix.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.len {
+            return Ok(());
+        }
+        for (k, v) in self.layers.iter_mut().flatten() {
+            *k = k.narrow(2, 0, len)?;
+            *v = v.narrow(2, 0, len)?;
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    /// Memory taken by the keys and values.
+    pub fn size_in_bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes())
+            .sum()
+    }
+}
+
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+    // GGUF files store q/k rows in llama.cpp's order, which pairs up adjacent
+    // dimensions instead of the two halves
+    interleaved: bool,
+}
+
+impl RotaryEmbedding {
+    fn new(
+        head_dim: usize,
+        max_len: usize,
+        theta: f64,
+        interleaved: bool,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let inv_freq: Vec<f32> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / theta.powf(i as f64 / head_dim as f64) as f32)
+            .collect();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, head_dim / 2), device)?;
+        let t = Tensor::arange(0u32, max_len as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((max_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+            interleaved,
+        })
+    }
+
+    /// `xs` is `[1, heads, seq, head_dim]`, starting at position `offset`.
+    fn apply(&self, xs: &Tensor, offset: usize) -> candle_core::Result<Tensor> {
+        let seq_len = xs.dim(2)?;
+        let cos = self.cos.narrow(0, offset, seq_len)?;
+        let sin = self.sin.narrow(0, offset, seq_len)?;
+        if self.interleaved {
+            candle_nn::rotary_emb::rope_i(&xs.contiguous()?, &cos, &sin)
+        } else {
+            candle_nn::rotary_emb::rope(&xs.contiguous()?, &cos, &sin)
+        }
+    }
+}
+
+struct DecoderLayer {
+    q_proj: QMatMul,
+    k_proj: QMatMul,
+    v_proj: QMatMul,
+    o_proj: QMatMul,
+    gate_proj: QMatMul,
+    up_proj: QMatMul,
+    down_proj: QMatMul,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+}
+
+impl DecoderLayer {
+    fn forward(
+        &self,
+        xs: &Tensor,
+        rotary: &RotaryEmbedding,
+        mask: Option<&Tensor>,
+        offset: usize,
+        cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.attention(&xs, rotary, mask, offset, cache)?;
+        let xs = (xs + residual)?;
+
+        let residual = &xs;
+        let h = self.post_attention_layernorm.forward(&xs)?;
+        let gate = candle_nn::ops::silu(&self.gate_proj.forward(&h)?)?;
+        let up = self.up_proj.forward(&h)?;
+        let h = self.down_proj.forward(&(gate * up)?)?;
+        Ok((residual + h)?)
+    }
+
+    fn attention(
+        &self,
+        xs: &Tensor,
+        rotary: &RotaryEmbedding,
+        mask: Option<&Tensor>,
+        offset: usize,
+        cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, hidden) = xs.dims3()?;
+
+        let q = self
+            .q_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = rotary.apply(&q, offset)?;
+        let k = rotary.apply(&k, offset)?;
+
+        let (k, v) = match cache.as_ref() {
+            None => (k, v),
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[prev_k, &k], 2)?,
+                Tensor::cat(&[prev_v, &v], 2)?,
+            ),
+        };
+        *cache = Some((k.clone(), v.clone()));
+
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = repeat_kv(k, n_rep)?.contiguous()?;
+        let v = repeat_kv(v, n_rep)?.contiguous()?;
+
+        let scale = 1f64 / (self.head_dim as f64).sqrt();
+        let weights = (q.matmul(&k.t()?)? * scale)?;
+        let weights = match mask {
+            Some(mask) => weights.broadcast_add(mask)?,
+            None => weights,
+        };
+        let weights = candle_nn::ops::softmax_last_dim(&weights)?;
+        let out = weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, hidden))?;
+        Ok(self.o_proj.forward(&out)?)
+    }
+}
+
+impl LlamaModel {
+    pub fn new_cache(&self) -> KvCache {
+        KvCache {
+            layers: vec![None; self.layers.len()],
+            len: 0,
+        }
+    }
+
+    /// Run `tokens` through the model after what `cache` already holds.
+    /// Returns the logits of the last position, shaped `[vocab]`, in f32.
+    pub fn forward(&self, tokens: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        let xs = self.hidden_states(tokens, cache)?;
+        let xs = self.norm.forward(&xs.narrow(1, tokens.len() - 1, 1)?)?;
+        let logits = self.lm_head.forward(&xs)?.flatten_all()?;
+        Ok(logits.to_dtype(DType::F32)?)
+    }
+
+    /// Like [`LlamaModel::forward`], but returns the logits of every position,
+    /// shaped `[len, vocab]`.
+    pub fn score(&self, tokens: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        let xs = self.hidden_states(tokens, cache)?;
+        let logits = self.lm_head.forward(&self.norm.forward(&xs)?)?;
+        Ok(logits.squeeze(0)?.to_dtype(DType::F32)?)
+    }
+
+    fn hidden_states(&self, tokens: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        if tokens.is_empty() {
+            anyhow::bail!("no tokens to run");
+        }
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let offset = cache.len;
+        let mask = self.attention_mask(offset, tokens.len())?;
+
+        let mut xs = self.embed_tokens.forward(&input)?;
+        for (layer, layer_cache) in self.layers.iter().zip(cache.layers.iter_mut()) {
+            xs = layer.forward(&xs, &self.rotary, mask.as_ref(), offset, layer_cache)?;
+        }
+        cache.len += tokens.len();
+        Ok(xs)
+    }
+
+    // Query i sees every cached position and the new ones up to itself
+    fn attention_mask(&self, offset: usize, seq_len: usize) -> Result<Option<Tensor>> {
+        if seq_len == 1 {
+            return Ok(None);
+        }
+        let kv_len = offset + seq_len;
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| {
+                (0..kv_len).map(move |j| {
+                    if j > offset + i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let mask =
+            Tensor::from_vec(mask, (1, 1, seq_len, kv_len), &self.device)?.to_dtype(self.dtype)?;
+        Ok(Some(mask))
+    }
+
+    fn from_safetensors(config: &LlamaConfig, vb: VarBuilder) -> Result<Self> {
+        let hidden = config.hidden_size;
+        let head_dim = hidden / config.num_attention_heads;
+        let kv_dim = config.num_key_value_heads() * head_dim;
+        let linear = |vb: VarBuilder, in_dim: usize, out_dim: usize| -> Result<QMatMul> {
+            Ok(QMatMul::Tensor(vb.get((out_dim, in_dim), "weight")?))
+        };
+        let rms_norm = |vb: VarBuilder| -> Result<RmsNorm> {
+            Ok(RmsNorm::new(vb.get(hidden, "weight")?, config.rms_norm_eps))
+        };
+
+        let vb_m = vb.pp("model");
+        let embeddings = vb_m.get((config.vocab_size, hidden), "embed_tokens.weight")?;
+        let mut layers = Vec::with_capacity(config.num_hidden_layers);
+        for i in 0..config.num_hidden_layers {
+            let vb_l = vb_m.pp("layers").pp(i);
+            let attn = vb_l.pp("self_attn");
+            let mlp = vb_l.pp("mlp");
+            layers.push(DecoderLayer {
+                q_proj: linear(attn.pp("q_proj"), hidden, hidden)?,
+                k_proj: linear(attn.pp("k_proj"), hidden, kv_dim)?,
+                v_proj: linear(attn.pp("v_proj"), hidden, kv_dim)?,
+                o_proj: linear(attn.pp("o_proj"), hidden, hidden)?,
+                gate_proj: linear(mlp.pp("gate_proj"), hidden, config.intermediate_size)?,
+                up_proj: linear(mlp.pp("up_proj"), hidden, config.intermediate_size)?,
+                down_proj: linear(mlp.pp("down_proj"), config.intermediate_size, hidden)?,
+                input_layernorm: rms_norm(vb_l.pp("input_layernorm"))?,
+                post_attention_layernorm: rms_norm(vb_l.pp("post_attention_layernorm"))?,
+                num_heads: config.num_attention_heads,
+                num_kv_heads: config.num_key_value_heads(),
+                head_dim,
+            });
+        }
+
+        let lm_head = if config.tie_word_embeddings.unwrap_or(false) {
+            embeddings.clone()
+        } else {
+            vb.get((config.vocab_size, hidden), "lm_head.weight")?
+        };
+
+        Ok(Self {
+            embed_tokens: Embedding::new(embeddings, hidden),
+            layers,
+            norm: rms_norm(vb_m.pp("norm"))?,
+            lm_head: QMatMul::Tensor(lm_head),
+            rotary: RotaryEmbedding::new(
+                head_dim,
+                config.max_position_embeddings,
+                config.rope_theta as f64,
+                false,
+                vb.dtype(),
+                vb.device(),
+            )?,
+            dtype: vb.dtype(),
+            device: vb.device().clone(),
+        })
+    }
+
+    fn from_gguf<R: Read + Seek>(
+        content: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md_get = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("missing {key} in GGUF metadata"))
+        };
+        let num_heads = md_get("llama.attention.head_count")?.to_u32()? as usize;
+        let num_kv_heads = md_get("llama.attention.head_count_kv")?.to_u32()? as usize;
+        let hidden = md_get("llama.embedding_length")?.to_u32()? as usize;
+        let context_length = md_get("llama.context_length")?.to_u32()? as usize;
+        let block_count = md_get("llama.block_count")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("llama.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_theta = md_get("llama.rope.freq_base")
+            .and_then(|v| Ok(v.to_f32()?))
+            .unwrap_or(10_000.) as f64;
+        let head_dim = hidden / num_heads;
+
+        let mut weights = GgufWeights {
+            content: &content,
+            reader,
+            device,
+        };
+
+        let mut layers = Vec::with_capacity(block_count);
+        for i in 0..block_count {
+            let prefix = format!("blk.{i}");
+            layers.push(DecoderLayer {
+                q_proj: weights.linear(&format!("{prefix}.attn_q"))?,
+                k_proj: weights.linear(&format!("{prefix}.attn_k"))?,
+                v_proj: weights.linear(&format!("{prefix}.attn_v"))?,
+                o_proj: weights.linear(&format!("{prefix}.attn_output"))?,
+                gate_proj: weights.linear(&format!("{prefix}.ffn_gate"))?,
+                up_proj: weights.linear(&format!("{prefix}.ffn_up"))?,
+                down_proj: weights.linear(&format!("{prefix}.ffn_down"))?,
+                input_layernorm: RmsNorm::new(
+                    weights.dense(&format!("{prefix}.attn_norm.weight"))?,
+                    rms_norm_eps,
+                ),
+                post_attention_layernorm: RmsNorm::new(
+                    weights.dense(&format!("{prefix}.ffn_norm.weight"))?,
+                    rms_norm_eps,
+                ),
+                num_heads,
+                num_kv_heads,
+                head_dim,
+            });
+        }
+
+        let embeddings = weights.dense("token_embd.weight")?;
+        let lm_head = match weights.qtensor("output.weight") {
+            Ok(output) => QMatMul::from_qtensor(output)?,
+            // Tied embeddings
+            Err(_) => QMatMul::from_qtensor(weights.qtensor("token_embd.weight")?)?,
+        };
+        let norm = weights.dense("output_norm.weight")?;
+
+        Ok(Self {
+            embed_tokens: Embedding::new(embeddings, hidden),
+            layers,
+            norm: RmsNorm::new(norm, rms_norm_eps),
+            lm_head,
+            rotary: RotaryEmbedding::new(
+                head_dim,
+                context_length,
+                rope_theta,
+                true,
+                DType::F32,
+                device,
+            )?,
+            dtype: DType::F32,
+            device: device.clone(),
+        })
+    }
+}
+
+// Reads tensors out of a GGUF file by name
+struct GgufWeights<'a, R> {
+    content: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: &'a Device,
+}
+
+impl<R: Read + Seek> GgufWeights<'_, R> {
+    fn qtensor(&mut self, name: &str) -> Result<QTensor> {
+        Ok(self.content.tensor(self.reader, name, self.device)?)
+    }
+
+    fn dense(&mut self, name: &str) -> Result<Tensor> {
+        Ok(self.qtensor(name)?.dequantize(self.device)?)
+    }
+
+    fn linear(&mut self, name: &str) -> Result<QMatMul> {
+        Ok(QMatMul::from_qtensor(
+            self.qtensor(&format!("{name}.weight"))?,
+        )?)
+    }
+}
+
+/// First `.gguf` file in the model directory, if any.
+pub fn find_gguf(model_dir: &Path) -> Result<Option<PathBuf>> {
+    let mut ggufs = Vec::new();
+    for entry in std::fs::read_dir(model_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "gguf") {
+            ggufs.push(path);
+        }
+    }
+    ggufs.sort();
+    Ok(ggufs.into_iter().next())
+}
+
+fn load_gguf<R: Read + Seek>(reader: &mut R, device: &Device) -> Result<(LlamaModel, usize)> {
+    let content = gguf_file::Content::read(reader)?;
+    let max_context = content
+        .metadata
+        .get("llama.context_length")
+        .and_then(|v| v.to_u32().ok())
+        .map(|v| v as usize)
+        .unwrap_or(2048);
+    let model = LlamaModel::from_gguf(content, reader, device)?;
+    Ok((model, max_context))
+}
+
+/// Load the model and return it with its context length.
+///
+/// A `.gguf` file in `model_dir` takes precedence over `model.safetensors`;
+/// with `quantize` set, the safetensors weights are quantized while loading.
+pub fn load_model(
+    model_dir: &Path,
+    dtype: DType,
+    device: &Device,
+    quantize: Option<QuantFormat>,
+) -> Result<(LlamaModel, usize)> {
+    if let Some(gguf_path) = find_gguf(model_dir)? {
+        println!("[Model] Loading quantized weights from {gguf_path:?}");
+        let mut file = std::fs::File::open(&gguf_path)?;
+        return load_gguf(&mut file, device)
+            .map_err(|e| anyhow::anyhow!("failed to load {gguf_path:?}: {e}"));
+    }
+
+    if let Some(format) = quantize {
+        println!("[Model] Quantizing safetensors weights to {format}...");
+        let mut gguf = quantize::quantize_in_memory(model_dir, format)?;
+        return load_gguf(&mut gguf, device);
+    }
+
+    let config_bytes = std::fs::read(model_dir.join("config.json"))?;
+    let llama_config: LlamaConfig = serde_json::from_slice(&config_bytes)?;
+
+    let filenames = vec![model_dir.join("model.safetensors")];
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, device)? };
+    let model = LlamaModel::from_safetensors(&llama_config, vb)?;
+
+    Ok((model, llama_config.max_position_embeddings))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::IndexOp;
+    use candle_transformers::models::{llama, quantized_llama};
+
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "hidden_size": 64,
+        "intermediate_size": 96,
+        "vocab_size": 80,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "max_position_embeddings": 64,
+        "tie_word_embeddings": false
+    }"#;
+
+    // Rows of 40 aren't a whole number of quantization blocks, so quantizing
+    // keeps every tensor in f32
+    const UNQUANTIZED_CONFIG: &str = r#"{
+        "hidden_size": 40,
+        "intermediate_size": 56,
+        "vocab_size": 80,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "rope_theta": 10000.0,
+        "max_position_embeddings": 64,
+        "tie_word_embeddings": false
+    }"#;
+
+    // A model directory with random weights, as `load_model` expects it
+    struct ModelDir {
+        path: PathBuf,
+        config: LlamaConfig,
+    }
+
+    impl ModelDir {
+        fn new(config_json: &str) -> Result<Self> {
+            let path = std::env::temp_dir().join(format!("llama-model-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&path)?;
+            std::fs::write(path.join("config.json"), config_json)?;
+            let config: LlamaConfig = serde_json::from_str(config_json)?;
+            let device = Device::Cpu;
+            let hidden = config.hidden_size;
+            let kv_dim = config.num_key_value_heads() * hidden / config.num_attention_heads;
+            let inter = config.intermediate_size;
+
+            let mut shapes: Vec<(String, Vec<usize>)> = vec![
+                (
+                    "model.embed_tokens.weight".into(),
+                    vec![config.vocab_size, hidden],
+                ),
+                ("model.norm.weight".into(), vec![hidden]),
+                ("lm_head.weight".into(), vec![config.vocab_size, hidden]),
+            ];
+            for i in 0..config.num_hidden_layers {
+                let p = format!("model.layers.{i}");
+                shapes.extend([
+                    (format!("{p}.self_attn.q_proj.weight"), vec![hidden, hidden]),
+                    (format!("{p}.self_attn.k_proj.weight"), vec![kv_dim, hidden]),
+                    (format!("{p}.self_attn.v_proj.weight"), vec![kv_dim, hidden]),
+                    (format!("{p}.self_attn.o_proj.weight"), vec![hidden, hidden]),
+                    (format!("{p}.mlp.gate_proj.weight"), vec![inter, hidden]),
+                    (format!("{p}.mlp.up_proj.weight"), vec![inter, hidden]),
+                    (format!("{p}.mlp.down_proj.weight"), vec![hidden, inter]),
+                    (format!("{p}.input_layernorm.weight"), vec![hidden]),
+                    (format!("{p}.post_attention_layernorm.weight"), vec![hidden]),
+                ]);
+            }
+            let mut tensors = HashMap::new();
+            for (name, shape) in shapes {
+                let tensor = if name.ends_with("norm.weight") {
+                    (Tensor::randn(0f32, 0.1, shape, &device)? + 1.)?
+                } else {
+                    Tensor::randn(0f32, 0.2, shape, &device)?
+                };
+                tensors.insert(name, tensor);
+            }
+            candle_core::safetensors::save(&tensors, path.join("model.safetensors"))?;
+            Ok(Self { path, config })
+        }
+    }
+
+    impl Drop for ModelDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    // Logits after the prompt, then after each of `tokens` fed one at a time
+    fn expected_logits(
+        mut forward: impl FnMut(&Tensor, usize) -> candle_core::Result<Tensor>,
+        prompt: &[u32],
+        tokens: &[u32],
+    ) -> Result<Vec<Tensor>> {
+        let device = Device::Cpu;
+        let input = Tensor::new(prompt, &device)?.unsqueeze(0)?;
+        let mut logits = vec![forward(&input, 0)?.flatten_all()?];
+        for (i, &token) in tokens.iter().enumerate() {
+            let input = Tensor::new(&[token], &device)?.unsqueeze(0)?;
+            logits.push(forward(&input, prompt.len() + i)?.flatten_all()?);
+        }
+        Ok(logits)
+    }
+
+    // Ours, fed the prompt, one token, then the rest scored at once
+    fn our_logits(model: &LlamaModel, prompt: &[u32], tokens: &[u32]) -> Result<Vec<Tensor>> {
+        let mut cache = model.new_cache();
+        let mut logits = vec![model.forward(prompt, &mut cache)?];
+        logits.push(model.forward(&tokens[..1], &mut cache)?);
+        let scored = model.score(&tokens[1..], &mut cache)?;
+        for i in 0..tokens.len() - 1 {
+            logits.push(scored.i(i)?);
+        }
+        assert_eq!(cache.len(), prompt.len() + tokens.len());
+        Ok(logits)
+    }
+
+    fn assert_close(actual: &[Tensor], expected: &[Tensor]) -> Result<()> {
+        for (position, (a, e)) in actual.iter().zip(expected).enumerate() {
+            let diff = (a - e)?.abs()?.max_all()?.to_scalar::<f32>()?;
+            assert!(diff < 1e-4, "logits differ by {diff} at step {position}");
+        }
+        Ok(())
+    }
+
+    const PROMPT: [u32; 6] = [1, 17, 42, 5, 63, 8];
+    const TOKENS: [u32; 4] = [12, 77, 3, 30];
+
+    #[test]
+    fn safetensors_weights_match_candle_llama() -> Result<()> {
+        let dir = ModelDir::new(CONFIG)?;
+        let device = Device::Cpu;
+        let (model, max_context) = load_model(&dir.path, DType::F32, &device, None)?;
+        assert_eq!(max_context, 64);
+
+        let files = [dir.path.join("model.safetensors")];
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files, DType::F32, &device)? };
+        let config = dir.config.clone().into_config(false);
+        let reference = llama::Llama::load(vb, &config)?;
+        let mut cache = llama::Cache::new(true, DType::F32, &config, &device)?;
+        let expected = expected_logits(
+            |input, pos| reference.forward(input, pos, &mut cache),
+            &PROMPT,
+            &TOKENS,
+        )?;
+
+        assert_close(&our_logits(&model, &PROMPT, &TOKENS)?, &expected)
+    }
+
+    #[test]
+    fn gguf_weights_match_candle_quantized_llama() -> Result<()> {
+        let dir = ModelDir::new(CONFIG)?;
+        let device = Device::Cpu;
+        let (model, _) = load_model(&dir.path, DType::F32, &device, Some(QuantFormat::Q8_0))?;
+
+        let mut gguf = quantize::quantize_in_memory(&dir.path, QuantFormat::Q8_0)?;
+        let content = gguf_file::Content::read(&mut gguf)?;
+        let mut reference = quantized_llama::ModelWeights::from_gguf(content, &mut gguf, &device)?;
+        let expected =
+            expected_logits(|input, pos| reference.forward(input, pos), &PROMPT, &TOKENS)?;
+
+        assert_close(&our_logits(&model, &PROMPT, &TOKENS)?, &expected)
+    }
+
+    // Rows reordered for GGUF with interleaved rotary embeddings must give
+    // what the original rows give with half-split ones
+    #[test]
+    fn gguf_rope_order_matches_the_safetensors_weights() -> Result<()> {
+        let dir = ModelDir::new(UNQUANTIZED_CONFIG)?;
+        let device = Device::Cpu;
+        let (dense, _) = load_model(&dir.path, DType::F32, &device, None)?;
+        let (gguf, _) = load_model(&dir.path, DType::F32, &device, Some(QuantFormat::Q8_0))?;
+
+        let expected = our_logits(&dense, &PROMPT, &TOKENS)?;
+        assert_close(&our_logits(&gguf, &PROMPT, &TOKENS)?, &expected)
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{Cursor, Seek, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::llama::LlamaConfig;
+
+/// Target format when quantizing safetensors weights.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantFormat {
+    Q8_0,
+    Q4K,
+}
+
+impl FromStr for QuantFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "q8_0" => Ok(QuantFormat::Q8_0),
+            "q4_k" | "q4k" => Ok(QuantFormat::Q4K),
+            other => Err(format!(
+                "unknown quantization {other:?}, expected q8_0 or q4_k"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for QuantFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuantFormat::Q8_0 => write!(f, "q8_0"),
+            QuantFormat::Q4K => write!(f, "q4_k"),
+        }
+    }
+}
+
+impl QuantFormat {
+    fn ggml_dtype(self) -> GgmlDType {
+        match self {
+            QuantFormat::Q8_0 => GgmlDType::Q8_0,
+            QuantFormat::Q4K => GgmlDType::Q4K,
+        }
+    }
+}
+
+// Hugging Face tensor name -> GGUF tensor name, as llama.cpp names them
+fn gguf_name(name: &str) -> Option<String> {
+    match name {
+        "model.embed_tokens.weight" => return Some("token_embd.weight".to_string()),
+        "model.norm.weight" => return Some("output_norm.weight".to_string()),
+        "lm_head.weight" => return Some("output.weight".to_string()),
+        _ => {}
+    }
+
+    let rest = name.strip_prefix("model.layers.")?;
+    let (layer, rest) = rest.split_once('.')?;
+    let (module, kind) = rest.rsplit_once('.')?;
+    let module = match module {
+        "self_attn.q_proj" => "attn_q",
+        "self_attn.k_proj" => "attn_k",
+        "self_attn.v_proj" => "attn_v",
+        "self_attn.o_proj" => "attn_output",
+        "mlp.gate_proj" => "ffn_gate",
+        "mlp.up_proj" => "ffn_up",
+        "mlp.down_proj" => "ffn_down",
+        "input_layernorm" => "attn_norm",
+        "post_attention_layernorm" => "ffn_norm",
+        _ => return None,
+    };
+    Some(format!("blk.{layer}.{module}.{kind}"))
+}
+
+fn quantize_tensor(tensor: &Tensor, format: QuantFormat) -> Result<QTensor> {
+    let dtype = format.ggml_dtype();
+    let dims = tensor.dims();
+    // Norms and biases stay in f32; so do matrices whose rows don't fit the block size
+    let target = if dims.len() < 2 {
+        GgmlDType::F32
+    } else if dims[dims.len() - 1].is_multiple_of(dtype.block_size()) {
+        dtype
+    } else if dims[dims.len() - 1].is_multiple_of(GgmlDType::Q8_0.block_size()) {
+        GgmlDType::Q8_0
+    } else {
+        GgmlDType::F32
+    };
+    Ok(QTensor::quantize(tensor, target)?)
+}
+
+// Hugging Face stores q/k rows for half-split rotary embeddings; the GGUF
+// loader applies them interleaved, so reorder the rows the way llama.cpp does
+fn permute_for_rope(tensor: &Tensor, n_head: usize) -> Result<Tensor> {
+    let (rows, cols) = tensor.dims2()?;
+    Ok(tensor
+        .reshape((n_head, 2, rows / n_head / 2, cols))?
+        .transpose(1, 2)?
+        .reshape((rows, cols))?)
+}
+
+/// Quantize `model.safetensors` in `model_dir` and write it out as a GGUF file.
+pub fn write_gguf<W: Write + Seek>(
+    model_dir: &Path,
+    format: QuantFormat,
+    out: &mut W,
+) -> Result<()> {
+    let config_bytes = std::fs::read(model_dir.join("config.json"))?;
+    let config: LlamaConfig = serde_json::from_slice(&config_bytes)?;
+    let head_count_kv = config.num_key_value_heads();
+
+    let safetensors = unsafe { MmapedSafetensors::new(model_dir.join("model.safetensors"))? };
+
+    let mut tensors = Vec::new();
+    for (name, _) in safetensors.tensors() {
+        let Some(gguf_name) = gguf_name(&name) else {
+            println!("[Quantize] Skipping {name}");
+            continue;
+        };
+        let mut tensor = safetensors
+            .load(&name, &Device::Cpu)?
+            .to_dtype(DType::F32)?;
+        if gguf_name.ends_with("attn_q.weight") {
+            tensor = permute_for_rope(&tensor, config.num_attention_heads)?;
+        } else if gguf_name.ends_with("attn_k.weight") {
+            tensor = permute_for_rope(&tensor, head_count_kv)?;
+        }
+        let qtensor = quantize_tensor(&tensor, format)?;
+        println!("[Quantize] {name} -> {gguf_name} ({:?})", qtensor.dtype());
+        tensors.push((gguf_name, qtensor));
+    }
+
+    let metadata = [
+        (
+            "general.architecture",
+            gguf_file::Value::String("llama".to_string()),
+        ),
+        (
+            "llama.attention.head_count",
+            gguf_file::Value::U32(config.num_attention_heads as u32),
+        ),
+        (
+            "llama.attention.head_count_kv",
+            gguf_file::Value::U32(head_count_kv as u32),
+        ),
+        (
+            "llama.embedding_length",
+            gguf_file::Value::U32(config.hidden_size as u32),
+        ),
+        (
+            "llama.rope.dimension_count",
+            gguf_file::Value::U32((config.hidden_size / config.num_attention_heads) as u32),
+        ),
+        (
+            "llama.context_length",
+            gguf_file::Value::U32(config.max_position_embeddings as u32),
+        ),
+        (
+            "llama.block_count",
+            gguf_file::Value::U32(config.num_hidden_layers as u32),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(config.rms_norm_eps as f32),
+        ),
+        (
+            "llama.rope.freq_base",
+            gguf_file::Value::F32(config.rope_theta),
+        ),
+    ];
+
+    let metadata: Vec<_> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+    let tensors: Vec<_> = tensors.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    gguf_file::write(out, &metadata, &tensors)?;
+    Ok(())
+}
+
+/// Quantize the safetensors weights into an in-memory GGUF image.
+pub fn quantize_in_memory(model_dir: &Path, format: QuantFormat) -> Result<Cursor<Vec<u8>>> {
+    let mut buffer = Cursor::new(Vec::new());
+    write_gguf(model_dir, format, &mut buffer)?;
+    buffer.set_position(0);
+    Ok(buffer)
+}
@@ -1,66 +1,212 @@
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::Result;
 use axum::extract::Query;
 use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::{extract::State, routing::post, Json, Router};
-use candle_core::{DType, Device, IndexOp, Tensor};
-use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::models::qwen2::{Config as QwenConfig, ModelForCausalLM};
-use serde::{Deserialize, Serialize};
+use candle_core::{DType, Device};
+use clap::{Parser, Subcommand};
 use std::convert::Infallible;
 use tokenizers::Tokenizer;
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::spawn_blocking;
-use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
 use tower_http::cors::{Any, CorsLayer};
 
-mod db;
+mod config;
+mod model;
+mod quantize;
+mod scheduler;
+mod speculative;
+
+use server_common::admission::{Admission, Ticket};
+use server_common::chat_template::{ChatMessage, ChatTemplate};
+use server_common::constraint::ResponseFormat;
+use server_common::context::PromptContext;
+use server_common::db::{
+    init_db, load_all_history, load_session_messages, save_chat_turn, DbPool, SessionWithMessages,
+};
+use server_common::events::StreamEvent;
+use server_common::openai::{
+    ChatCompletionRequest, ChunkKind, Chunks, Completion, CompletionRequest,
+};
+use server_common::request::{
+    ChatResponse, ChatStreamQuery, Generation, GenerationOptions, PrefixRequest, PrefixResponse,
+};
+use server_common::{admission, candidates, context, eos, fim, openai};
+
+use crate::config::{PartialConfig, ServerConfig};
+use crate::quantize::QuantFormat;
+use crate::scheduler::{Scheduler, SequenceRequest};
+use crate::speculative::Draft;
+
+/// Command-line flags; each one can also be set through its environment
+/// variable and overrides the config file.
+#[derive(Parser)]
+#[command(about = "Candle Qwen2 chat server")]
+struct Cli {
+    /// TOML config file
+    #[arg(long, env = "QWEN_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long, env = "QWEN_HOST")]
+    host: Option<String>,
+    /// Port to listen on [default: 8001]
+    #[arg(long, env = "QWEN_PORT")]
+    port: Option<u16>,
+    /// Model directory [default: models/qwen2_0_5b_instruct]
+    #[arg(long, env = "QWEN_MODEL_DIR")]
+    model_dir: Option<PathBuf>,
+    /// Weight dtype for safetensors models: f32, bf16 or f16 [default: f32]
+    #[arg(long, env = "QWEN_DTYPE")]
+    dtype: Option<String>,
+    /// CPU threads used by the model [default: all cores]
+    #[arg(long, env = "QWEN_THREADS")]
+    threads: Option<usize>,
+    /// SQLite database file [default: chat.db]
+    #[arg(long, env = "QWEN_DB_PATH")]
+    db_path: Option<PathBuf>,
+    /// Quantize the safetensors weights while loading (q8_0 or q4_k)
+    #[arg(long, env = "QWEN_QUANTIZE")]
+    quantize: Option<String>,
+    /// max_tokens for requests that don't set it [default: 64]
+    #[arg(long, env = "QWEN_DEFAULT_MAX_TOKENS")]
+    default_max_tokens: Option<usize>,
+    /// Upper bound on max_tokens [default: 256]
+    #[arg(long, env = "QWEN_MAX_TOKENS_CAP")]
+    max_tokens_cap: Option<usize>,
+    /// Generations running at once [default: 8]
+    #[arg(long, env = "QWEN_MAX_CONCURRENT")]
+    max_concurrent: Option<usize>,
+    /// Requests waiting for a slot before new ones get a 503 [default: 16]
+    #[arg(long, env = "QWEN_MAX_QUEUE")]
+    max_queue: Option<usize>,
+    /// Memory for KV caches kept between turns of a session, in MB; 0 disables [default: 512]
+    #[arg(long, env = "QWEN_SESSION_CACHE_MB")]
+    session_cache_mb: Option<usize>,
+    /// Memory for the KV states of registered system prefixes, in MB [default: 128]
+    #[arg(long, env = "QWEN_PREFIX_CACHE_MB")]
+    prefix_cache_mb: Option<usize>,
+    /// Most sequences decoded together in one batch [default: 8]
+    #[arg(long, env = "QWEN_MAX_BATCH_SIZE")]
+    max_batch_size: Option<usize>,
+    /// Draft model directory; enables speculative decoding
+    #[arg(long, env = "QWEN_DRAFT_MODEL_DIR")]
+    draft_model_dir: Option<PathBuf>,
+    /// Tokens the draft model proposes per step [default: 4]
+    #[arg(long, env = "QWEN_DRAFT_TOKENS")]
+    draft_tokens: Option<usize>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-use crate::db::{load_all_history, save_chat_turn, SessionWithMessages};
-use db::{init_db, DbPool};
+impl Cli {
+    /// Defaults, then the config file, then environment variables and flags.
+    fn server_config(&self) -> Result<ServerConfig> {
+        let file = match &self.config {
+            Some(path) => PartialConfig::from_file(path)?,
+            None => PartialConfig::default(),
+        };
+        let overrides = PartialConfig {
+            host: self.host.clone(),
+            port: self.port,
+            model_dir: self.model_dir.clone(),
+            dtype: self.dtype.clone(),
+            threads: self.threads,
+            db_path: self.db_path.clone(),
+            quantize: self.quantize.clone(),
+            default_max_tokens: self.default_max_tokens,
+            max_tokens_cap: self.max_tokens_cap,
+            max_concurrent: self.max_concurrent,
+            max_queue: self.max_queue,
+            session_cache_mb: self.session_cache_mb,
+            prefix_cache_mb: self.prefix_cache_mb,
+            // Only set in the config file
+            system_prefixes: None,
+            max_batch_size: self.max_batch_size,
+            draft_model_dir: self.draft_model_dir.clone(),
+            draft_tokens: self.draft_tokens,
+        };
+        let defaults = ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 8001,
+            model_dir: PathBuf::from("models/qwen2_0_5b_instruct"),
+            dtype: DType::F32,
+            threads: None,
+            db_path: PathBuf::from("chat.db"),
+            quantize: None,
+            default_max_tokens: 64,
+            max_tokens_cap: 256,
+            max_concurrent: 8,
+            max_queue: 16,
+            session_cache_mb: 512,
+            prefix_cache_mb: 128,
+            system_prefixes: Vec::new(),
+            max_batch_size: 8,
+            draft_model_dir: None,
+            draft_tokens: 4,
+        };
+        ServerConfig::resolve(file.merge(overrides), defaults)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Quantize the safetensors weights and write them to a GGUF file
+    Quantize {
+        /// q8_0 or q4_k
+        #[arg(long, default_value = "q8_0")]
+        format: QuantFormat,
+        /// Output file (default: <model dir>/model-<format>.gguf)
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
 
 #[derive(Clone)]
 struct AppState {
-    model: Arc<Mutex<ModelForCausalLM>>,
-    config: QwenConfig,
-    dtype: DType,
-    device: Device,
+    scheduler: Scheduler,
+    /// Context window of the loaded model, in tokens.
+    max_context: usize,
+    default_max_tokens: usize,
+    max_tokens_cap: usize,
+    admission: Admission,
+    /// Name of the model directory, reported as the model id by `/v1/models`.
+    model_id: String,
     tokenizer: Arc<Tokenizer>,
+    chat_template: Arc<ChatTemplate>,
     db_pool: DbPool,
 }
 
-#[derive(Deserialize)]
-struct ChatRequest {
-    prompt: String,
-    #[serde(default = "default_max_tokens")]
-    max_tokens: usize,
-}
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let config = cli.server_config()?;
 
-#[derive(Deserialize, Clone)]
-struct ChatStreamQuery {
-    pub session_id: String,
-    pub prompt: String,
-    #[serde(default = "default_max_tokens")]
-    pub max_tokens: usize,
-}
+    if let Some(threads) = config.threads {
+        // candle sizes its CPU thread pool from this; set it before any model code runs
+        std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+    }
 
-#[derive(Serialize)]
-struct ChatResponse {
-    response: String,
-}
+    if let Some(Command::Quantize { format, output }) = cli.command {
+        return write_quantized_gguf(&config.model_dir, format, output);
+    }
 
-fn default_max_tokens() -> usize {
-    64
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(config))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let db_pool = init_db().await?;
-    let state = load_qwen_state(db_pool)?;
+async fn serve(config: ServerConfig) -> Result<()> {
+    let db_pool = init_db(&config.db_path).await?;
+    let state = load_qwen_state(db_pool, &config)?;
+    for system in &config.system_prefixes {
+        register_system_prefix(&state, system).await?;
+    }
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
@@ -70,13 +216,17 @@ async fn main() -> Result<()> {
         .route("/chat", post(chat_handler))
         .route("/chat/stream", axum::routing::get(chat_stream_handler))
         .route("/history", axum::routing::get(history_handler))
+        .route("/prefixes", post(prefix_handler))
+        .route("/v1/chat/completions", post(chat_completions_handler))
+        .route("/v1/completions", post(completions_handler))
+        .route("/v1/models", axum::routing::get(models_handler))
         .layer(cors)
         .with_state(state);
 
-    let addr: std::net::SocketAddr = "0.0.0.0:8001".parse().unwrap();
+    let addr = config.bind_addr();
     println!("🚀 Candle Qwen2 0.5B Instruct server running on http://{addr}");
 
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = TcpListener::bind(&addr).await?;
     axum::serve(listener, app).await.unwrap();
 
     Ok(())
@@ -85,39 +235,67 @@ async fn main() -> Result<()> {
 async fn chat_stream_handler(
     State(state): State<AppState>,
     Query(params): Query<ChatStreamQuery>,
-) -> Sse<ReceiverStream<Result<Event, Infallible>>> {
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
     println!(
         "[Qwen2] Received frontend request. Prompt: {}",
         params.prompt
     );
 
-    let (tx, rx) = mpsc::channel::<Result<Event, Infallible>>(16);
+    let options = GenerationOptions::from_query(&params, &state.tokenizer)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[Qwen2] Queue is full, rejecting request");
+        return Err(admission::queue_full_response());
+    };
+
+    let history = load_history(&state, &params.session_id).await;
+    let events = spawn_generation(state, params, history, options, ticket);
+
+    let stream = UnboundedReceiverStream::new(events).map(|event| Ok(event.into_sse()));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// Waits for a free slot, runs the generation, then saves to DB, in a task of
+// its own; the returned receiver gets the generation's events
+fn spawn_generation(
+    state: AppState,
+    params: ChatStreamQuery,
+    history: Vec<ChatMessage>,
+    options: GenerationOptions,
+    ticket: Ticket,
+) -> mpsc::UnboundedReceiver<StreamEvent> {
+    // Unbounded, so that a slow client never holds up the rest of the batch
+    let (tx, rx) = mpsc::unbounded_channel::<StreamEvent>();
 
     let state_for_gen = state.clone();
     let params_for_gen = params.clone();
-    let state_for_db = state.clone();
-    let params_for_db = params.clone();
-
-    let handle =
-        spawn_blocking(move || run_streaming_generation_qwen(state_for_gen, params_for_gen, tx));
+    let response_format = options.response_format.clone();
 
-    // async task that waits for generation to finish, then saves to DB
     tokio::spawn(async move {
-        match handle.await {
-            // handle.await : Result<anyhow::Result<String>, JoinError>
-            Ok(Ok(final_answer)) => {
-                if let Err(e) = save_chat_turn(
-                    &state_for_db.db_pool,
-                    &params_for_db.session_id,
-                    &params_for_db.prompt,
-                    &final_answer,
-                )
-                .await
-                {
-                    eprintln!("[Qwen2] Failed to save chat turn: {e}");
-                } else {
-                    println!("[Qwen2] Chat turn saved to DB.");
-                }
+        // Tell the client where it stands while it waits
+        let queue_tx = tx.clone();
+        let permit = tokio::select! {
+            permit = ticket.wait(|position| {
+                let data = serde_json::json!({ "position": position });
+                let _ = queue_tx.send(StreamEvent::json("queued", None, data));
+            }) => permit,
+            _ = tx.closed() => {
+                println!("[Qwen2] Client left the queue");
+                return;
+            }
+        };
+
+        let handle = spawn_blocking(move || {
+            run_streaming_generation_qwen(state_for_gen, params_for_gen, history, options, tx)
+        });
+        let result = handle.await;
+        drop(permit);
+
+        match result {
+            // handle.await : Result<anyhow::Result<Vec<Generation>>, JoinError>
+            Ok(Ok(generations)) => {
+                save_answer(&state, &params, response_format.as_ref(), &generations).await;
             }
             Ok(Err(e)) => {
                 eprintln!("[Qwen2] Generation error: {e}");
@@ -128,151 +306,450 @@ async fn chat_stream_handler(
         }
     });
 
-    let stream = ReceiverStream::new(rx);
-    Sse::new(stream).keep_alive(KeepAlive::default())
+    rx
 }
 
-fn run_streaming_generation_qwen(
-    state: AppState,
-    params: ChatStreamQuery,
-    tx: mpsc::Sender<Result<Event, Infallible>>,
-) -> anyhow::Result<String> {
-    let model_arc = Arc::clone(&state.model);
-    let tokenizer = Arc::clone(&state.tokenizer);
-    let device = state.device.clone();
-
-    println!("--> [Qwen2] Acquiring model lock...");
-    let mut model = model_arc
-        .lock()
-        .map_err(|_| anyhow::anyhow!("failed to lock Qwen2 model"))?;
-
-    // New request → clear cached KV
-    model.clear_kv_cache();
-
-    println!("--> [Qwen2] Encoding prompt...");
-    let encoding = tokenizer
-        .encode(params.prompt.clone(), true)
-        .map_err(candle_core::Error::msg)?;
-    let mut tokens: Vec<u32> = encoding.get_ids().to_vec();
-
-    // EOS token (adjust if Qwen2 uses a different one in your tokenizer)
-    let eos_token = tokenizer.get_vocab(true).get("</s>").copied().unwrap_or(2);
+// Waits for a free slot and runs the generation without streaming, then saves to DB
+async fn generate_answer(
+    state: &AppState,
+    params: &ChatStreamQuery,
+    history: Vec<ChatMessage>,
+    options: GenerationOptions,
+    ticket: Ticket,
+) -> anyhow::Result<Vec<Generation>> {
+    let response_format = options.response_format.clone();
+    let permit = ticket.wait(|_| {}).await;
+
+    // Nobody reads the events; kept open so that the scheduler doesn't take
+    // the request for a disconnected client
+    let (tx, _events) = mpsc::unbounded_channel::<StreamEvent>();
+    let state_for_gen = state.clone();
+    let params_for_gen = params.clone();
+    let result = spawn_blocking(move || {
+        run_streaming_generation_qwen(state_for_gen, params_for_gen, history, options, tx)
+    })
+    .await;
+    drop(permit);
+
+    let generations = match result {
+        Ok(Ok(generations)) => generations,
+        Ok(Err(e)) => {
+            eprintln!("[Qwen2] Generation error: {e}");
+            return Err(e);
+        }
+        Err(join_err) => {
+            eprintln!("[Qwen2] Join error in spawn_blocking: {join_err}");
+            return Err(join_err.into());
+        }
+    };
+    save_answer(state, params, response_format.as_ref(), &generations).await;
+    Ok(generations)
+}
 
-    let mut seqlen_offset: usize = 0;
-    let mut prev_text_len: usize = 0;
+// Earlier turns of this session, so the model sees the whole conversation
+async fn load_history(state: &AppState, session_id: &str) -> Vec<ChatMessage> {
+    match load_session_messages(&state.db_pool, session_id).await {
+        Ok(rows) => rows
+            .into_iter()
+            .map(|row| ChatMessage::new(&row.role, &row.content))
+            .collect(),
+        Err(e) => {
+            eprintln!("[Qwen2] Failed to load session history: {e}");
+            Vec::new()
+        }
+    }
+}
 
-    // This will be what you save into the DB as the assistant answer
-    let mut final_answer = String::new();
+// Every candidate answer becomes a sibling assistant message of the prompt
+async fn save_answer(
+    state: &AppState,
+    params: &ChatStreamQuery,
+    response_format: Option<&ResponseFormat>,
+    generations: &[Generation],
+) {
+    // Raw `/v1/completions` have no session to save to
+    if params.session_id.is_empty() {
+        return;
+    }
+    let mut replies = Vec::new();
+    for (index, generation) in generations.iter().enumerate() {
+        // A stop sequence or the token limit can cut a constrained answer short
+        match response_format.map(|f| f.validate(&generation.answer)) {
+            Some(Err(e)) => eprintln!(
+                "[Qwen2] Answer {index} doesn't match the response format, not saving it: {e}"
+            ),
+            _ => replies.push((index, generation.answer.as_str())),
+        }
+    }
+    if replies.is_empty() {
+        return;
+    }
 
-    let seed = 42;
-    let temperature = Some(0.7);
-    let top_p = Some(0.9);
-    let mut logits_processor = LogitsProcessor::new(seed, temperature, top_p);
+    if let Err(e) =
+        save_chat_turn(&state.db_pool, &params.session_id, &params.prompt, &replies).await
+    {
+        eprintln!("[Qwen2] Failed to save chat turn: {e}");
+    } else {
+        println!("[Qwen2] Chat turn saved to DB.");
+    }
+}
 
+fn run_streaming_generation_qwen(
+    state: AppState,
+    params: ChatStreamQuery,
+    history: Vec<ChatMessage>,
+    options: GenerationOptions,
+    tx: mpsc::UnboundedSender<StreamEvent>,
+) -> anyhow::Result<Vec<Generation>> {
     // Hard cap to avoid insane values from frontend
-    let max_steps = params.max_tokens.min(256);
-    println!("--> [Qwen2] Entering generation loop (max_steps = {max_steps})...");
+    let max_steps = params
+        .max_tokens
+        .unwrap_or(state.default_max_tokens)
+        .min(state.max_tokens_cap);
 
-    for step in 0..max_steps {
-        let context_size = if step > 0 { 1 } else { tokens.len() };
-        let start_at = tokens.len().saturating_sub(context_size);
-        let ctx = &tokens[start_at..];
+    println!("--> [Qwen2] Encoding prompt...");
+    let context = build_context(&state, &params, &history, max_steps)?;
+    // A short context may leave less room than was reserved for the answer
+    let max_steps = max_steps.min(state.max_context.saturating_sub(context.tokens.len()));
+    if context.trimmed_turns > 0 || context.truncated {
+        println!(
+            "--> [Qwen2] Prompt over budget: trimmed {} turns, truncated = {}",
+            context.trimmed_turns, context.truncated
+        );
+    }
+    let context_event = serde_json::json!({
+        "prompt_tokens": context.tokens.len(),
+        "trimmed_turns": context.trimmed_turns,
+        "truncated": context.truncated,
+    });
+    let _ = tx.send(StreamEvent::json("context", None, context_event));
+
+    // The scheduler streams each answer into `tx` and hands back the full
+    // text. Candidates are separate sequences sharing one prefill, so they
+    // decode in one batch.
+    let mut requests = Vec::with_capacity(options.n);
+    let mut done = Vec::with_capacity(options.n);
+    for index in 0..options.n {
+        let mut options = options.clone();
+        options.sampling.seed = candidates::seed(options.sampling.seed, index);
+        let (done_tx, done_rx) = oneshot::channel();
+        requests.push(SequenceRequest {
+            session_id: params.session_id.clone(),
+            prompt_tokens: context.tokens.clone(),
+            max_steps,
+            candidate: (options.n > 1).then_some(index),
+            options,
+            events: tx.clone(),
+            done: done_tx,
+        });
+        done.push(done_rx);
+    }
+    state.scheduler.submit(requests)?;
 
-        let input = Tensor::new(ctx, &device)?.reshape((1, ctx.len()))?;
+    let generations = done
+        .into_iter()
+        .map(|done_rx| {
+            done_rx
+                .blocking_recv()
+                .map_err(|_| anyhow::anyhow!("scheduler dropped the request"))?
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-        // Forward pass – Qwen2 returns [batch, seq_len, vocab]
-        let logits_all = model.forward(&input, seqlen_offset)?;
-        seqlen_offset += ctx.len();
+    // send final DONE event so frontend knows to stop
+    let _ = tx.send(StreamEvent::Done);
+    println!("--> [Qwen2] Sent DONE event, finishing generation");
 
-        // dims: [1, seq_len, vocab]
-        let dims = logits_all.dims();
-        let last_pos = dims[1] - 1; // seq_len - 1
+    Ok(generations)
+}
 
-        // Take batch = 0, last sequence position -> [vocab]
-        let logits = logits_all
-            .i((0, last_pos))? // -> [vocab]
-            .to_dtype(DType::F32)?; // logits_processor expects f32
+fn build_context(
+    state: &AppState,
+    params: &ChatStreamQuery,
+    history: &[ChatMessage],
+    max_new_tokens: usize,
+) -> anyhow::Result<PromptContext> {
+    let budget = context::prompt_budget(state.max_context, max_new_tokens);
+    if params.raw {
+        return match &params.suffix {
+            Some(suffix) => fim::fit(&state.tokenizer, &params.prompt, suffix, budget),
+            None => context::fit_raw(&state.tokenizer, &params.prompt, budget),
+        };
+    }
 
-        let next_token = logits_processor.sample(&logits)?;
-        tokens.push(next_token);
+    let mut messages = Vec::new();
+    if let Some(system) = &params.system {
+        messages.push(ChatMessage::new("system", system));
+    }
+    messages.extend_from_slice(history);
+    messages.push(ChatMessage::new("user", &params.prompt));
 
-        // Decode full text and figure out the *new* part
-        let text = tokenizer
-            .decode(&tokens, true)
-            .map_err(|e| anyhow::anyhow!("tokenizer decode error: {e}"))?;
+    context::fit_chat(&state.tokenizer, &state.chat_template, messages, budget)
+}
 
-        let new_text = &text[prev_text_len..];
-        prev_text_len = text.len();
+/// Same parameters as `/chat/stream` as a JSON body, answered in one response.
+async fn chat_handler(
+    State(state): State<AppState>,
+    Json(params): Json<ChatStreamQuery>,
+) -> Result<Json<ChatResponse>, Response> {
+    println!("[Qwen2] Received request. Prompt: {}", params.prompt);
+
+    let options = GenerationOptions::from_query(&params, &state.tokenizer)
+        .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[Qwen2] Queue is full, rejecting request");
+        return Err(admission::queue_full_response());
+    };
+
+    let history = load_history(&state, &params.session_id).await;
+    let generations = generate_answer(&state, &params, history, options, ticket)
+        .await
+        .map_err(|e| {
+            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        })?;
+
+    let logprobs = params.logprobs.is_some();
+    let mut responses: Vec<ChatResponse> = generations
+        .into_iter()
+        .map(|generation| ChatResponse::new(generation, logprobs))
+        .collect();
+    if responses.len() == 1 {
+        return Ok(Json(responses.remove(0)));
+    }
+    let first = &responses[0];
+    Ok(Json(ChatResponse {
+        response: first.response.clone(),
+        finish_reason: first.finish_reason,
+        logprobs: first.logprobs.clone(),
+        beam_score: first.beam_score,
+        candidates: Some(responses),
+    }))
+}
 
-        if !new_text.is_empty() {
-            // append to final answer (for DB)
-            final_answer.push_str(new_text);
+/// OpenAI-compatible chat completions, streamed as `chat.completion.chunk`s
+/// when `stream` is set. The turn is saved like a `/chat` one.
+async fn chat_completions_handler(
+    State(state): State<AppState>,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Response {
+    let (params, history) = match request.to_query() {
+        Ok(query) => query,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+    println!(
+        "[Qwen2] Received OpenAI chat request. Prompt: {}",
+        params.prompt
+    );
 
-            // stream to client
-            let event = Event::default().data(new_text.to_string());
-            if tx.blocking_send(Ok(event)).is_err() {
-                println!("--> [Qwen2] Client disconnected, stopping generation");
-                break;
-            }
-        }
+    let options = match GenerationOptions::from_query(&params, &state.tokenizer) {
+        Ok(options) => options,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[Qwen2] Queue is full, rejecting request");
+        return admission::queue_full_response();
+    };
+
+    let completion = Completion::new("chatcmpl", &state.model_id);
+    if request.stream {
+        let chunks = Chunks::new(
+            completion,
+            ChunkKind::Chat,
+            options.n,
+            request.include_usage(),
+        );
+        let events = spawn_generation(state, params, history, options, ticket);
+        let stream = openai::relay(UnboundedReceiverStream::new(events), chunks);
+        return Sse::new(stream)
+            .keep_alive(KeepAlive::default())
+            .into_response();
+    }
 
-        // ---- Stop conditions ----
-        if next_token == eos_token {
-            println!("--> [Qwen2] Hit EOS, stopping generation");
-            break;
+    match generate_answer(&state, &params, history, options, ticket).await {
+        Ok(generations) => {
+            Json(completion.chat_response(generations, request.logprobs)).into_response()
         }
+        Err(e) => openai::error_response(axum::http::StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// OpenAI-compatible raw completions: the prompt is continued without the
+/// chat template, or filled in up to `suffix`, and nothing is saved.
+async fn completions_handler(
+    State(state): State<AppState>,
+    Json(request): Json<CompletionRequest>,
+) -> Response {
+    let params = match request.to_query() {
+        Ok(params) => params,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+    println!(
+        "[Qwen2] Received OpenAI completion request. Prompt: {}",
+        params.prompt
+    );
+
+    let options = match GenerationOptions::from_query(&params, &state.tokenizer) {
+        Ok(options) => options,
+        Err(e) => return openai::error_response(axum::http::StatusCode::BAD_REQUEST, e),
+    };
+
+    let Some(ticket) = state.admission.enqueue() else {
+        println!("[Qwen2] Queue is full, rejecting request");
+        return admission::queue_full_response();
+    };
+
+    let completion = Completion::new("cmpl", &state.model_id);
+    let echo = request.echo.then(|| params.prompt.clone());
+    if request.stream {
+        let chunks = Chunks::new(
+            completion,
+            ChunkKind::Text { echo },
+            options.n,
+            request.include_usage(),
+        );
+        let events = spawn_generation(state, params, Vec::new(), options, ticket);
+        let stream = openai::relay(UnboundedReceiverStream::new(events), chunks);
+        return Sse::new(stream)
+            .keep_alive(KeepAlive::default())
+            .into_response();
+    }
 
-        if step + 1 == max_steps {
-            println!("--> [Qwen2] Reached max_steps = {max_steps}, stopping generation");
+    let logprobs = request.logprobs.is_some();
+    match generate_answer(&state, &params, Vec::new(), options, ticket).await {
+        Ok(generations) => {
+            Json(completion.text_response(generations, echo.as_deref(), logprobs)).into_response()
         }
+        Err(e) => openai::error_response(axum::http::StatusCode::INTERNAL_SERVER_ERROR, e),
     }
+}
 
-    // send final DONE event so frontend knows to stop
-    let _ = tx.blocking_send(Ok(Event::default()
-        .event("message") // keep same event name as normal tokens
-        .data("[DONE]")));
-    println!("--> [Qwen2] Sent DONE event, finishing generation");
+async fn models_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(openai::model_list(&state.model_id))
+}
 
-    Ok(final_answer)
+// Fallback ChatML template for Qwen2 checkpoints without a `chat_template`
+const CHATML_TEMPLATE: &str = r#"{%- if messages[0]['role'] != 'system' -%}
+{{ '<|im_start|>system\nYou are Qwen, created by Alibaba Cloud. You are a helpful assistant.<|im_end|>\n' }}
+{%- endif -%}
+{%- for message in messages -%}
+{{ '<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>\n' }}
+{%- endfor -%}
+{%- if add_generation_prompt -%}{{ '<|im_start|>assistant\n' }}{%- endif -%}"#;
+
+fn write_quantized_gguf(
+    model_dir: &Path,
+    format: QuantFormat,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let output = output.unwrap_or_else(|| model_dir.join(format!("model-{format}.gguf")));
+
+    println!("[Quantize] Writing {format} weights to {output:?}...");
+    let mut file = std::fs::File::create(&output)?;
+    quantize::write_gguf(model_dir, format, &mut file)?;
+    println!("[Quantize] Done. A .gguf in the model directory is picked up on the next start.");
+
+    Ok(())
 }
 
-async fn chat_handler(
-    State(_state): State<AppState>,
-    Json(_req): Json<ChatRequest>,
-) -> Result<Json<ChatResponse>, axum::http::StatusCode> {
-    Ok(Json(ChatResponse {
-        response: "Please use /chat/stream for Qwen2 as well.".to_string(),
-    }))
+// The model directory's name, reported as the model id
+fn model_id(model_dir: &Path) -> String {
+    model_dir.file_name().map_or_else(
+        || model_dir.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
 }
 
-fn load_qwen_state(db_pool: DbPool) -> Result<AppState> {
-    let model_dir = PathBuf::from("models/qwen2_0_5b_instruct");
+fn load_qwen_state(db_pool: DbPool, config: &ServerConfig) -> Result<AppState> {
+    let model_dir = &config.model_dir;
     let tokenizer_path = model_dir.join("tokenizer.json");
 
     let tokenizer = Tokenizer::from_file(&tokenizer_path)
         .map_err(|e| anyhow::anyhow!("tokenizer error: {e}"))?;
 
-    let config_bytes = std::fs::read(model_dir.join("config.json"))?;
-    let qwen_config: QwenConfig = serde_json::from_slice(&config_bytes)?;
-
-    let filenames = vec![model_dir.join("model.safetensors")];
+    let eos_tokens = eos::load_eos_tokens(model_dir, &tokenizer)?;
+    let chat_template = ChatTemplate::load(model_dir, CHATML_TEMPLATE)?;
 
     let device = Device::Cpu;
-    let dtype = DType::F32;
-
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-    let model = ModelForCausalLM::new(&qwen_config, vb)?;
+    let dtype = config.dtype;
+
+    // Uses a .gguf file from the model directory when there is one
+    let (model, mut max_context) = model::load_model(model_dir, dtype, &device, config.quantize)?;
+
+    let draft = match &config.draft_model_dir {
+        Some(draft_dir) => {
+            println!("[Model] Loading draft model from {draft_dir:?}");
+            let (model, draft_context) =
+                model::load_model(draft_dir, dtype, &device, config.quantize)?;
+            // The draft follows every sequence to its last position
+            max_context = max_context.min(draft_context);
+            Some(Draft {
+                model,
+                tokens: config.draft_tokens,
+            })
+        }
+        None => None,
+    };
+
+    let tokenizer = Arc::new(tokenizer);
+    let scheduler = Scheduler::start(
+        model,
+        Arc::clone(&tokenizer),
+        Arc::new(eos_tokens),
+        config.max_batch_size,
+        config.session_cache_mb * 1024 * 1024,
+        config.prefix_cache_mb * 1024 * 1024,
+        draft,
+    )?;
 
     Ok(AppState {
-        model: Arc::new(Mutex::new(model)),
-        config: qwen_config,
-        dtype,
-        device,
-        tokenizer: Arc::new(tokenizer),
+        scheduler,
+        max_context,
+        default_max_tokens: config.default_max_tokens,
+        max_tokens_cap: config.max_tokens_cap,
+        admission: Admission::new(config.max_concurrent, config.max_queue),
+        model_id: model_id(model_dir),
+        tokenizer,
+        chat_template: Arc::new(chat_template),
         db_pool,
     })
 }
 
+/// Precompute the KV state of a system prompt so that sessions using it skip its prefill.
+async fn register_system_prefix(state: &AppState, system: &str) -> Result<PrefixResponse> {
+    let tokens = context::system_prefix(&state.tokenizer, &state.chat_template, system)?;
+    let len = tokens.len();
+    // A prompt starting with it needs at least one more token
+    if len >= state.max_context {
+        anyhow::bail!(
+            "prefix is {len} tokens, the context window only {}",
+            state.max_context
+        );
+    }
+    let hash = state
+        .scheduler
+        .register_prefix(tokens)?
+        .await
+        .map_err(|_| anyhow::anyhow!("scheduler dropped the prefix"))??;
+    Ok(PrefixResponse {
+        hash: format!("{hash:016x}"),
+        tokens: len,
+    })
+}
+
+async fn prefix_handler(
+    State(state): State<AppState>,
+    Json(req): Json<PrefixRequest>,
+) -> Result<Json<PrefixResponse>, Response> {
+    match register_system_prefix(&state, &req.system).await {
+        Ok(prefix) => Ok(Json(prefix)),
+        Err(e) => {
+            eprintln!("[Qwen2] Failed to register prefix: {e}");
+            Err((axum::http::StatusCode::BAD_REQUEST, e.to_string()).into_response())
+        }
+    }
+}
+
 async fn history_handler(
     State(state): State<AppState>,
 ) -> Result<Json<Vec<SessionWithMessages>>, axum::http::StatusCode> {
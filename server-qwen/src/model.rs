@@ -0,0 +1,769 @@
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, IndexOp, Module, Tensor};
+use candle_nn::{Activation, Embedding, RmsNorm, VarBuilder};
+use candle_transformers::models::qwen2::Config as QwenConfig;
+use candle_transformers::utils::repeat_kv;
+
+use crate::quantize::{self, QuantFormat};
+
+/// Qwen2 decoder whose KV caches live outside the model, one per sequence,
+/// so that sequences at different positions can be decoded in one batch.
+///
+/// Weights come either from full precision safetensors or a quantized GGUF file.
+pub struct QwenModel {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: QMatMul,
+    rotary: RotaryEmbedding,
+    dtype: DType,
+    device: Device,
+}
+
+/// Keys and values of one sequence, per layer, shaped `[1, kv_heads, len, head_dim]`.
+#[derive(Clone)]
+pub struct KvCache {
+    layers: Vec<Option<(Tensor, Tensor)>>,
+    len: usize,
+}
+
+impl KvCache {
+    /// Number of positions held.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Drop every position from `len` on, keeping the prefix.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.len {
+            return Ok(());
+        }
+        for (k, v) in self.layers.iter_mut().flatten() {
+            *k = k.narrow(2, 0, len)?;
+            *v = v.narrow(2, 0, len)?;
+        }
+        self.len = len;
+        Ok(())
+    }
+
+    /// Memory taken by the keys and values.
+    pub fn size_in_bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes())
+            .sum()
+    }
+}
+
+struct Linear {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let xs = self.weight.forward(xs)?;
+        match &self.bias {
+            Some(bias) => xs.broadcast_add(bias),
+            None => Ok(xs),
+        }
+    }
+}
+
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(
+        head_dim: usize,
+        max_len: usize,
+        theta: f64,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let inv_freq: Vec<f32> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / theta.powf(i as f64 / head_dim as f64) as f32)
+            .collect();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, head_dim / 2), device)?;
+        let t = Tensor::arange(0u32, max_len as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((max_len, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: freqs.sin()?.to_dtype(dtype)?,
+            cos: freqs.cos()?.to_dtype(dtype)?,
+        })
+    }
+
+    /// `xs` is `[batch, heads, seq, head_dim]`; row `b` starts at position `offsets[b]`.
+    fn apply(&self, xs: &Tensor, offsets: &[usize]) -> candle_core::Result<Tensor> {
+        let seq_len = xs.dim(2)?;
+        let (cos, sin) = if let [offset] = offsets {
+            (
+                self.cos.narrow(0, *offset, seq_len)?,
+                self.sin.narrow(0, *offset, seq_len)?,
+            )
+        } else {
+            let cos: Vec<Tensor> = offsets
+                .iter()
+                .map(|&offset| self.cos.narrow(0, offset, seq_len))
+                .collect::<candle_core::Result<_>>()?;
+            let sin: Vec<Tensor> = offsets
+                .iter()
+                .map(|&offset| self.sin.narrow(0, offset, seq_len))
+                .collect::<candle_core::Result<_>>()?;
+            (Tensor::stack(&cos, 0)?, Tensor::stack(&sin, 0)?)
+        };
+        candle_nn::rotary_emb::rope(&xs.contiguous()?, &cos, &sin)
+    }
+}
+
+struct DecoderLayer {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    gate_proj: Linear,
+    up_proj: Linear,
+    down_proj: Linear,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+    act: Activation,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+}
+
+impl DecoderLayer {
+    fn forward(
+        &self,
+        xs: &Tensor,
+        rotary: &RotaryEmbedding,
+        mask: Option<&Tensor>,
+        offsets: &[usize],
+        caches: &mut [&mut Option<(Tensor, Tensor)>],
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.attention(&xs, rotary, mask, offsets, caches)?;
+        let xs = (xs + residual)?;
+
+        let residual = &xs;
+        let h = self.post_attention_layernorm.forward(&xs)?;
+        let gate = self.gate_proj.forward(&h)?.apply(&self.act)?;
+        let up = self.up_proj.forward(&h)?;
+        let h = self.down_proj.forward(&(gate * up)?)?;
+        Ok((residual + h)?)
+    }
+
+    fn attention(
+        &self,
+        xs: &Tensor,
+        rotary: &RotaryEmbedding,
+        mask: Option<&Tensor>,
+        offsets: &[usize],
+        caches: &mut [&mut Option<(Tensor, Tensor)>],
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, hidden) = xs.dims3()?;
+
+        let q = self
+            .q_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .k_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .v_proj
+            .forward(xs)?
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = rotary.apply(&q, offsets)?;
+        let k = rotary.apply(&k, offsets)?;
+
+        // Append to each sequence's own cache, then right-pad to the longest
+        // one; the mask hides the padding
+        let mut keys = Vec::with_capacity(b_sz);
+        let mut values = Vec::with_capacity(b_sz);
+        for (b, cache) in caches.iter_mut().enumerate() {
+            let k_b = k.narrow(0, b, 1)?;
+            let v_b = v.narrow(0, b, 1)?;
+            let (k_b, v_b) = match cache.as_ref() {
+                None => (k_b, v_b),
+                Some((prev_k, prev_v)) => (
+                    Tensor::cat(&[prev_k, &k_b], 2)?,
+                    Tensor::cat(&[prev_v, &v_b], 2)?,
+                ),
+            };
+            **cache = Some((k_b.clone(), v_b.clone()));
+            keys.push(k_b);
+            values.push(v_b);
+        }
+        let (k, v) = if b_sz == 1 {
+            (keys.remove(0), values.remove(0))
+        } else {
+            let kv_len = offsets.iter().max().copied().unwrap_or(0) + q_len;
+            let pad = |t: &Tensor| -> candle_core::Result<Tensor> {
+                let len = t.dim(2)?;
+                t.pad_with_zeros(2, 0, kv_len - len)
+            };
+            let keys = keys
+                .iter()
+                .map(pad)
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            let values = values
+                .iter()
+                .map(pad)
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            (Tensor::cat(&keys, 0)?, Tensor::cat(&values, 0)?)
+        };
+
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = repeat_kv(k, n_rep)?.contiguous()?;
+        let v = repeat_kv(v, n_rep)?.contiguous()?;
+
+        let scale = 1f64 / (self.head_dim as f64).sqrt();
+        let weights = (q.matmul(&k.t()?)? * scale)?;
+        let weights = match mask {
+            Some(mask) => weights.broadcast_add(mask)?,
+            None => weights,
+        };
+        let weights = candle_nn::ops::softmax_last_dim(&weights)?;
+        let out = weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, hidden))?;
+        Ok(self.o_proj.forward(&out)?)
+    }
+}
+
+impl QwenModel {
+    pub fn new_cache(&self) -> KvCache {
+        KvCache {
+            layers: vec![None; self.layers.len()],
+            len: 0,
+        }
+    }
+
+    /// Run `tokens` through the model after what `cache` already holds.
+    /// Returns the logits of the last position, shaped `[vocab]`, in f32.
+    pub fn prefill(&self, tokens: &[u32], cache: &mut KvCache) -> Result<Tensor> {
+        if tokens.is_empty() {
+            anyhow::bail!("nothing to prefill");
+        }
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let logits = self.forward(&input, &mut [cache], true)?;
+        Ok(logits.i(0)?)
+    }
+
+    /// Decode one token for each sequence in a single batch.
+    /// Returns the logits shaped `[batch, vocab]`, in f32.
+    pub fn decode(&self, tokens: &[u32], caches: &mut [&mut KvCache]) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(1)?;
+        self.forward(&input, caches, true)
+    }
+
+    /// Run `tokens[b]` after what `caches[b]` holds, in a single batch; every
+    /// row must have the same length. Returns the logits of every position,
+    /// shaped `[batch, len, vocab]`, in f32.
+    pub fn score(&self, tokens: &[Vec<u32>], caches: &mut [&mut KvCache]) -> Result<Tensor> {
+        let input = Tensor::new(tokens.to_vec(), &self.device)?;
+        self.forward(&input, caches, false)
+    }
+
+    fn forward(
+        &self,
+        input: &Tensor,
+        caches: &mut [&mut KvCache],
+        last_only: bool,
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len) = input.dims2()?;
+        let offsets: Vec<usize> = caches.iter().map(|c| c.len).collect();
+        let mask = self.attention_mask(&offsets, seq_len)?;
+
+        let mut xs = self.embed_tokens.forward(input)?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let mut layer_caches: Vec<&mut Option<(Tensor, Tensor)>> =
+                caches.iter_mut().map(|c| &mut c.layers[i]).collect();
+            xs = layer.forward(
+                &xs,
+                &self.rotary,
+                mask.as_ref(),
+                &offsets,
+                &mut layer_caches,
+            )?;
+        }
+        for cache in caches.iter_mut() {
+            cache.len += seq_len;
+        }
+
+        if !last_only {
+            let logits = self.lm_head.forward(&self.norm.forward(&xs)?)?;
+            return Ok(logits.to_dtype(DType::F32)?);
+        }
+        let xs = self.norm.forward(&xs.narrow(1, seq_len - 1, 1)?)?;
+        let logits = self.lm_head.forward(&xs)?.reshape((b_sz, ()))?;
+        Ok(logits.to_dtype(DType::F32)?)
+    }
+
+    // Position j of row b is visible to query i if it is neither in the future
+    // nor padding: j <= offset_b + i
+    fn attention_mask(&self, offsets: &[usize], seq_len: usize) -> Result<Option<Tensor>> {
+        let all_equal = offsets.windows(2).all(|w| w[0] == w[1]);
+        if seq_len == 1 && all_equal {
+            return Ok(None);
+        }
+        let kv_len = offsets.iter().max().copied().unwrap_or(0) + seq_len;
+        let mask: Vec<f32> = offsets
+            .iter()
+            .flat_map(|&offset| {
+                (0..seq_len).flat_map(move |i| {
+                    (0..kv_len).map(move |j| {
+                        if j > offset + i {
+                            f32::NEG_INFINITY
+                        } else {
+                            0.
+                        }
+                    })
+                })
+            })
+            .collect();
+        let mask = Tensor::from_vec(mask, (offsets.len(), 1, seq_len, kv_len), &self.device)?
+            .to_dtype(self.dtype)?;
+        Ok(Some(mask))
+    }
+
+    fn from_safetensors(config: &QwenConfig, vb: VarBuilder) -> Result<Self> {
+        let hidden = config.hidden_size;
+        let head_dim = hidden / config.num_attention_heads;
+        let kv_dim = config.num_key_value_heads * head_dim;
+        let linear =
+            |vb: VarBuilder, in_dim: usize, out_dim: usize, bias: bool| -> Result<Linear> {
+                Ok(Linear {
+                    weight: QMatMul::Tensor(vb.get((out_dim, in_dim), "weight")?),
+                    bias: if bias {
+                        Some(vb.get(out_dim, "bias")?)
+                    } else {
+                        None
+                    },
+                })
+            };
+        let rms_norm = |vb: VarBuilder| -> Result<RmsNorm> {
+            Ok(RmsNorm::new(vb.get(hidden, "weight")?, config.rms_norm_eps))
+        };
+
+        let vb_m = vb.pp("model");
+        let embeddings = vb_m.get((config.vocab_size, hidden), "embed_tokens.weight")?;
+        let mut layers = Vec::with_capacity(config.num_hidden_layers);
+        for i in 0..config.num_hidden_layers {
+            let vb_l = vb_m.pp("layers").pp(i);
+            let attn = vb_l.pp("self_attn");
+            let mlp = vb_l.pp("mlp");
+            layers.push(DecoderLayer {
+                q_proj: linear(attn.pp("q_proj"), hidden, hidden, true)?,
+                k_proj: linear(attn.pp("k_proj"), hidden, kv_dim, true)?,
+                v_proj: linear(attn.pp("v_proj"), hidden, kv_dim, true)?,
+                o_proj: linear(attn.pp("o_proj"), hidden, hidden, false)?,
+                gate_proj: linear(mlp.pp("gate_proj"), hidden, config.intermediate_size, false)?,
+                up_proj: linear(mlp.pp("up_proj"), hidden, config.intermediate_size, false)?,
+                down_proj: linear(mlp.pp("down_proj"), config.intermediate_size, hidden, false)?,
+                input_layernorm: rms_norm(vb_l.pp("input_layernorm"))?,
+                post_attention_layernorm: rms_norm(vb_l.pp("post_attention_layernorm"))?,
+                act: config.hidden_act,
+                num_heads: config.num_attention_heads,
+                num_kv_heads: config.num_key_value_heads,
+                head_dim,
+            });
+        }
+
+        let lm_head = if vb.contains_tensor("lm_head.weight") {
+            vb.get((config.vocab_size, hidden), "lm_head.weight")?
+        } else {
+            embeddings.clone()
+        };
+
+        Ok(Self {
+            embed_tokens: Embedding::new(embeddings, hidden),
+            layers,
+            norm: rms_norm(vb_m.pp("norm"))?,
+            lm_head: QMatMul::Tensor(lm_head),
+            rotary: RotaryEmbedding::new(
+                head_dim,
+                config.max_position_embeddings,
+                config.rope_theta,
+                vb.dtype(),
+                vb.device(),
+            )?,
+            dtype: vb.dtype(),
+            device: vb.device().clone(),
+        })
+    }
+
+    fn from_gguf<R: Read + Seek>(
+        content: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md_get = |key: &str| {
+            content
+                .metadata
+                .get(key)
+                .ok_or_else(|| anyhow::anyhow!("missing {key} in GGUF metadata"))
+        };
+        let num_heads = md_get("qwen2.attention.head_count")?.to_u32()? as usize;
+        let num_kv_heads = md_get("qwen2.attention.head_count_kv")?.to_u32()? as usize;
+        let hidden = md_get("qwen2.embedding_length")?.to_u32()? as usize;
+        let context_length = md_get("qwen2.context_length")?.to_u32()? as usize;
+        let block_count = md_get("qwen2.block_count")?.to_u32()? as usize;
+        let rms_norm_eps = md_get("qwen2.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
+        let rope_theta = md_get("qwen2.rope.freq_base")
+            .and_then(|v| Ok(v.to_f32()?))
+            .unwrap_or(10_000.) as f64;
+        let head_dim = hidden / num_heads;
+
+        let mut weights = GgufWeights {
+            content: &content,
+            reader,
+            device,
+        };
+
+        let mut layers = Vec::with_capacity(block_count);
+        for i in 0..block_count {
+            let prefix = format!("blk.{i}");
+            layers.push(DecoderLayer {
+                q_proj: weights.linear(&format!("{prefix}.attn_q"), true)?,
+                k_proj: weights.linear(&format!("{prefix}.attn_k"), true)?,
+                v_proj: weights.linear(&format!("{prefix}.attn_v"), true)?,
+                o_proj: weights.linear(&format!("{prefix}.attn_output"), false)?,
+                gate_proj: weights.linear(&format!("{prefix}.ffn_gate"), false)?,
+                up_proj: weights.linear(&format!("{prefix}.ffn_up"), false)?,
+                down_proj: weights.linear(&format!("{prefix}.ffn_down"), false)?,
+                input_layernorm: RmsNorm::new(
+                    weights.dense(&format!("{prefix}.attn_norm.weight"))?,
+                    rms_norm_eps,
+                ),
+                post_attention_layernorm: RmsNorm::new(
+                    weights.dense(&format!("{prefix}.ffn_norm.weight"))?,
+                    rms_norm_eps,
+                ),
+                act: Activation::Silu,
+                num_heads,
+                num_kv_heads,
+                head_dim,
+            });
+        }
+
+        let embeddings = weights.dense("token_embd.weight")?;
+        let lm_head = match weights.qtensor("output.weight") {
+            Ok(output) => QMatMul::from_qtensor(output)?,
+            // Tied embeddings
+            Err(_) => QMatMul::from_qtensor(weights.qtensor("token_embd.weight")?)?,
+        };
+        let norm = weights.dense("output_norm.weight")?;
+
+        Ok(Self {
+            embed_tokens: Embedding::new(embeddings, hidden),
+            layers,
+            norm: RmsNorm::new(norm, rms_norm_eps),
+            lm_head,
+            rotary: RotaryEmbedding::new(head_dim, context_length, rope_theta, DType::F32, device)?,
+            dtype: DType::F32,
+            device: device.clone(),
+        })
+    }
+}
+
+// Reads tensors out of a GGUF file by name
+struct GgufWeights<'a, R> {
+    content: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: &'a Device,
+}
+
+impl<R: Read + Seek> GgufWeights<'_, R> {
+    fn qtensor(&mut self, name: &str) -> Result<QTensor> {
+        Ok(self.content.tensor(self.reader, name, self.device)?)
+    }
+
+    fn dense(&mut self, name: &str) -> Result<Tensor> {
+        Ok(self.qtensor(name)?.dequantize(self.device)?)
+    }
+
+    fn linear(&mut self, name: &str, bias: bool) -> Result<Linear> {
+        let weight = QMatMul::from_qtensor(self.qtensor(&format!("{name}.weight"))?)?;
+        let bias = if bias {
+            Some(self.dense(&format!("{name}.bias"))?)
+        } else {
+            None
+        };
+        Ok(Linear { weight, bias })
+    }
+}
+
+/// First `.gguf` file in the model directory, if any.
+pub fn find_gguf(model_dir: &Path) -> Result<Option<PathBuf>> {
+    let mut ggufs = Vec::new();
+    for entry in std::fs::read_dir(model_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "gguf") {
+            ggufs.push(path);
+        }
+    }
+    ggufs.sort();
+    Ok(ggufs.into_iter().next())
+}
+
+fn load_gguf<R: Read + Seek>(reader: &mut R, device: &Device) -> Result<(QwenModel, usize)> {
+    let content = gguf_file::Content::read(reader)?;
+    let max_context = content
+        .metadata
+        .get("qwen2.context_length")
+        .and_then(|v| v.to_u32().ok())
+        .map(|v| v as usize)
+        .unwrap_or(32768);
+    let model = QwenModel::from_gguf(content, reader, device)?;
+    Ok((model, max_context))
+}
+
+/// `model.safetensors`, or the shards listed in `model.safetensors.index.json`
+/// for larger checkpoints.
+pub fn safetensors_files(model_dir: &Path) -> Result<Vec<PathBuf>> {
+    let index_path = model_dir.join("model.safetensors.index.json");
+    if !index_path.exists() {
+        return Ok(vec![model_dir.join("model.safetensors")]);
+    }
+
+    let index: serde_json::Value = serde_json::from_slice(&std::fs::read(&index_path)?)?;
+    let weight_map = index
+        .get("weight_map")
+        .and_then(|map| map.as_object())
+        .ok_or_else(|| anyhow::anyhow!("no weight_map in {index_path:?}"))?;
+    let mut shards: Vec<&str> = weight_map.values().filter_map(|v| v.as_str()).collect();
+    shards.sort_unstable();
+    shards.dedup();
+    Ok(shards
+        .into_iter()
+        .map(|shard| model_dir.join(shard))
+        .collect())
+}
+
+/// Load the model and return it with its context length.
+///
+/// A `.gguf` file in `model_dir` takes precedence over the safetensors weights;
+/// with `quantize` set, the safetensors weights are quantized while loading.
+pub fn load_model(
+    model_dir: &Path,
+    dtype: DType,
+    device: &Device,
+    quantize: Option<QuantFormat>,
+) -> Result<(QwenModel, usize)> {
+    if let Some(gguf_path) = find_gguf(model_dir)? {
+        println!("[Model] Loading quantized weights from {gguf_path:?}");
+        let mut file = std::fs::File::open(&gguf_path)?;
+        return load_gguf(&mut file, device)
+            .map_err(|e| anyhow::anyhow!("failed to load {gguf_path:?}: {e}"));
+    }
+
+    if let Some(format) = quantize {
+        println!("[Model] Quantizing safetensors weights to {format}...");
+        let mut gguf = quantize::quantize_in_memory(model_dir, format)?;
+        return load_gguf(&mut gguf, device);
+    }
+
+    let config_bytes = std::fs::read(model_dir.join("config.json"))?;
+    let qwen_config: QwenConfig = serde_json::from_slice(&config_bytes)?;
+
+    let filenames = safetensors_files(model_dir)?;
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, device)? };
+    let model = QwenModel::from_safetensors(&qwen_config, vb)?;
+
+    Ok((model, qwen_config.max_position_embeddings))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_transformers::models::qwen2;
+
+    use super::*;
+
+    fn tiny_config() -> QwenConfig {
+        QwenConfig {
+            vocab_size: 64,
+            hidden_size: 32,
+            intermediate_size: 48,
+            num_hidden_layers: 2,
+            num_attention_heads: 4,
+            num_key_value_heads: 2,
+            max_position_embeddings: 64,
+            sliding_window: 64,
+            max_window_layers: 2,
+            tie_word_embeddings: false,
+            rope_theta: 10_000.,
+            rms_norm_eps: 1e-6,
+            use_sliding_window: false,
+            hidden_act: Activation::Silu,
+        }
+    }
+
+    // Random weights under the Hugging Face names both implementations load
+    fn random_weights(config: &QwenConfig, device: &Device) -> Result<VarBuilder<'static>> {
+        let hidden = config.hidden_size;
+        let kv_dim = config.num_key_value_heads * hidden / config.num_attention_heads;
+        let inter = config.intermediate_size;
+        let mut shapes: Vec<(String, Vec<usize>)> = vec![
+            (
+                "model.embed_tokens.weight".into(),
+                vec![config.vocab_size, hidden],
+            ),
+            ("model.norm.weight".into(), vec![hidden]),
+            ("lm_head.weight".into(), vec![config.vocab_size, hidden]),
+        ];
+        for i in 0..config.num_hidden_layers {
+            let p = format!("model.layers.{i}");
+            shapes.extend([
+                (format!("{p}.self_attn.q_proj.weight"), vec![hidden, hidden]),
+                (format!("{p}.self_attn.q_proj.bias"), vec![hidden]),
+                (format!("{p}.self_attn.k_proj.weight"), vec![kv_dim, hidden]),
+                (format!("{p}.self_attn.k_proj.bias"), vec![kv_dim]),
+                (format!("{p}.self_attn.v_proj.weight"), vec![kv_dim, hidden]),
+                (format!("{p}.self_attn.v_proj.bias"), vec![kv_dim]),
+                (format!("{p}.self_attn.o_proj.weight"), vec![hidden, hidden]),
+                (format!("{p}.mlp.gate_proj.weight"), vec![inter, hidden]),
+                (format!("{p}.mlp.up_proj.weight"), vec![inter, hidden]),
+                (format!("{p}.mlp.down_proj.weight"), vec![hidden, inter]),
+                (format!("{p}.input_layernorm.weight"), vec![hidden]),
+                (format!("{p}.post_attention_layernorm.weight"), vec![hidden]),
+            ]);
+        }
+
+        let mut tensors = HashMap::new();
+        for (name, shape) in shapes {
+            let tensor = if name.ends_with("norm.weight") {
+                (Tensor::randn(0f32, 0.1, shape, device)? + 1.)?
+            } else {
+                Tensor::randn(0f32, 0.2, shape, device)?
+            };
+            tensors.insert(name, tensor);
+        }
+        Ok(VarBuilder::from_tensors(tensors, DType::F32, device))
+    }
+
+    fn models() -> Result<(QwenModel, qwen2::ModelForCausalLM)> {
+        let device = Device::Cpu;
+        let config = tiny_config();
+        let vb = random_weights(&config, &device)?;
+        let ours = QwenModel::from_safetensors(&config, vb.clone())?;
+        let reference = qwen2::ModelForCausalLM::new(&config, vb)?;
+        Ok((ours, reference))
+    }
+
+    // Logits of the last token, running `tokens` one at a time after `prompt`
+    fn reference_logits(
+        reference: &qwen2::ModelForCausalLM,
+        prompt: &[u32],
+        tokens: &[u32],
+    ) -> Result<Vec<Tensor>> {
+        let mut reference = reference.clone();
+        reference.clear_kv_cache();
+        let device = Device::Cpu;
+        let input = Tensor::new(prompt, &device)?.unsqueeze(0)?;
+        let mut logits = vec![reference.forward(&input, 0)?.flatten_all()?];
+        for (i, &token) in tokens.iter().enumerate() {
+            let input = Tensor::new(&[token], &device)?.unsqueeze(0)?;
+            logits.push(reference.forward(&input, prompt.len() + i)?.flatten_all()?);
+        }
+        Ok(logits)
+    }
+
+    fn assert_close(actual: &Tensor, expected: &Tensor) -> Result<()> {
+        let diff = (actual - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "logits differ by {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn single_sequence_matches_candle_qwen2() -> Result<()> {
+        let (model, reference) = models()?;
+        let prompt = [3, 14, 15, 9, 26, 5];
+        let tokens = [35, 8, 9];
+        let expected = reference_logits(&reference, &prompt, &tokens)?;
+
+        let mut cache = model.new_cache();
+        assert_close(&model.prefill(&prompt, &mut cache)?, &expected[0])?;
+        for (i, &token) in tokens.iter().enumerate() {
+            let logits = model.decode(&[token], &mut [&mut cache])?;
+            assert_close(&logits.i(0)?, &expected[i + 1])?;
+        }
+        assert_eq!(cache.len(), prompt.len() + tokens.len());
+        Ok(())
+    }
+
+    #[test]
+    fn empty_prefill_is_an_error() -> Result<()> {
+        let (model, _) = models()?;
+        let mut cache = model.new_cache();
+        assert!(model.prefill(&[], &mut cache).is_err());
+        assert_eq!(cache.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn padded_batch_matches_candle_qwen2() -> Result<()> {
+        let (model, reference) = models()?;
+        let prompts: [&[u32]; 3] = [&[1, 2, 3], &[7, 40, 2, 11, 12, 60, 4], &[5]];
+        let tokens: [[u32; 3]; 3] = [[10, 11, 12], [20, 21, 22], [30, 31, 32]];
+
+        let mut caches: Vec<KvCache> = prompts
+            .iter()
+            .map(|prompt| {
+                let mut cache = model.new_cache();
+                model.prefill(prompt, &mut cache).map(|_| cache)
+            })
+            .collect::<Result<_>>()?;
+
+        // One token per sequence, at different positions
+        let mut refs: Vec<&mut KvCache> = caches.iter_mut().collect();
+        let decoded = model.decode(&[tokens[0][0], tokens[1][0], tokens[2][0]], &mut refs)?;
+
+        // Then the remaining two at once, as when verifying drafts
+        let rows: Vec<Vec<u32>> = tokens.iter().map(|t| t[1..].to_vec()).collect();
+        let mut refs: Vec<&mut KvCache> = caches.iter_mut().collect();
+        let scored = model.score(&rows, &mut refs)?;
+
+        for (b, prompt) in prompts.iter().enumerate() {
+            let expected = reference_logits(&reference, prompt, &tokens[b])?;
+            assert_close(&decoded.i(b)?, &expected[1])?;
+            assert_close(&scored.i((b, 0))?, &expected[2])?;
+            assert_close(&scored.i((b, 1))?, &expected[3])?;
+            assert_eq!(caches[b].len(), prompt.len() + 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_cache_continues_like_a_fresh_one() -> Result<()> {
+        let (model, reference) = models()?;
+        let prompt = [3, 14, 15, 9];
+        let expected = reference_logits(&reference, &prompt, &[50])?;
+
+        let mut cache = model.new_cache();
+        model.prefill(&[3, 14, 15, 9, 26, 5, 35], &mut cache)?;
+        cache.truncate(prompt.len())?;
+        let logits = model.decode(&[50], &mut [&mut cache])?;
+        assert_close(&logits.i(0)?, &expected[1])
+    }
+}
@@ -0,0 +1,1113 @@
+use std::collections::{HashSet, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+
+use anyhow::Result;
+use candle_core::{IndexOp, Tensor};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tokenizers::Tokenizer;
+use tokio::sync::{mpsc, oneshot};
+
+use server_common::beam::{BeamConfig, BeamSearch, Hypothesis};
+use server_common::constraint::{Constraint, ResponseFormat, Vocabulary};
+use server_common::events::StreamEvent;
+use server_common::logit_bias::LogitBias;
+use server_common::logprobs::{self, TokenLogprob};
+use server_common::penalties::PenaltyConfig;
+use server_common::prefix_cache::{self, PrefixCache};
+use server_common::prompt_lookup;
+use server_common::request::{Generation, GenerationOptions};
+use server_common::samplers::Sampler;
+use server_common::sampling::SamplingConfig;
+use server_common::session_cache::{self, SessionCache};
+use server_common::stop::StopSequences;
+use server_common::token_output_stream::TokenOutputStream;
+
+use crate::model::{KvCache, QwenModel};
+use crate::speculative::{self, Draft};
+
+/// A prompt to generate from, handed to the scheduler.
+pub struct SequenceRequest {
+    pub session_id: String,
+    pub prompt_tokens: Vec<u32>,
+    pub max_steps: usize,
+    /// Index among the request's candidates, when it asked for several.
+    pub candidate: Option<usize>,
+    pub options: GenerationOptions,
+    /// The client's SSE stream.
+    pub events: mpsc::UnboundedSender<StreamEvent>,
+    /// Receives the whole answer once the sequence is finished.
+    pub done: oneshot::Sender<Result<Generation>>,
+}
+
+enum Job {
+    // Candidates for one prompt, candidate 0 first
+    Sequences(Vec<SequenceRequest>),
+    Prefix {
+        tokens: Vec<u32>,
+        done: oneshot::Sender<Result<u64>>,
+    },
+}
+
+/// Handle to the scheduler thread.
+///
+/// The thread owns the model. Each new sequence gets its own KV cache and a
+/// prefill pass, starting from a registered prefix or from what the session's
+/// previous cache shares with the prompt. Further candidates for the same
+/// prompt start from a copy of the first one's cache. After that every active sequence
+/// advances by one token per batched forward pass, and sequences join or leave
+/// the batch between steps.
+///
+/// Beam search requests keep all their beams in one batched pass of their own
+/// each step, next to the sampled sequences.
+///
+/// With a draft model, each step instead lets the draft propose a few tokens
+/// per sequence and the model verifies them all in one batched pass. Without
+/// one, sequences asking for prompt lookup get their drafts copied from the
+/// prompt and checked the same way.
+#[derive(Clone)]
+pub struct Scheduler {
+    jobs: Sender<Job>,
+}
+
+impl Scheduler {
+    pub fn start(
+        model: QwenModel,
+        tokenizer: Arc<Tokenizer>,
+        eos_tokens: Arc<HashSet<u32>>,
+        max_batch_size: usize,
+        session_cache_bytes: usize,
+        prefix_cache_bytes: usize,
+        draft: Option<Draft>,
+    ) -> Result<Self> {
+        // Takes a while for a large vocabulary; on the scheduler thread it would
+        // stall every stream when the first response format comes in
+        println!("--> [Qwen2] Indexing the vocabulary for constrained generation...");
+        let vocabulary = Arc::new(Vocabulary::new(&tokenizer)?);
+        let (jobs, receiver) = channel();
+        let worker = Worker {
+            model,
+            draft,
+            tokenizer,
+            eos_tokens,
+            vocabulary,
+            max_batch_size,
+            sessions: SessionCache::new(session_cache_bytes),
+            prefixes: PrefixCache::new(prefix_cache_bytes),
+            jobs: receiver,
+            waiting: VecDeque::new(),
+            active: Vec::new(),
+            beams: Vec::new(),
+        };
+        std::thread::Builder::new()
+            .name("qwen-scheduler".to_string())
+            .spawn(move || worker.run())?;
+        Ok(Self { jobs })
+    }
+
+    /// Generate from `requests`, the candidates of one prompt. Only the first
+    /// one prefills it; the others continue from a copy of its cache.
+    pub fn submit(&self, requests: Vec<SequenceRequest>) -> Result<()> {
+        self.send(Job::Sequences(requests))
+    }
+
+    /// Prefill `tokens` once and keep the result for every prompt starting with
+    /// them. The receiver gets the prefix's hash once it is ready.
+    pub fn register_prefix(&self, tokens: Vec<u32>) -> Result<oneshot::Receiver<Result<u64>>> {
+        let (done, receiver) = oneshot::channel();
+        self.send(Job::Prefix { tokens, done })?;
+        Ok(receiver)
+    }
+
+    fn send(&self, job: Job) -> Result<()> {
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow::anyhow!("scheduler thread has stopped"))
+    }
+}
+
+struct Worker {
+    model: QwenModel,
+    draft: Option<Draft>,
+    tokenizer: Arc<Tokenizer>,
+    eos_tokens: Arc<HashSet<u32>>,
+    vocabulary: Arc<Vocabulary>,
+    max_batch_size: usize,
+    sessions: SessionCache<KvCache>,
+    prefixes: PrefixCache<KvCache>,
+    jobs: Receiver<Job>,
+    waiting: VecDeque<Vec<SequenceRequest>>,
+    active: Vec<Sequence>,
+    // Beam searches; each takes one slot of the batch
+    beams: Vec<BeamSequence>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        loop {
+            if self.active.is_empty() && self.beams.is_empty() && self.waiting.is_empty() {
+                match self.jobs.recv() {
+                    Ok(job) => self.accept(job),
+                    // Every handle is gone, the server is shutting down
+                    Err(_) => return,
+                }
+            }
+            while let Ok(job) = self.jobs.try_recv() {
+                self.accept(job);
+            }
+
+            // Candidates join together; a group larger than the batch still gets in
+            // once the batch is empty
+            while let Some(group) = self.waiting.front() {
+                let running = self.active.len() + self.beams.len();
+                if running > 0 && running + group.len() > self.max_batch_size {
+                    break;
+                }
+                let group = self.waiting.pop_front().unwrap_or_default();
+                self.admit(group);
+            }
+
+            // Steps catch panics of their own sequences; this keeps the thread
+            // serving everyone else should one get through anyway
+            if let Err(e) = catch_panic(|| {
+                self.step();
+                Ok(())
+            }) {
+                eprintln!("--> [Qwen2] Scheduler step failed: {e}");
+            }
+        }
+    }
+
+    fn accept(&mut self, job: Job) {
+        match job {
+            Job::Sequences(group) if group.is_empty() => {}
+            Job::Sequences(group) => self.waiting.push_back(group),
+            Job::Prefix { tokens, done } => {
+                let result = catch_panic(|| self.register_prefix(tokens));
+                if let Err(e) = &result {
+                    eprintln!("--> [Qwen2] Failed to register prefix: {e}");
+                }
+                let _ = done.send(result);
+            }
+        }
+    }
+
+    fn register_prefix(&mut self, tokens: Vec<u32>) -> Result<u64> {
+        if tokens.is_empty() {
+            anyhow::bail!("prefix is empty");
+        }
+        if self.prefixes.contains(&tokens) {
+            return Ok(prefix_cache::token_hash(&tokens));
+        }
+
+        let mut cache = self.model.new_cache();
+        self.model.prefill(&tokens, &mut cache)?;
+        let len = tokens.len();
+        let bytes = cache.size_in_bytes();
+        let Some(hash) = self.prefixes.insert(tokens, cache, bytes) else {
+            anyhow::bail!("prefix needs {bytes} bytes of KV cache, more than prefix_cache_mb");
+        };
+        println!("--> [Qwen2] Registered prefix {hash:016x} ({len} tokens)");
+        Ok(hash)
+    }
+
+    // Prefill the prompt once and sample the first token of every candidate
+    fn admit(&mut self, group: Vec<SequenceRequest>) {
+        let mut group = group.into_iter();
+        let Some(request) = group.next() else {
+            return;
+        };
+        if let Some(config) = request.options.beam.clone() {
+            self.admit_beam(request, config);
+            return;
+        }
+        let mut first = Sequence::new(request, &self.model, &self.tokenizer);
+        let mut others: Vec<Sequence> = group
+            .map(|request| Sequence::new(request, &self.model, &self.tokenizer))
+            .collect();
+        if first.max_steps == 0 {
+            first.finish();
+            others.into_iter().for_each(Sequence::finish);
+            return;
+        }
+
+        first.constrain(&self.vocabulary);
+        match catch_panic(|| self.prefill(&mut first)) {
+            Ok(logits) => {
+                for seq in &mut others {
+                    seq.constrain(&self.vocabulary);
+                    seq.cache = first.cache.clone();
+                    seq.cached_tokens = first.cached_tokens;
+                    seq.draft_cache = first.draft_cache.clone();
+                }
+                for seq in std::iter::once(&mut first).chain(&mut others) {
+                    if let Err(e) = seq.advance(&logits, &self.eos_tokens) {
+                        seq.error = Some(e);
+                        seq.finished = true;
+                    }
+                }
+            }
+            Err(e) => {
+                for seq in &mut others {
+                    seq.error = Some(anyhow::anyhow!("{e}"));
+                    seq.finished = true;
+                }
+                first.error = Some(e);
+                first.finished = true;
+            }
+        }
+
+        for seq in std::iter::once(first).chain(others) {
+            if seq.finished {
+                self.finish(seq);
+            } else {
+                self.active.push(seq);
+                println!(
+                    "--> [Qwen2] Sequence joined the batch ({} active, {} waiting)",
+                    self.active.len(),
+                    self.waiting.len()
+                );
+            }
+        }
+    }
+
+    // Returns the logits of the last prompt position
+    fn prefill(&mut self, seq: &mut Sequence) -> Result<Tensor> {
+        seq.cache = self.restore_cache(&seq.session_id, &seq.tokens)?;
+        seq.cached_tokens = seq.cache.len();
+        let cached = seq.cached_tokens;
+        println!(
+            "--> [Qwen2] Prefilling {} prompt tokens ({} cached)...",
+            seq.tokens.len() - cached,
+            cached
+        );
+        let logits = self.model.prefill(&seq.tokens[cached..], &mut seq.cache)?;
+
+        if let (Some(draft), false) = (&self.draft, seq.skips_draft()) {
+            // The draft starts two tokens behind, see `speculate`
+            let mut cache = draft.model.new_cache();
+            if seq.prompt_len > 1 {
+                draft
+                    .model
+                    .prefill(&seq.tokens[..seq.prompt_len - 1], &mut cache)?;
+            }
+            seq.draft_cache = Some(cache);
+        }
+        Ok(logits)
+    }
+
+    // Prefill the prompt and take the first beam search step
+    fn admit_beam(&mut self, request: SequenceRequest, config: BeamConfig) {
+        let cache = match self.restore_cache(&request.session_id, &request.prompt_tokens) {
+            Ok(cache) => cache,
+            Err(e) => {
+                let _ = request.done.send(Err(e));
+                return;
+            }
+        };
+        let beams = BeamSequence::new(request, config, cache, &self.tokenizer);
+        println!(
+            "--> [Qwen2] Beam search over {} beams, prefilling {} prompt tokens ({} cached)...",
+            beams.search.config().width,
+            beams.prompt_tokens.len() - beams.cached_tokens,
+            beams.cached_tokens
+        );
+        self.advance_beams(beams);
+    }
+
+    // One step of every beam search, each in a batched pass over its beams
+    fn step_beams(&mut self) {
+        for beams in std::mem::take(&mut self.beams) {
+            self.advance_beams(beams);
+        }
+    }
+
+    fn advance_beams(&mut self, mut beams: BeamSequence) {
+        if beams.events.is_closed() {
+            println!("--> [Qwen2] Client disconnected, stopping beam search");
+        } else if !beams.search.is_done() {
+            if let Err(e) = catch_panic(|| beams.step(&self.model, &self.eos_tokens)) {
+                beams.error = Some(e);
+            }
+        }
+        if beams.events.is_closed() || beams.error.is_some() || beams.search.is_done() {
+            self.finish_beams(beams);
+        } else {
+            self.beams.push(beams);
+        }
+    }
+
+    // The beams' caches end in different answers; the session keeps the prompt's
+    fn finish_beams(&mut self, mut beams: BeamSequence) {
+        if let (None, Some(cache)) = (&beams.error, beams.prompt_cache.take()) {
+            let tokens = beams.prompt_tokens[..cache.len()].to_vec();
+            let bytes = cache.size_in_bytes();
+            self.sessions
+                .insert(beams.session_id.clone(), cache, tokens, bytes);
+        }
+        beams.finish();
+    }
+
+    fn step(&mut self) {
+        self.step_sequences();
+        self.step_beams();
+    }
+
+    // One batched step over every active sequence
+    fn step_sequences(&mut self) {
+        for seq in &mut self.active {
+            if seq.events.is_closed() {
+                println!("--> [Qwen2] Client disconnected, stopping generation");
+                seq.finished = true;
+            }
+        }
+        self.retire_finished();
+        if self.active.is_empty() {
+            return;
+        }
+
+        let (model, eos_tokens) = (&self.model, &self.eos_tokens);
+        match &self.draft {
+            Some(draft) => {
+                self.active.sort_by_key(|seq| seq.skips_draft());
+                let split = self.active.partition_point(|seq| !seq.skips_draft());
+                let (drafted, plain) = self.active.split_at_mut(split);
+                step_isolated(drafted, |batch| speculate(draft, model, batch, eos_tokens));
+                step_isolated(plain, |batch| decode(model, batch, eos_tokens));
+            }
+            None => step_isolated(&mut self.active, |batch| decode(model, batch, eos_tokens)),
+        }
+        self.retire_finished();
+    }
+
+    fn retire_finished(&mut self) {
+        let (finished, active): (Vec<_>, Vec<_>) = std::mem::take(&mut self.active)
+            .into_iter()
+            .partition(|seq| seq.finished);
+        self.active = active;
+        for seq in finished {
+            self.finish(seq);
+        }
+    }
+
+    // Start from whichever covers more of the prompt: a registered prefix, or the
+    // session's previous cache cut back to the part the prompt still shares.
+    // At least one prompt token is always left to prefill.
+    fn restore_cache(&mut self, session_id: &str, prompt_tokens: &[u32]) -> Result<KvCache> {
+        let mut best = self.prefixes.lookup(prompt_tokens);
+        if let Some((cache, tokens)) = self.sessions.take(session_id) {
+            let shared = session_cache::common_prefix_len(&tokens, prompt_tokens)
+                .min(prompt_tokens.len().saturating_sub(1));
+            if shared > best.as_ref().map_or(0, |(len, _)| *len) {
+                best = Some((shared, cache));
+            }
+        }
+
+        match best {
+            Some((len, mut cache)) => {
+                cache.truncate(len)?;
+                Ok(cache)
+            }
+            None => Ok(self.model.new_cache()),
+        }
+    }
+
+    // Keep the cache for the session's next turn, then close the stream. Of
+    // several candidates the first is the one the conversation continues from.
+    fn finish(&mut self, mut seq: Sequence) {
+        if seq.error.is_none() && seq.candidate.unwrap_or(0) == 0 {
+            let cache = std::mem::replace(&mut seq.cache, self.model.new_cache());
+            let tokens = seq.tokens[..cache.len()].to_vec();
+            let bytes = cache.size_in_bytes();
+            self.sessions
+                .insert(seq.session_id.clone(), cache, tokens, bytes);
+        }
+        seq.finish();
+    }
+}
+
+// Run `f`, turning a panic into an error. Candle panics on some inputs it
+// can't handle; that should fail the sequences involved, not the thread that
+// serves every request.
+fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        Err(anyhow::anyhow!("model panicked: {message}"))
+    })
+}
+
+// Run `step` over the batch. If the batched pass fails, rewind every sequence
+// and step each on its own, so that only a sequence failing by itself gets the
+// error. Nothing has been streamed by then: sequences sample once the model's
+// pass is done and keep their own errors from there on.
+fn step_isolated(batch: &mut [Sequence], mut step: impl FnMut(&mut [Sequence]) -> Result<()>) {
+    if batch.is_empty() {
+        return;
+    }
+    let checkpoints: Vec<Checkpoint> = batch.iter().map(Sequence::checkpoint).collect();
+    let Err(e) = catch_panic(|| step(batch)) else {
+        return;
+    };
+    if let [seq] = batch {
+        seq.error = Some(e);
+        seq.finished = true;
+        return;
+    }
+
+    eprintln!("--> [Qwen2] Batched decode failed, stepping sequences one by one: {e}");
+    for (seq, checkpoint) in batch.iter_mut().zip(checkpoints) {
+        seq.restore(checkpoint);
+        if let Err(e) = catch_panic(|| step(std::slice::from_mut(seq))) {
+            seq.error = Some(e);
+            seq.finished = true;
+        }
+    }
+}
+
+// Advance every sequence by one token, or by several where prompt lookup
+// drafts get accepted
+fn decode(model: &QwenModel, active: &mut [Sequence], eos_tokens: &HashSet<u32>) -> Result<()> {
+    for seq in active.iter_mut() {
+        seq.drafts = seq.lookup();
+    }
+    if active.iter().any(|seq| !seq.drafts.is_empty()) {
+        return verify_lookups(model, active, eos_tokens);
+    }
+
+    let tokens: Vec<u32> = active.iter().map(|seq| seq.last_token()).collect();
+    let mut caches: Vec<&mut KvCache> = active.iter_mut().map(|seq| &mut seq.cache).collect();
+    let logits = model.decode(&tokens, &mut caches)?;
+
+    for (i, seq) in active.iter_mut().enumerate() {
+        let result = logits
+            .i(i)
+            .map_err(anyhow::Error::from)
+            .and_then(|logits| seq.advance(&logits, eos_tokens));
+        if let Err(e) = result {
+            seq.error = Some(e);
+            seq.finished = true;
+        }
+    }
+    Ok(())
+}
+
+// Check every sequence's prompt lookup drafts in one batched pass. Rows are
+// padded to the same length; the padding positions are scored but never used.
+fn verify_lookups(
+    model: &QwenModel,
+    active: &mut [Sequence],
+    eos_tokens: &HashSet<u32>,
+) -> Result<()> {
+    // The padding must not run past the last position any row may use either
+    let limit = active.iter().map(Sequence::remaining).min().unwrap_or(0);
+    for seq in active.iter_mut() {
+        seq.drafts.truncate(limit);
+    }
+    let width = active.iter().map(|seq| seq.drafts.len()).max().unwrap_or(0) + 1;
+    let inputs: Vec<Vec<u32>> = active
+        .iter()
+        .map(|seq| {
+            let mut input = [&[seq.last_token()][..], &seq.drafts].concat();
+            input.resize(width, seq.last_token());
+            input
+        })
+        .collect();
+    let mut caches: Vec<&mut KvCache> = active.iter_mut().map(|seq| &mut seq.cache).collect();
+    let logits = model.score(&inputs, &mut caches)?;
+
+    for (i, seq) in active.iter_mut().enumerate() {
+        let result = logits
+            .i((i, ..seq.drafts.len() + 1))
+            .map_err(anyhow::Error::from)
+            .and_then(|logits| seq.verify_lookup(&logits, eos_tokens));
+        if let Err(e) = result {
+            seq.error = Some(e);
+            seq.finished = true;
+        }
+    }
+    Ok(())
+}
+
+// Let the draft propose `draft.tokens` tokens for every sequence, one batched
+// draft pass per token, then check them all in one batched pass of the model
+fn speculate(
+    draft: &Draft,
+    model: &QwenModel,
+    active: &mut [Sequence],
+    eos_tokens: &HashSet<u32>,
+) -> Result<()> {
+    // The first draft pass feeds the last two tokens: after a fully accepted
+    // run the draft hasn't seen its own last token yet
+    let mut inputs = Vec::with_capacity(active.len());
+    for seq in active.iter_mut() {
+        let start = seq.tokens.len() - 2;
+        seq.draft_cache()?.truncate(start)?;
+        inputs.push(seq.tokens[start..].to_vec());
+    }
+    let logits = draft.model.score(&inputs, &mut draft_caches(active)?)?;
+    for (i, seq) in active.iter_mut().enumerate() {
+        seq.draft(&logits.i((i, 1))?)?;
+    }
+    // Drafts past the last token a sequence may generate would reach beyond
+    // the end of its context; every sequence has at least one left
+    let tokens = active
+        .iter()
+        .map(Sequence::remaining)
+        .min()
+        .unwrap_or(0)
+        .min(draft.tokens);
+    for _ in 1..tokens {
+        let tokens: Vec<u32> = active
+            .iter()
+            .map(|seq| seq.drafts[seq.drafts.len() - 1])
+            .collect();
+        let logits = draft.model.decode(&tokens, &mut draft_caches(active)?)?;
+        for (i, seq) in active.iter_mut().enumerate() {
+            seq.draft(&logits.i(i)?)?;
+        }
+    }
+
+    let inputs: Vec<Vec<u32>> = active
+        .iter()
+        .map(|seq| [&[seq.last_token()][..], &seq.drafts].concat())
+        .collect();
+    let mut caches: Vec<&mut KvCache> = active.iter_mut().map(|seq| &mut seq.cache).collect();
+    let logits = model.score(&inputs, &mut caches)?;
+
+    for (i, seq) in active.iter_mut().enumerate() {
+        let result = logits
+            .i(i)
+            .map_err(anyhow::Error::from)
+            .and_then(|logits| seq.verify(&logits, eos_tokens));
+        if let Err(e) = result {
+            seq.error = Some(e);
+            seq.finished = true;
+        }
+    }
+    Ok(())
+}
+
+fn draft_caches(active: &mut [Sequence]) -> Result<Vec<&mut KvCache>> {
+    active.iter_mut().map(|seq| seq.draft_cache()).collect()
+}
+
+// What a failed batched pass may have changed in a sequence. Cache clones
+// share their tensors, so taking one is cheap.
+struct Checkpoint {
+    cache: KvCache,
+    draft_cache: Option<KvCache>,
+    rng: StdRng,
+}
+
+struct Sequence {
+    session_id: String,
+    candidate: Option<usize>,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    max_steps: usize,
+    cache: KvCache,
+    // Prompt tokens taken from the session's previous cache
+    cached_tokens: usize,
+    sampling: SamplingConfig,
+    penalties: PenaltyConfig,
+    logit_bias: Option<Arc<LogitBias>>,
+    sampler: Sampler,
+    prompt_lookup: bool,
+    // Tokens proposed for the next step, by the draft model or prompt lookup
+    drafts: Vec<u32>,
+    drafted: usize,
+    accepted: usize,
+    // Only used with a draft model
+    draft_cache: Option<KvCache>,
+    draft_probs: Vec<Vec<f32>>,
+    rng: StdRng,
+    // Waits here until prefill turns it into `constraint`
+    response_format: Option<ResponseFormat>,
+    constraint: Option<Constraint>,
+    // How many alternatives to report with each token's log-probability
+    top_logprobs: Option<usize>,
+    logprobs: Vec<TokenLogprob>,
+    tokenizer: Arc<Tokenizer>,
+    token_stream: TokenOutputStream,
+    stop_sequences: StopSequences,
+    // What gets saved into the DB as the assistant answer
+    answer: String,
+    finish_reason: &'static str,
+    finished: bool,
+    error: Option<anyhow::Error>,
+    events: mpsc::UnboundedSender<StreamEvent>,
+    done: oneshot::Sender<Result<Generation>>,
+}
+
+impl Sequence {
+    fn new(request: SequenceRequest, model: &QwenModel, tokenizer: &Arc<Tokenizer>) -> Self {
+        let GenerationOptions {
+            sampling,
+            penalties,
+            logit_bias,
+            stop,
+            prompt_lookup,
+            response_format,
+            logprobs: top_logprobs,
+            n: _,
+            beam: _,
+        } = request.options;
+        Self {
+            session_id: request.session_id,
+            candidate: request.candidate,
+            prompt_len: request.prompt_tokens.len(),
+            max_steps: request.max_steps,
+            cache: model.new_cache(),
+            cached_tokens: 0,
+            sampler: Sampler::new(&sampling),
+            prompt_lookup,
+            drafts: Vec::new(),
+            drafted: 0,
+            accepted: 0,
+            draft_cache: None,
+            draft_probs: Vec::new(),
+            rng: StdRng::seed_from_u64(sampling.seed),
+            response_format,
+            constraint: None,
+            top_logprobs,
+            logprobs: Vec::new(),
+            tokenizer: Arc::clone(tokenizer),
+            sampling,
+            penalties,
+            logit_bias,
+            // Only stream what comes after the prompt
+            token_stream: TokenOutputStream::new(Arc::clone(tokenizer), &request.prompt_tokens),
+            stop_sequences: StopSequences::new(stop),
+            tokens: request.prompt_tokens,
+            answer: String::new(),
+            finish_reason: "length",
+            finished: false,
+            error: None,
+            events: request.events,
+            done: request.done,
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            cache: self.cache.clone(),
+            draft_cache: self.draft_cache.clone(),
+            rng: self.rng.clone(),
+        }
+    }
+
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.cache = checkpoint.cache;
+        self.draft_cache = checkpoint.draft_cache;
+        self.rng = checkpoint.rng;
+        self.drafts.clear();
+        self.draft_probs.clear();
+    }
+
+    // Drafts know nothing of the response format, tokens accepted from them
+    // have no log-probabilities of their own, and draft verification only
+    // knows the distribution `LogitsProcessor` samples from, so these
+    // sequences always decode without the draft model
+    fn skips_draft(&self) -> bool {
+        self.constraint.is_some() || self.top_logprobs.is_some() || self.sampling.chain.is_some()
+    }
+
+    fn constrain(&mut self, vocabulary: &Arc<Vocabulary>) {
+        if let Some(format) = self.response_format.take() {
+            self.constraint = Some(format.constraint(Arc::clone(vocabulary)));
+        }
+    }
+
+    fn last_token(&self) -> u32 {
+        self.tokens[self.tokens.len() - 1]
+    }
+
+    /// Sample the next token from the last position's logits and stream its text.
+    fn advance(&mut self, logits: &Tensor, eos_tokens: &HashSet<u32>) -> Result<()> {
+        let logits = self.adjust(logits, &self.tokens)?;
+        let distribution = match self.top_logprobs {
+            Some(_) => Some(logprobs::log_softmax(&logits)?),
+            None => None,
+        };
+        let next_token = match &mut self.constraint {
+            Some(constraint) => constraint.sample(&logits, &mut self.sampler, eos_tokens)?,
+            None => self.sampler.sample(&logits)?,
+        };
+        if let (Some(top_n), Some(distribution)) = (self.top_logprobs, distribution) {
+            let entry = logprobs::entry(&distribution, next_token, top_n, &self.tokenizer)?;
+            let event =
+                StreamEvent::json("logprobs", self.candidate, serde_json::to_value(&entry)?);
+            let _ = self.events.send(event);
+            self.logprobs.push(entry);
+        }
+        self.push_token(next_token, eos_tokens)
+    }
+
+    /// Prompt lookup drafts for the next step, if the sequence asked for them.
+    fn lookup(&self) -> Vec<u32> {
+        if !self.prompt_lookup {
+            return Vec::new();
+        }
+        prompt_lookup::propose(&self.tokens, self.prompt_len, self.remaining())
+    }
+
+    /// Tokens still to generate. Positions past them may lie beyond the end
+    /// of the context, since `max_steps` only fits the space left after the prompt.
+    fn remaining(&self) -> usize {
+        self.max_steps - (self.tokens.len() - self.prompt_len)
+    }
+
+    /// Sample from the logits for the last token and each prompt lookup draft,
+    /// `[drafts + 1, vocab]`, keeping drafts only while they match what was
+    /// sampled. With drafts that were certain of themselves this is the same
+    /// as sampling on its own.
+    fn verify_lookup(&mut self, logits: &Tensor, eos_tokens: &HashSet<u32>) -> Result<()> {
+        let drafts = std::mem::take(&mut self.drafts);
+        for i in 0..=drafts.len() {
+            self.advance(&logits.i(i)?, eos_tokens)?;
+            if self.finished || drafts.get(i) != Some(&self.last_token()) {
+                break;
+            }
+            self.accepted += 1;
+        }
+        self.drafted += drafts.len();
+        // Forget the positions of rejected drafts and of the padding
+        self.cache.truncate(self.tokens.len() - 1)
+    }
+
+    fn draft_cache(&mut self) -> Result<&mut KvCache> {
+        self.draft_cache
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("sequence has no draft cache"))
+    }
+
+    /// Sample a draft token, keeping the draft's distribution for [`Sequence::verify`].
+    fn draft(&mut self, logits: &Tensor) -> Result<()> {
+        let history = [&self.tokens[..], &self.drafts].concat();
+        let probs = self.probabilities(logits, &history)?;
+        let token = speculative::sample(&probs, &mut self.rng)?;
+        self.drafts.push(token);
+        self.draft_probs.push(probs);
+        Ok(())
+    }
+
+    /// Check the drafts against the model's logits for the last token and each
+    /// draft, `[drafts + 1, vocab]`. Streams the accepted drafts followed by one
+    /// token sampled from the model.
+    fn verify(&mut self, logits: &Tensor, eos_tokens: &HashSet<u32>) -> Result<()> {
+        let drafts = std::mem::take(&mut self.drafts);
+        let draft_probs = std::mem::take(&mut self.draft_probs);
+
+        let mut history = self.tokens.clone();
+        let mut correction = None;
+        for (i, (&token, q)) in drafts.iter().zip(&draft_probs).enumerate() {
+            let p = self.probabilities(&logits.i(i)?, &history)?;
+            let p_token = p.get(token as usize).copied().unwrap_or(0.0);
+            if speculative::accept(p_token, q[token as usize], &mut self.rng) {
+                history.push(token);
+            } else {
+                let residual = speculative::residual(&p, q);
+                correction = Some(speculative::sample(&residual, &mut self.rng)?);
+                break;
+            }
+        }
+        let accepted = history.len() - self.tokens.len();
+        let next_token = match correction {
+            Some(token) => token,
+            // Every draft was accepted, so the model's last position gives one more
+            None => {
+                let p = self.probabilities(&logits.i(drafts.len())?, &history)?;
+                speculative::sample(&p, &mut self.rng)?
+            }
+        };
+        self.drafted += drafts.len();
+        self.accepted += accepted;
+
+        history.push(next_token);
+        for token in history.split_off(self.tokens.len()) {
+            self.push_token(token, eos_tokens)?;
+            if self.finished {
+                break;
+            }
+        }
+        // Forget the positions of rejected drafts, and of any past the end
+        self.cache.truncate(self.tokens.len() - 1)
+    }
+
+    // Sampling distribution after `history`, penalties included
+    fn probabilities(&self, logits: &Tensor, history: &[u32]) -> Result<Vec<f32>> {
+        let logits = self.adjust(logits, history)?;
+        speculative::probabilities(&self.sampling, &logits)
+    }
+
+    // Penalties and logit bias after `history`
+    fn adjust(&self, logits: &Tensor, history: &[u32]) -> Result<Tensor> {
+        let generated = &history[self.prompt_len..];
+        let logits = self.penalties.apply(logits, history, generated)?;
+        Ok(match &self.logit_bias {
+            Some(bias) => bias.apply(&logits, generated)?,
+            None => logits,
+        })
+    }
+
+    /// Append a sampled token and stream its text.
+    fn push_token(&mut self, next_token: u32, eos_tokens: &HashSet<u32>) -> Result<()> {
+        self.tokens.push(next_token);
+
+        // Decode only the *new* part (None while a character is still incomplete)
+        let new_text = self
+            .token_stream
+            .next_token(next_token)?
+            .unwrap_or_default();
+
+        // Hold back anything that might be the start of a stop string
+        let (new_text, hit_stop) = self.stop_sequences.push(&new_text);
+
+        if !new_text.is_empty() {
+            self.answer.push_str(&new_text);
+            if self
+                .events
+                .send(StreamEvent::text(self.candidate, new_text))
+                .is_err()
+            {
+                println!("--> [Qwen2] Client disconnected, stopping generation");
+                self.finished = true;
+                return Ok(());
+            }
+        }
+
+        if hit_stop {
+            println!("--> [Qwen2] Hit stop sequence, stopping generation");
+            self.finish_reason = "stop";
+            self.finished = true;
+        } else if eos_tokens.contains(&next_token) {
+            println!("--> [Qwen2] Hit EOS, stopping generation");
+            self.finish_reason = "stop";
+            self.finished = true;
+        } else if self.tokens.len() - self.prompt_len >= self.max_steps {
+            println!(
+                "--> [Qwen2] Reached max_steps = {}, stopping generation",
+                self.max_steps
+            );
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) {
+        if let Some(e) = self.error.take() {
+            let _ = self.done.send(Err(e));
+            return;
+        }
+
+        // Flush text still buffered in the decoder or held back as a possible stop string
+        let rest = match self.token_stream.decode_rest() {
+            Ok(rest) => rest,
+            Err(e) => {
+                let _ = self.done.send(Err(e));
+                return;
+            }
+        };
+        let (mut rest, _) = self.stop_sequences.push(&rest);
+        rest.push_str(&self.stop_sequences.flush());
+        if !rest.is_empty() {
+            self.answer.push_str(&rest);
+            let _ = self.events.send(StreamEvent::text(self.candidate, rest));
+        }
+
+        let mut summary = serde_json::json!({
+            "sampling": self.sampling,
+            "penalties": self.penalties,
+            "completion_tokens": self.tokens.len() - self.prompt_len,
+            "cached_tokens": self.cached_tokens,
+            "finish_reason": self.finish_reason,
+        });
+        if self.draft_cache.is_some() || self.prompt_lookup {
+            let acceptance_rate = if self.drafted > 0 {
+                self.accepted as f64 / self.drafted as f64
+            } else {
+                0.0
+            };
+            summary["speculative"] = serde_json::json!({
+                "draft_tokens": self.drafted,
+                "accepted_tokens": self.accepted,
+                "acceptance_rate": acceptance_rate,
+            });
+        }
+        let _ = self
+            .events
+            .send(StreamEvent::json("summary", self.candidate, summary));
+
+        let _ = self.done.send(Ok(Generation {
+            answer: self.answer,
+            finish_reason: self.finish_reason,
+            logprobs: self.logprobs,
+            beam_score: None,
+            prompt_tokens: self.prompt_len,
+            completion_tokens: self.tokens.len() - self.prompt_len,
+        }));
+    }
+}
+
+/// A beam search request. Nothing streams until the search is over; then the
+/// best beam goes out in one piece.
+struct BeamSequence {
+    session_id: String,
+    prompt_tokens: Vec<u32>,
+    search: BeamSearch<KvCache>,
+    cached_tokens: usize,
+    // The cache right after prefill, for the session's next turn
+    prompt_cache: Option<KvCache>,
+    penalties: PenaltyConfig,
+    logit_bias: Option<Arc<LogitBias>>,
+    stop: Vec<String>,
+    tokenizer: Arc<Tokenizer>,
+    error: Option<anyhow::Error>,
+    events: mpsc::UnboundedSender<StreamEvent>,
+    done: oneshot::Sender<Result<Generation>>,
+}
+
+impl BeamSequence {
+    fn new(
+        request: SequenceRequest,
+        config: BeamConfig,
+        cache: KvCache,
+        tokenizer: &Arc<Tokenizer>,
+    ) -> Self {
+        Self {
+            session_id: request.session_id,
+            cached_tokens: cache.len(),
+            search: BeamSearch::new(config, request.max_steps, cache),
+            prompt_tokens: request.prompt_tokens,
+            prompt_cache: None,
+            penalties: request.options.penalties,
+            logit_bias: request.options.logit_bias,
+            stop: request.options.stop,
+            tokenizer: Arc::clone(tokenizer),
+            error: None,
+            events: request.events,
+            done: request.done,
+        }
+    }
+
+    /// Run every live beam through the model and extend the search. The first
+    /// step prefills the prompt for the single beam there is.
+    fn step(&mut self, model: &QwenModel, eos_tokens: &HashSet<u32>) -> Result<()> {
+        let beams = self.search.beams_mut();
+        let logits: Vec<Tensor> = if beams[0].tokens.is_empty() {
+            let cache = &mut beams[0].cache;
+            let logits = model.prefill(&self.prompt_tokens[cache.len()..], cache)?;
+            self.prompt_cache = Some(cache.clone());
+            vec![logits]
+        } else {
+            let tokens: Vec<u32> = beams
+                .iter()
+                .map(|beam| beam.tokens[beam.tokens.len() - 1])
+                .collect();
+            let mut caches: Vec<&mut KvCache> =
+                beams.iter_mut().map(|beam| &mut beam.cache).collect();
+            let logits = model.decode(&tokens, &mut caches)?;
+            (0..tokens.len())
+                .map(|i| logits.i(i))
+                .collect::<candle_core::Result<_>>()?
+        };
+
+        let mut distributions = Vec::with_capacity(logits.len());
+        for (beam, logits) in self.search.beams().iter().zip(&logits) {
+            let history = [&self.prompt_tokens[..], &beam.tokens].concat();
+            let logits = self.penalties.apply(logits, &history, &beam.tokens)?;
+            let logits = match &self.logit_bias {
+                Some(bias) => bias.apply(&logits, &beam.tokens)?,
+                None => logits,
+            };
+            distributions.push(logprobs::log_softmax(&logits)?);
+        }
+        self.search.step(&distributions, eos_tokens)
+    }
+
+    fn finish(self) {
+        if let Some(e) = self.error {
+            let _ = self.done.send(Err(e));
+            return;
+        }
+
+        let config = self.search.config().clone();
+        let best = self.search.best().unwrap_or(Hypothesis {
+            tokens: Vec::new(),
+            score: 0.0,
+            stopped: false,
+        });
+        let (answer, hit_stop) = match server_common::beam::answer_text(
+            self.tokenizer,
+            &self.prompt_tokens,
+            &best.tokens,
+            self.stop,
+        ) {
+            Ok(answer) => answer,
+            Err(e) => {
+                let _ = self.done.send(Err(e));
+                return;
+            }
+        };
+        let finish_reason = if best.stopped || hit_stop {
+            "stop"
+        } else {
+            "length"
+        };
+        println!(
+            "--> [Qwen2] Beam search finished: {} tokens, score {:.4}",
+            best.tokens.len(),
+            best.score
+        );
+
+        if !answer.is_empty() {
+            let _ = self.events.send(StreamEvent::text(None, answer.clone()));
+        }
+        // The end-of-sequence token counts, as when sampling
+        let completion_tokens = best.tokens.len() + usize::from(best.stopped);
+        let summary = serde_json::json!({
+            "beam": {
+                "width": config.width,
+                "length_penalty": config.length_penalty,
+                "early_stopping": config.early_stopping,
+                "score": best.score,
+            },
+            "penalties": self.penalties,
+            "completion_tokens": completion_tokens,
+            "cached_tokens": self.cached_tokens,
+            "finish_reason": finish_reason,
+        });
+        let _ = self
+            .events
+            .send(StreamEvent::json("summary", None, summary));
+
+        let _ = self.done.send(Ok(Generation {
+            answer,
+            finish_reason,
+            logprobs: Vec::new(),
+            beam_score: Some(best.score),
+            prompt_tokens: self.prompt_tokens.len(),
+            completion_tokens,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_become_errors() {
+        assert_eq!(catch_panic(|| Ok(7)).unwrap(), 7);
+        let e = catch_panic::<()>(|| panic!("index {} out of range", 3)).unwrap_err();
+        assert_eq!(e.to_string(), "model panicked: index 3 out of range");
+        let e = catch_panic::<()>(|| panic!("static message")).unwrap_err();
+        assert_eq!(e.to_string(), "model panicked: static message");
+    }
+}
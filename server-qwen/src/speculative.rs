@@ -0,0 +1,96 @@
+use anyhow::Result;
+use candle_core::Tensor;
+use rand::distr::weighted::WeightedIndex;
+use rand::distr::Distribution;
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use server_common::sampling::SamplingConfig;
+
+use crate::model::QwenModel;
+
+/// A small model sharing the target's tokenizer. It proposes `tokens` tokens
+/// per step, which the target then checks in a single forward pass.
+pub struct Draft {
+    pub model: QwenModel,
+    pub tokens: usize,
+}
+
+/// The distribution the logits processor samples from: softmax at the
+/// sampling temperature, then top-k and top-p. Greedy puts all the mass on
+/// the most likely token.
+pub fn probabilities(sampling: &SamplingConfig, logits: &Tensor) -> Result<Vec<f32>> {
+    let logits = logits.to_vec1::<f32>()?;
+    let mut order: Vec<usize> = (0..logits.len()).collect();
+    order.sort_unstable_by(|&i, &j| logits[j].total_cmp(&logits[i]));
+
+    let mut probs = vec![0.0; logits.len()];
+    if sampling.greedy {
+        if let Some(&best) = order.first() {
+            probs[best] = 1.0;
+        }
+        return Ok(probs);
+    }
+
+    let max = logits[order[0]];
+    let temperature = sampling.temperature as f32;
+    for (p, &logit) in probs.iter_mut().zip(&logits) {
+        *p = ((logit - max) / temperature).exp();
+    }
+    normalize(&mut probs);
+
+    if let Some(k) = sampling.top_k {
+        for &i in order.iter().skip(k) {
+            probs[i] = 0.0;
+        }
+    }
+    if let Some(top_p) = sampling.top_p {
+        let mut cumsum = 0.0;
+        for &i in &order {
+            if cumsum >= top_p as f32 {
+                probs[i] = 0.0;
+            } else {
+                cumsum += probs[i];
+            }
+        }
+    }
+    normalize(&mut probs);
+    Ok(probs)
+}
+
+fn normalize(probs: &mut [f32]) {
+    let sum: f32 = probs.iter().sum();
+    if sum > 0.0 {
+        probs.iter_mut().for_each(|p| *p /= sum);
+    }
+}
+
+/// Draw a token from `probs`, which need not sum to one.
+pub fn sample(probs: &[f32], rng: &mut StdRng) -> Result<u32> {
+    let index = WeightedIndex::new(probs).map_err(|e| anyhow::anyhow!("cannot sample: {e}"))?;
+    Ok(index.sample(rng) as u32)
+}
+
+/// Whether to keep a draft token that the draft picked with probability `q`
+/// and the target gives probability `p`: always if `p >= q`, else with chance `p / q`.
+pub fn accept(p: f32, q: f32, rng: &mut StdRng) -> bool {
+    rng.random::<f32>() * q < p
+}
+
+/// What to sample from after a rejection: the part of the target's
+/// distribution the draft under-covers, `max(0, p - q)`. Together with
+/// [`accept`] this leaves the output distributed exactly as if the target
+/// had sampled on its own.
+pub fn residual(p: &[f32], q: &[f32]) -> Vec<f32> {
+    let residual: Vec<f32> = p
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| (p - q.get(i).copied().unwrap_or(0.0)).max(0.0))
+        .collect();
+    // Only rounding leaves nothing here; the target's own distribution is then as good
+    if residual.iter().sum::<f32>() > 0.0 {
+        residual
+    } else {
+        p.to_vec()
+    }
+}